@@ -0,0 +1,417 @@
+use crate::persist::write_atomic;
+use crate::rpc::{ContentIndex, ContentObject, ContentRef};
+use crate::workspace::{ShelfId, WorkspaceId};
+use iroh::NodeId;
+use prost::Message;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Content-addressed cache of files pulled from peers. Objects are stored once per
+// content hash, whatever the number of remote files sharing it, and evicted least
+// recently used first once the size limit is exceeded. Objects referenced by a pinned
+// file are never evicted, so they stay available while the peer is offline.
+#[derive(Debug)]
+pub struct ContentStore {
+    dir: PathBuf,
+    limit: u64,
+    used: u64,
+    tick: u64,
+    objects: HashMap<Hash, Object>,
+    refs: HashMap<RemotePath, Ref>,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+    corrupted: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ContentStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: u64,
+    pub used: u64,
+    pub budget: u64,
+    pub corrupted: u64, // Objects dropped because their content did not match their hash
+}
+
+pub type Hash = [u8; 32];
+pub type RemotePath = (NodeId, ShelfId, PathBuf);
+
+#[derive(Debug)]
+struct Object {
+    size: u64,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Ref {
+    workspace_id: WorkspaceId, // Set for pinned files, to fetch them again
+    hash: Option<Hash>,
+    modified: u64,
+    pinned: bool,
+}
+
+fn hex(hash: &Hash) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl ContentStore {
+    pub fn open(dir: PathBuf, limit: u64) -> io::Result<Self> {
+        std::fs::create_dir_all(dir.join("objects"))?;
+        let index = std::fs::read(dir.join("index.pb"))
+            .ok()
+            .and_then(|buf| ContentIndex::decode(&*buf).ok())
+            .unwrap_or_default();
+
+        let mut store = ContentStore {
+            dir,
+            limit,
+            used: 0,
+            tick: index.tick,
+            objects: HashMap::new(),
+            refs: HashMap::new(),
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+            corrupted: 0,
+        };
+        for obj in index.objects {
+            let Ok(hash) = Hash::try_from(obj.hash) else {
+                continue;
+            };
+            // Objects lost since the index was written are forgotten
+            if store.object_path(&hash).is_file() {
+                store.used += obj.size;
+                store.objects.insert(
+                    hash,
+                    Object {
+                        size: obj.size,
+                        last_used: obj.last_used,
+                    },
+                );
+            }
+        }
+        for r in index.refs {
+            let Some(node) = <[u8; 32]>::try_from(r.node).ok().and_then(|n| NodeId::from_bytes(&n).ok()) else {
+                continue;
+            };
+            let hash = Hash::try_from(r.hash).ok().filter(|h| store.objects.contains_key(h));
+            if hash.is_none() && !r.pinned {
+                continue;
+            }
+            store.refs.insert(
+                (node, r.shelf_id, PathBuf::from(r.path)),
+                Ref {
+                    workspace_id: r.workspace_id,
+                    hash,
+                    modified: r.modified,
+                    pinned: r.pinned,
+                },
+            );
+        }
+        Ok(store)
+    }
+
+    fn object_path(&self, hash: &Hash) -> PathBuf {
+        self.dir.join("objects").join(hex(hash))
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        let index = ContentIndex {
+            tick: self.tick,
+            objects: self
+                .objects
+                .iter()
+                .map(|(hash, obj)| ContentObject {
+                    hash: hash.to_vec(),
+                    size: obj.size,
+                    last_used: obj.last_used,
+                })
+                .collect(),
+            refs: self
+                .refs
+                .iter()
+                .map(|((node, shelf_id, path), r)| ContentRef {
+                    node: node.as_bytes().to_vec(),
+                    shelf_id: *shelf_id,
+                    path: path.to_string_lossy().into_owned(),
+                    hash: r.hash.map(|h| h.to_vec()).unwrap_or_default(),
+                    modified: r.modified,
+                    pinned: r.pinned,
+                    workspace_id: r.workspace_id,
+                })
+                .collect(),
+        };
+        write_atomic(&self.dir.join("index.pb"), &index.encode_to_vec())
+    }
+
+    // Cached content of a remote file. With modified set, content cached for another
+    // version of the file is ignored; without, any cached version is returned (offline).
+    // Content not matching its hash is dropped.
+    pub fn get(&mut self, key: &RemotePath, modified: Option<u64>) -> io::Result<Option<Vec<u8>>> {
+        let hash = match self.refs.get(key) {
+            Some(Ref { hash: Some(hash), modified: m, .. }) if modified.is_none_or(|modified| modified == *m) => *hash,
+            _ => {
+                self.misses += 1;
+                return Ok(None);
+            }
+        };
+        let data = match std::fs::read(self.object_path(&hash)) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        if Sha256::digest(&data)[..] != hash[..] {
+            println!("Corrupted cache object {}, dropping it", hex(&hash));
+            self.corrupted += 1;
+            self.misses += 1;
+            self.drop_object(&hash)?;
+            self.persist()?;
+            return Ok(None);
+        }
+        self.hits += 1;
+        self.tick += 1;
+        if let Some(obj) = self.objects.get_mut(&hash) {
+            // Recency is only persisted along with the next change to the index
+            obj.last_used = self.tick;
+        }
+        Ok(Some(data))
+    }
+
+    pub fn put(&mut self, key: RemotePath, modified: u64, data: &[u8]) -> io::Result<Hash> {
+        let hash: Hash = Sha256::digest(data).into();
+        self.tick += 1;
+        if let Some(obj) = self.objects.get_mut(&hash) {
+            obj.last_used = self.tick;
+        } else {
+            write_atomic(&self.object_path(&hash), data)?;
+            self.used += data.len() as u64;
+            self.objects.insert(
+                hash,
+                Object {
+                    size: data.len() as u64,
+                    last_used: self.tick,
+                },
+            );
+        }
+        let r = self.refs.entry(key).or_default();
+        let previous = r.hash.replace(hash);
+        r.modified = modified;
+        if let Some(previous) = previous.filter(|p| *p != hash) {
+            self.release(&previous)?;
+        }
+        self.evict(Some(&hash))?;
+        self.persist()?;
+        Ok(hash)
+    }
+
+    // Returns whether the content of the file is available locally
+    pub fn pin(&mut self, workspace_id: WorkspaceId, key: RemotePath, pinned: bool) -> io::Result<bool> {
+        let cached = match self.refs.get_mut(&key) {
+            Some(r) => {
+                r.pinned = pinned;
+                r.workspace_id = workspace_id;
+                let cached = r.hash.is_some();
+                if !pinned && !cached {
+                    self.refs.remove(&key);
+                }
+                cached
+            }
+            None if pinned => {
+                self.refs.insert(
+                    key,
+                    Ref {
+                        workspace_id,
+                        pinned,
+                        ..Default::default()
+                    },
+                );
+                false
+            }
+            None => false,
+        };
+        if !pinned {
+            self.evict(None)?;
+        }
+        self.persist()?;
+        Ok(cached)
+    }
+
+    // Modification time of the cached copy of the file
+    pub fn modified(&self, key: &RemotePath) -> Option<u64> {
+        self.refs.get(key).filter(|r| r.hash.is_some()).map(|r| r.modified)
+    }
+
+    // Peer a cached file was pulled from, when the owner of its shelf is not known
+    // anymore (e.g. after a restart while offline)
+    pub fn owner(&self, shelf_id: ShelfId, path: &Path) -> Option<NodeId> {
+        self.refs
+            .keys()
+            .find(|(_, s_id, p)| *s_id == shelf_id && p == path)
+            .map(|(node, _, _)| *node)
+    }
+
+    pub fn is_pinned(&self, key: &RemotePath) -> bool {
+        self.refs.get(key).is_some_and(|r| r.pinned)
+    }
+
+    // Pinned files whose content has not been fetched yet
+    pub fn missing_pins(&self) -> Vec<(WorkspaceId, RemotePath)> {
+        self.refs
+            .iter()
+            .filter(|(_, r)| r.pinned && r.hash.is_none())
+            .map(|(key, r)| (r.workspace_id, key.clone()))
+            .collect()
+    }
+
+    fn is_protected(&self, hash: &Hash) -> bool {
+        self.refs.values().any(|r| r.pinned && r.hash.as_ref() == Some(hash))
+    }
+
+    // Delete hash once no file refers to it anymore
+    fn release(&mut self, hash: &Hash) -> io::Result<()> {
+        if self.refs.values().any(|r| r.hash.as_ref() == Some(hash)) {
+            return Ok(());
+        }
+        self.remove_object(hash)
+    }
+
+    fn remove_object(&mut self, hash: &Hash) -> io::Result<()> {
+        if let Some(obj) = self.objects.remove(hash) {
+            self.used -= obj.size;
+            match std::fs::remove_file(self.object_path(hash)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Forget hash along with the files referring to it, pins excepted
+    fn drop_object(&mut self, hash: &Hash) -> io::Result<()> {
+        self.refs.retain(|_, r| {
+            if r.hash.as_ref() == Some(hash) {
+                r.hash = None;
+            }
+            r.hash.is_some() || r.pinned
+        });
+        self.remove_object(hash)
+    }
+
+    // Evict unpinned objects, least recently used first, until under the limit.
+    // keep (the object just stored) is evicted last.
+    fn evict(&mut self, keep: Option<&Hash>) -> io::Result<()> {
+        if self.used <= self.limit {
+            return Ok(());
+        }
+        let mut candidates: Vec<(u64, Hash)> = self
+            .objects
+            .iter()
+            .filter(|(hash, _)| Some(*hash) != keep && !self.is_protected(hash))
+            .map(|(hash, obj)| (obj.last_used, *hash))
+            .collect();
+        candidates.sort();
+        if let Some(keep) = keep.filter(|k| !self.is_protected(k) && self.objects.contains_key(*k)) {
+            candidates.push((u64::MAX, *keep));
+        }
+        for (_, hash) in candidates {
+            if self.used <= self.limit {
+                break;
+            }
+            self.drop_object(&hash)?;
+            self.evictions += 1;
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> ContentStats {
+        ContentStats {
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            entries: self.objects.len() as u64,
+            used: self.used,
+            budget: self.limit,
+            corrupted: self.corrupted,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::SecretKey;
+
+    fn key(path: &str) -> RemotePath {
+        (SecretKey::from_bytes(&[1; 32]).public(), 2, PathBuf::from(path))
+    }
+
+    fn store(limit: u64) -> ContentStore {
+        let dir = std::env::temp_dir().join(format!("ebi-content-{}", rand::random::<u64>()));
+        ContentStore::open(dir, limit).unwrap()
+    }
+
+    #[test]
+    fn content_is_checked_against_its_hash() {
+        let mut store = store(1024);
+        let hash = store.put(key("a"), 1, b"hello").unwrap();
+        assert_eq!(hash[..], Sha256::digest(b"hello")[..]);
+        assert_eq!(store.get(&key("a"), Some(1)).unwrap(), Some(b"hello".to_vec()));
+        std::fs::write(store.object_path(&hash), b"jello").unwrap();
+        assert_eq!(store.get(&key("a"), Some(1)).unwrap(), None);
+        assert_eq!(store.modified(&key("a")), None);
+        let stats = store.stats();
+        assert_eq!((stats.hits, stats.misses, stats.corrupted, stats.used), (1, 1, 1, 0));
+        std::fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn objects_are_shared_and_versioned() {
+        let mut store = store(1024);
+        let hash = store.put(key("a"), 1, b"same").unwrap();
+        assert_eq!(store.put(key("b"), 1, b"same").unwrap(), hash);
+        assert_eq!((store.stats().entries, store.stats().used), (1, 4));
+        assert_eq!(store.get(&key("a"), Some(2)).unwrap(), None);
+        // Offline, any version will do
+        assert_eq!(store.get(&key("a"), None).unwrap(), Some(b"same".to_vec()));
+        // The object stays as long as a file refers to it
+        store.put(key("a"), 2, b"other").unwrap();
+        assert_eq!(store.get(&key("b"), Some(1)).unwrap(), Some(b"same".to_vec()));
+        store.put(key("b"), 2, b"other").unwrap();
+        assert_eq!((store.stats().entries, store.stats().used), (1, 5));
+        std::fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn pinned_objects_are_never_evicted() {
+        let mut store = store(10);
+        store.put(key("a"), 1, b"aaaa").unwrap();
+        assert!(store.pin(1, key("a"), true).unwrap());
+        store.put(key("b"), 1, b"bbbb").unwrap();
+        store.put(key("c"), 1, b"cccc").unwrap();
+        assert!(store.get(&key("a"), Some(1)).unwrap().is_some());
+        assert!(store.get(&key("b"), Some(1)).unwrap().is_none());
+        assert!(store.get(&key("c"), Some(1)).unwrap().is_some());
+        assert_eq!(store.stats().evictions, 1);
+        // A pin of a file not cached yet is kept for fetching
+        assert!(!store.pin(1, key("d"), true).unwrap());
+        assert_eq!(store.missing_pins(), vec![(1, key("d"))]);
+        std::fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn index_reloads() {
+        let mut saved = store(1024);
+        saved.put(key("a"), 3, b"hello").unwrap();
+        saved.pin(1, key("a"), true).unwrap();
+        let mut loaded = ContentStore::open(saved.dir.clone(), 1024).unwrap();
+        assert_eq!(loaded.modified(&key("a")), Some(3));
+        assert!(loaded.is_pinned(&key("a")));
+        assert_eq!(loaded.get(&key("a"), Some(3)).unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(loaded.owner(2, Path::new("a")), Some(key("a").0));
+        std::fs::remove_dir_all(&saved.dir).unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+// Map bounded by the total weight (approximate size in bytes) of its values. When an
+// insertion exceeds the budget, the least recently used entries are evicted first.
+#[derive(Debug)]
+pub struct Lru<K, V> {
+    budget: usize,
+    used: usize,
+    tick: u64,
+    entries: HashMap<K, Entry<V>>,
+    recency: BTreeMap<u64, K>, // Last access tick -> key, oldest first
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+#[derive(Debug)]
+struct Entry<V> {
+    value: V,
+    weight: usize,
+    tick: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LruStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: u64,
+    pub used: u64,
+    pub budget: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> Lru<K, V> {
+    pub fn new(budget: usize) -> Self {
+        Lru {
+            budget,
+            used: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        self.get_valid(key, |_| true)
+    }
+
+    // Entries failing is_valid are dropped and counted as misses
+    pub fn get_valid<F: FnOnce(&V) -> bool>(&mut self, key: &K, is_valid: F) -> Option<&V> {
+        let valid = match self.entries.get(key) {
+            Some(entry) => is_valid(&entry.value),
+            None => {
+                self.misses += 1;
+                return None;
+            }
+        };
+        if !valid {
+            self.remove(key);
+            self.misses += 1;
+            return None;
+        }
+        self.hits += 1;
+        self.tick += 1;
+        let entry = self.entries.get_mut(key)?;
+        self.recency.remove(&entry.tick);
+        entry.tick = self.tick;
+        self.recency.insert(self.tick, key.clone());
+        Some(&entry.value)
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    // Values heavier than the whole budget are not cached
+    pub fn insert(&mut self, key: K, value: V, weight: usize) -> bool {
+        self.remove(&key);
+        if weight > self.budget {
+            return false;
+        }
+        while self.used + weight > self.budget {
+            if self.evict().is_none() {
+                break;
+            }
+        }
+        self.tick += 1;
+        self.used += weight;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                weight,
+                tick: self.tick,
+            },
+        );
+        true
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.tick);
+        self.used -= entry.weight;
+        Some(entry.value)
+    }
+
+    // Drop the least recently used entry
+    pub fn evict(&mut self) -> Option<(K, V)> {
+        let (_, key) = self.recency.pop_first()?;
+        let entry = self.entries.remove(&key)?;
+        self.used -= entry.weight;
+        self.evictions += 1;
+        Some((key, entry.value))
+    }
+
+    pub fn retain<F: FnMut(&K, &V) -> bool>(&mut self, mut keep: F) {
+        let dropped: Vec<K> = self
+            .entries
+            .iter()
+            .filter(|(key, entry)| !keep(key, &entry.value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in dropped {
+            self.remove(&key);
+        }
+    }
+
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        while self.used > self.budget {
+            if self.evict().is_none() {
+                break;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> LruStats {
+        LruStats {
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            entries: self.entries.len() as u64,
+            used: self.used as u64,
+            budget: self.budget as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_is_evicted_first() {
+        let mut lru = Lru::new(30);
+        lru.insert("a", 1, 10);
+        lru.insert("b", 2, 10);
+        lru.insert("c", 3, 10);
+        assert_eq!(lru.get(&"a"), Some(&1));
+        lru.insert("d", 4, 10);
+        assert!(!lru.contains(&"b"));
+        assert!(lru.contains(&"a") && lru.contains(&"c") && lru.contains(&"d"));
+        // Several entries make room for a heavy one
+        lru.insert("e", 5, 25);
+        assert!(lru.contains(&"e"));
+        assert_eq!(lru.stats().entries, 1);
+        let stats = lru.stats();
+        assert_eq!((stats.used, stats.evictions), (25, 4));
+    }
+
+    #[test]
+    fn budget_is_never_exceeded() {
+        let mut lru = Lru::new(20);
+        assert!(!lru.insert("heavy", 0, 21));
+        lru.insert("a", 1, 15);
+        // Replacing an entry releases its weight first
+        lru.insert("a", 2, 20);
+        assert_eq!(lru.get(&"a"), Some(&2));
+        assert_eq!(lru.stats().used, 20);
+        lru.set_budget(10);
+        assert_eq!((lru.stats().used, lru.stats().entries), (0, 0));
+    }
+
+    #[test]
+    fn invalid_entries_are_misses() {
+        let mut lru = Lru::new(20);
+        lru.insert("a", 1, 5);
+        lru.insert("b", 2, 5);
+        assert_eq!(lru.get_valid(&"a", |v| *v == 2), None);
+        assert!(!lru.contains(&"a"));
+        assert_eq!(lru.get(&"c"), None);
+        assert_eq!(lru.get(&"b"), Some(&2));
+        lru.retain(|_, v| *v != 2);
+        let stats = lru.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries, stats.used), (1, 2, 0, 0));
+    }
+}
@@ -1,21 +1,26 @@
 #![allow(dead_code)]
-use shelf::shelf::Shelf;
+// TagRef and FileRef wrap locks, but are keyed on fields that do not change while in a set
+#![allow(clippy::mutable_key_type)]
 use std::sync::Arc;
 use iroh::{SecretKey, Endpoint,
     endpoint::Connection,
     NodeId,
 };
 use tokio::net::{TcpListener, TcpStream};
-use tokio::task::JoinHandle;
 use anyhow::Result;
 use tokio::sync::{RwLock, Mutex};
 use tower::{Service, ServiceBuilder};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, AsyncBufReadExt, AsyncRead};
 use std::collections::HashMap;
 use crate::services::peer::{PeerService, Client};
-use crate::services::rpc::{RpcService, TaskID};
-use crate::rpc::{QueryRequest, RequestCode, EchoData};
+use crate::services::cache::{CacheConfig, CacheService};
+use crate::shelf::node::ScanConfig;
+use crate::services::workspace::WorkspaceService;
+use crate::tag::TagManager;
+use crate::services::rpc::{RpcService, Task, TaskID};
+use crate::rpc::{QueryRequest, RequestCode, EchoData, InviteRequest, RedeemRequest, TagRequest, PeerStatusRequest, MutationStatusRequest, ValidateRequest, CacheStatsRequest, PinRequest, WorkspaceRequest, CreateTagRequest, TaskRequest};
 use prost::Message;
 
 use std::time::Instant;
@@ -27,13 +32,20 @@ mod tag;
 mod services;
 mod workspace;
 mod rpc;
+mod sync;
+mod queue;
+mod lru;
+mod content;
+mod persist;
 
 const ALPN: &[u8] = b"ebi";
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let listener = TcpListener::bind("127.0.0.1:3000").await.unwrap();
-    let sec_key = get_secret_key();
+    let data_dir = data_dir();
+    std::fs::create_dir_all(&data_dir)?;
+    let sec_key = get_secret_key(&data_dir)?;
     let ep = Endpoint::builder()
         .discovery_n0()
         .secret_key(sec_key)
@@ -44,8 +56,16 @@ async fn main() -> Result<()> {
     println!("{:?}", ep.node_addr().await?.node_id);
     let peers = Arc::new(RwLock::new(HashMap::<NodeId, Connection>::new()));
     let clients = Arc::new(RwLock::new(Vec::<Client>::new()));
-    let tasks = Arc::new(HashMap::<TaskID, JoinHandle<()>>::new());
-    let service = ServiceBuilder::new().service(RpcService {  peer_service: PeerService { peers: peers.clone(), clients: clients.clone() }, tasks: tasks.clone() } );
+    let tasks = Arc::new(std::sync::RwLock::new(HashMap::<TaskID, Task>::new()));
+    TagManager::load(&data_dir)?;
+    let peer_service = PeerService::new(ep.clone(), peers.clone(), clients.clone(), &data_dir);
+    tokio::spawn(peer_service.clone().run_manager());
+    let cache_service = CacheService::new(peer_service.clone(), CacheConfig::from_env(), &data_dir)?;
+    tokio::spawn(cache_service.clone().run_pins());
+    let workspace_service = WorkspaceService::load(peer_service.clone(), cache_service.clone(), ScanConfig::from_env(), &data_dir).await?;
+    tokio::spawn(workspace_service.clone().run_applier());
+    tokio::spawn(workspace_service.clone().run_evictor());
+    let service = ServiceBuilder::new().service(RpcService {  peer_service: peer_service.clone(), cache_service, workspace_service, tasks: tasks.clone() } );
     loop {
         tokio::select! {
             Ok((stream, addr)) = listener.accept() => {
@@ -63,15 +83,16 @@ async fn main() -> Result<()> {
             },
             conn = ep.accept() => {
                 let conn = conn.unwrap().await?;
-                peers.write().await.insert(conn.remote_node_id().unwrap(), conn.clone());
-                let (mut send, mut recv) = conn.accept_bi().await?;
-                let msg = recv.read_to_end(100).await?;
+                let peer_service = peer_service.clone();
+                tokio::spawn(async move {
+                    peer_service.connected(conn).await;
+                });
             }
         }
     }
 }
 
-async fn handle_client(mut socket: Arc<Mutex<TcpStream>>, addr: SocketAddr, mut service: RpcService) {
+async fn handle_client(socket: Arc<Mutex<TcpStream>>, addr: SocketAddr, mut service: RpcService) {
     let mut header = vec![0; 9];
     let mut socket = socket.lock().await;
 
@@ -96,10 +117,83 @@ async fn handle_client(mut socket: Arc<Mutex<TcpStream>>, addr: SocketAddr, mut
         let req_res = match req_type.try_into() {
             Ok(RequestCode::Query) => {
                 let req = QueryRequest::decode(&*buffer).unwrap();
-                if let Ok(response) = service.call(req).await {
-                    let mut buf = Vec::new();
-                    response.encode(&mut buf).unwrap();
-                    let _ = socket.write_all(&buf).await;
+                // Results are sent unframed, errors framed like any other
+                match service.call(req).await {
+                    Ok(response) => {
+                        let mut buf = Vec::new();
+                        response.encode(&mut buf).unwrap();
+                        let _ = socket.write_all(&buf).await;
+                    }
+                    Err(err) => {
+                        let _ = write_response(&mut socket, RequestCode::Error, &err).await;
+                    }
+                }
+                Ok(())
+            }
+            Ok(RequestCode::CreateInvite) => {
+                let req = InviteRequest::decode(&*buffer).unwrap();
+                let _ = reply(&mut socket, RequestCode::CreateInvite, service.call(req).await).await;
+                Ok(())
+            }
+            Ok(RequestCode::RedeemInvite) => {
+                let req = RedeemRequest::decode(&*buffer).unwrap();
+                let _ = reply(&mut socket, RequestCode::RedeemInvite, service.call(req).await).await;
+                Ok(())
+            }
+            Ok(RequestCode::Tag) => {
+                let req = TagRequest::decode(&*buffer).unwrap();
+                let _ = reply(&mut socket, RequestCode::Tag, service.call(req).await).await;
+                Ok(())
+            }
+            Ok(RequestCode::PeerStatus) => {
+                let req = PeerStatusRequest::decode(&*buffer).unwrap();
+                let _ = reply(&mut socket, RequestCode::PeerStatus, service.call(req).await).await;
+                Ok(())
+            }
+            Ok(RequestCode::MutationStatus) => {
+                let req = MutationStatusRequest::decode(&*buffer).unwrap();
+                let _ = reply(&mut socket, RequestCode::MutationStatus, service.call(req).await).await;
+                Ok(())
+            }
+            Ok(RequestCode::Validate) => {
+                let req = ValidateRequest::decode(&*buffer).unwrap();
+                let _ = reply(&mut socket, RequestCode::Validate, service.call(req).await).await;
+                Ok(())
+            }
+            Ok(RequestCode::CacheStats) => {
+                let req = CacheStatsRequest::decode(&*buffer).unwrap();
+                let _ = reply(&mut socket, RequestCode::CacheStats, service.call(req).await).await;
+                Ok(())
+            }
+            Ok(RequestCode::Pin) => {
+                let req = PinRequest::decode(&*buffer).unwrap();
+                let _ = reply(&mut socket, RequestCode::Pin, service.call(req).await).await;
+                Ok(())
+            }
+            Ok(RequestCode::Workspace) => {
+                let req = WorkspaceRequest::decode(&*buffer).unwrap();
+                let _ = reply(&mut socket, RequestCode::Workspace, service.call(req).await).await;
+                Ok(())
+            }
+            Ok(RequestCode::CreateTag) => {
+                let req = CreateTagRequest::decode(&*buffer).unwrap();
+                let _ = reply(&mut socket, RequestCode::CreateTag, service.call(req).await).await;
+                Ok(())
+            }
+            Ok(RequestCode::Task) => {
+                let req = TaskRequest::decode(&*buffer).unwrap();
+                match service.call(req).await {
+                    // The connection follows the task until it is over
+                    Ok(mut reports) => {
+                        while let Some(report) = reports.recv().await {
+                            if write_response(&mut socket, RequestCode::Task, &report).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        let _ = write_response(&mut socket, RequestCode::Error, &err).await;
+                    }
                 }
                 Ok(())
             }
@@ -120,7 +214,7 @@ async fn handle_client(mut socket: Arc<Mutex<TcpStream>>, addr: SocketAddr, mut
                 }
                 Ok(())
             }
-            Err(_) => {
+            Ok(RequestCode::Error) | Err(_) => {
                 println!("Unknown header {}", req_type);
                 Err(())
             }
@@ -131,7 +225,53 @@ async fn handle_client(mut socket: Arc<Mutex<TcpStream>>, addr: SocketAddr, mut
     println!("Client disconnected: {}", addr);
 }
 
-fn get_secret_key() -> SecretKey {
-    let mut rng = rand::rngs::OsRng;
-    iroh_base::SecretKey::generate(&mut rng)
+// Responses are framed like requests: 1 byte code, 8 bytes (LE) size, payload
+async fn write_response<M: Message>(socket: &mut TcpStream, code: RequestCode, msg: &M) -> std::io::Result<()> {
+    let payload = msg.encode_to_vec();
+    let mut buf = Vec::with_capacity(9 + payload.len());
+    buf.push(code as u8);
+    buf.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    buf.extend_from_slice(&payload);
+    socket.write_all(&buf).await
+}
+
+// Failed requests are answered with an Error frame instead of their response
+async fn reply<M: Message>(socket: &mut TcpStream, code: RequestCode, res: Result<M, rpc::Error>) -> std::io::Result<()> {
+    match res {
+        Ok(response) => write_response(socket, code, &response).await,
+        Err(err) => write_response(socket, RequestCode::Error, &err).await,
+    }
+}
+
+// Daemon state directory: $EBI_DATA_DIR, or ~/.local/share/ebi
+fn data_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("EBI_DATA_DIR") {
+        return PathBuf::from(dir);
+    }
+    let home = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+    home.join(".local").join("share").join("ebi")
+}
+
+// Node identity, generated on the first start: peers trust our NodeId, so it must not change
+fn get_secret_key(data_dir: &Path) -> std::io::Result<SecretKey> {
+    let path = data_dir.join("secret.key");
+    match std::fs::read(&path) {
+        Ok(buf) => {
+            let bytes: [u8; 32] = buf
+                .try_into()
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "malformed secret key"))?;
+            Ok(SecretKey::from_bytes(&bytes))
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let mut rng = rand::rngs::OsRng;
+            let key = iroh_base::SecretKey::generate(&mut rng);
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            std::io::Write::write_all(&mut options.open(&path)?, &key.to_bytes())?;
+            Ok(key)
+        }
+        Err(err) => Err(err),
+    }
 }
@@ -1,12 +1,21 @@
+use crate::rpc;
 use crate::shelf::file::{FileMetadata, FileRef};
+use crate::shelf::shelf::UpdateErr;
+use crate::shelf::summary::ShelfSummary;
 use crate::tag::{TagManager, TagRef};
+use crate::workspace::WorkspaceId;
+use iroh::NodeId;
+use roaring::RoaringBitmap;
 use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::fmt::Binary;
+use std::fmt::{self, Debug};
 use std::path::PathBuf;
 use std::result;
+use std::future::Future;
+use std::pin::Pin;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Order {
     Ascending,
     Descending,
@@ -14,48 +23,48 @@ pub enum Order {
 
 pub trait FileOrder {}
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Name {
     pub order: Order,
 }
 
 impl FileOrder for Name {}
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Size {
     pub order: Order,
 }
 
 impl FileOrder for Size {}
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Modified {
     pub order: Order,
 }
 
 impl FileOrder for Modified {}
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Created {
     pub order: Order,
 }
 
 impl FileOrder for Created {}
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Accessed {
     pub order: Order,
 }
 
 impl FileOrder for Accessed {}
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Unordered;
 
 impl FileOrder for Unordered {}
 
 peg::parser! {
-    grammar tag_query() for str {
+    grammar tag_query(workspace_id: WorkspaceId) for str {
         pub rule expression() -> Formula
             = precedence! {
                 x:(@) _ "OR" _ y:@ { Formula::BinaryExpression((BinaryOp::OR), (Box::new(x)), (Box::new(y))) }
@@ -66,13 +75,22 @@ peg::parser! {
                 --
                 "NOT" _ x:@ { Formula::UnaryExpression((UnaryOp::NOT), (Box::new(x))) }
                 --
-                t:term() {
-                    Formula::Proposition(Proposition { tag: TagManager::retrieve_tag(t) })
-                }
+                "UNTAGGED" { Formula::Untagged }
+                --
+                p:proposition() { p }
                 --
                 "(" _ e:expression() _ ")" { e }
             }
 
+        rule proposition() -> Formula
+            = start:position!() t:term() end:position!() {
+                Formula::Proposition(Proposition {
+                    tag: TagManager::retrieve_tag(workspace_id, t),
+                    name: t.to_string(),
+                    span: (start, end),
+                })
+            }
+
         rule term() -> &'input str
             = "\"" t:$([^ '"']+) "\"" { t }
 
@@ -83,11 +101,12 @@ peg::parser! {
 #[derive(Debug, Clone)]
 enum Formula {
     Proposition(Proposition),
+    Untagged, // Files with neither tags nor dtags
     BinaryExpression(BinaryOp, Box<Formula>, Box<Formula>),
     UnaryExpression(UnaryOp, Box<Formula>),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 enum BinaryOp {
     AND,
     OR,
@@ -100,7 +119,9 @@ enum UnaryOp {
 
 #[derive(Debug, Eq, PartialEq, Hash, Clone)]
 struct Proposition {
-    tag: Option<TagRef>,
+    tag: Option<TagRef>, // None if no tag of that name is visible in the workspace
+    name: String,
+    span: (usize, usize), // Byte range of the quoted name in the query
 }
 
 #[derive(Debug, Clone)]
@@ -114,9 +135,19 @@ pub struct OrderedFileID<FileOrder> {
     order: FileOrder,
 }
 
+impl<T> OrderedFileID<T> {
+    pub fn new(file_id: FileID, order: T) -> Self {
+        OrderedFileID { file_id, order }
+    }
+
+    pub fn file_id(&self) -> &FileID {
+        &self.file_id
+    }
+}
+
 impl PartialEq for OrderedFileID<Name> {
     fn eq(&self, other: &Self) -> bool {
-        self.file_id.path.file_name() == other.file_id.path.file_name() //[!] path.file_name() is an Option (can, theoretically, be None)
+        self.file_id.path.file_name() == other.file_id.path.file_name() && self.file_id.path == other.file_id.path
     }
 }
 
@@ -124,29 +155,22 @@ impl Eq for OrderedFileID<Name> {}
 
 impl PartialOrd for OrderedFileID<Name> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(
-            self.file_id
-                .path
-                .file_name()
-                .unwrap()
-                .cmp(other.file_id.path.file_name().unwrap()),
-        )
+        Some(self.cmp(other))
     }
 }
 
+// Ties are broken by path, so that distinct files never collapse in a set
 impl Ord for OrderedFileID<Name> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.file_id
-            .path
-            .file_name()
-            .unwrap()
-            .cmp(other.file_id.path.file_name().unwrap())
+        self.file_id.path.file_name()
+            .cmp(&other.file_id.path.file_name())
+            .then_with(|| self.file_id.path.cmp(&other.file_id.path))
     }
 }
 
 impl PartialEq for OrderedFileID<Size> {
     fn eq(&self, other: &Self) -> bool {
-        self.file_id.metadata.size == other.file_id.metadata.size
+        self.file_id.metadata.size == other.file_id.metadata.size && self.file_id.path == other.file_id.path
     }
 }
 
@@ -154,19 +178,22 @@ impl Eq for OrderedFileID<Size> {}
 
 impl PartialOrd for OrderedFileID<Size> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.file_id.metadata.size.cmp(&other.file_id.metadata.size))
+        Some(self.cmp(other))
     }
 }
 
+// Ties are broken by path, so that distinct files never collapse in a set
 impl Ord for OrderedFileID<Size> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.file_id.metadata.size.cmp(&other.file_id.metadata.size)
+        self.file_id.metadata.size
+            .cmp(&other.file_id.metadata.size)
+            .then_with(|| self.file_id.path.cmp(&other.file_id.path))
     }
 }
 
 impl PartialEq for OrderedFileID<Modified> {
     fn eq(&self, other: &Self) -> bool {
-        self.file_id.metadata.modified == other.file_id.metadata.modified
+        self.file_id.metadata.modified == other.file_id.metadata.modified && self.file_id.path == other.file_id.path
     }
 }
 
@@ -174,27 +201,22 @@ impl Eq for OrderedFileID<Modified> {}
 
 impl PartialOrd for OrderedFileID<Modified> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(
-            self.file_id
-                .metadata
-                .modified
-                .cmp(&other.file_id.metadata.modified),
-        )
+        Some(self.cmp(other))
     }
 }
 
+// Ties are broken by path, so that distinct files never collapse in a set
 impl Ord for OrderedFileID<Modified> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.file_id
-            .metadata
-            .modified
+        self.file_id.metadata.modified
             .cmp(&other.file_id.metadata.modified)
+            .then_with(|| self.file_id.path.cmp(&other.file_id.path))
     }
 }
 
 impl PartialEq for OrderedFileID<Created> {
     fn eq(&self, other: &Self) -> bool {
-        self.file_id.metadata.created == other.file_id.metadata.created
+        self.file_id.metadata.created == other.file_id.metadata.created && self.file_id.path == other.file_id.path
     }
 }
 
@@ -202,27 +224,22 @@ impl Eq for OrderedFileID<Created> {}
 
 impl PartialOrd for OrderedFileID<Created> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(
-            self.file_id
-                .metadata
-                .created
-                .cmp(&other.file_id.metadata.created),
-        )
+        Some(self.cmp(other))
     }
 }
 
+// Ties are broken by path, so that distinct files never collapse in a set
 impl Ord for OrderedFileID<Created> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.file_id
-            .metadata
-            .created
+        self.file_id.metadata.created
             .cmp(&other.file_id.metadata.created)
+            .then_with(|| self.file_id.path.cmp(&other.file_id.path))
     }
 }
 
 impl PartialEq for OrderedFileID<Accessed> {
     fn eq(&self, other: &Self) -> bool {
-        self.file_id.metadata.accessed == other.file_id.metadata.accessed
+        self.file_id.metadata.accessed == other.file_id.metadata.accessed && self.file_id.path == other.file_id.path
     }
 }
 
@@ -230,46 +247,41 @@ impl Eq for OrderedFileID<Accessed> {}
 
 impl PartialOrd for OrderedFileID<Accessed> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(
-            self.file_id
-                .metadata
-                .accessed
-                .cmp(&other.file_id.metadata.accessed),
-        )
+        Some(self.cmp(other))
     }
 }
 
+// Ties are broken by path, so that distinct files never collapse in a set
 impl Ord for OrderedFileID<Accessed> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.file_id
-            .metadata
-            .accessed
+        self.file_id.metadata.accessed
             .cmp(&other.file_id.metadata.accessed)
+            .then_with(|| self.file_id.path.cmp(&other.file_id.path))
     }
 }
 
 impl PartialEq for OrderedFileID<Unordered> {
-    fn eq(&self, _other: &Self) -> bool {
-        true
+    fn eq(&self, other: &Self) -> bool {
+        self.file_id.path == other.file_id.path
     }
 }
 
 impl Eq for OrderedFileID<Unordered> {}
 
 impl PartialOrd for OrderedFileID<Unordered> {
-    fn partial_cmp(&self, _other: &Self) -> Option<std::cmp::Ordering> {
-        Some(std::cmp::Ordering::Equal)
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for OrderedFileID<Unordered> {
-    fn cmp(&self, _other: &Self) -> std::cmp::Ordering {
-        std::cmp::Ordering::Equal
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.file_id.path.cmp(&other.file_id.path)
     }
 }
 
 #[derive(Debug, Clone)]
-struct FileID {
+pub struct FileID {
     root: Option<PeerID>, //[/] Whether the file is local or remote
     path: PathBuf,
     metadata: FileMetadata,
@@ -283,8 +295,36 @@ impl FileID {
             metadata,
         }
     }
+
+    pub fn local(path: PathBuf, metadata: FileMetadata) -> Self {
+        FileID::new(None, path, metadata)
+    }
+
+    pub fn remote(node: NodeId, path: PathBuf, metadata: FileMetadata) -> Self {
+        FileID::new(Some(PeerID { id: node.to_string() }), path, metadata)
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn metadata(&self) -> &FileMetadata {
+        &self.metadata
+    }
+}
+
+impl From<&FileID> for rpc::File {
+    fn from(file: &FileID) -> Self {
+        rpc::File {
+            path: file.path.to_string_lossy().into_owned(),
+            metadata: Some((&file.metadata).into()),
+            pending: false,
+        }
+    }
 }
 
+type Evaluation = Pin<Box<dyn Future<Output = Result<RoaringBitmap, QueryErr>> + Send>>;
+
 pub struct Query<T: FileOrder + Clone> {
     formula: Formula,
     order: T,
@@ -292,8 +332,12 @@ pub struct Query<T: FileOrder + Clone> {
 }
 
 impl<T: FileOrder + Clone> Query<T> {
-    pub fn new(query: &str, order: T) -> Result<Self, QueryErr> {
-        let formula = tag_query::expression(query).or_else(|_err| Err(QueryErr::SyntaxError))?;
+    // Tags are resolved within the workspace the query runs in
+    pub fn new(query: &str, order: T, workspace_id: WorkspaceId) -> Result<Self, QueryErr> {
+        let formula = tag_query::expression(query, workspace_id).map_err(|err| QueryErr::Syntax {
+            offset: err.location.offset,
+            expected: err.expected.to_string(),
+        })?;
         Ok(Query {
             formula,
             order,
@@ -301,74 +345,94 @@ impl<T: FileOrder + Clone> Query<T> {
         })
     }
 
+    // Identical for queries with the same result, up to the order of the operands of
+    // associative operators
+    pub fn key(&mut self) -> String
+    where
+        T: Debug,
+    {
+        self.simplify();
+        format!("{}|{:?}", self.formula.canonical(), self.order)
+    }
+
+    // Tag names with no tag in the workspace, which only other peers may know, with
+    // their span in the query
+    pub fn unresolved(&self) -> Vec<(&str, (usize, usize))> {
+        let mut acc = Vec::new();
+        self.formula.unresolved(&mut acc);
+        acc
+    }
+
+    // Whether a shelf with the given summary may hold files satisfying the query
+    pub fn may_match(&self, workspace_id: WorkspaceId, summary: &ShelfSummary) -> bool {
+        self.formula.may_match(workspace_id, summary)
+    }
+
+    // Set operations run on file id bitmaps, the result is only sorted once at the end
     pub async fn evaluate<R>(&mut self, ret_service: R) -> Result<BTreeSet<OrderedFileID<T>>, QueryErr>
     where
+        T: Send + Sync + 'static,
         OrderedFileID<T>: Ord,
-        R: RetrieveService + Clone
+        R: RetrieveService + Clone + Send + 'static
     {
         self.simplify();
-        Query::recursive_evaluate(self.formula.clone(), ret_service.clone()).await
+        let files = Query::<T>::recursive_evaluate(self.formula.clone(), ret_service.clone()).await?;
+        ret_service.sort(files, self.order.clone()).await
     }
 
-    async fn recursive_evaluate<R>(formula: Formula, ret_service: R) -> Result<BTreeSet<OrderedFileID<T>>, QueryErr>
+    // Boxed, as the evaluation recurses on the sub-formulas
+    fn recursive_evaluate<R>(formula: Formula, ret_service: R) -> Evaluation
     where
-        OrderedFileID<T>: Ord,
-        R: RetrieveService + Clone
+        R: RetrieveService + Clone + Send + 'static
     {
-        match formula {
-            Formula::BinaryExpression(BinaryOp::AND, x, y) => match (*x.clone(), *y.clone()) {
-                (_, Formula::UnaryExpression(UnaryOp::NOT, b)) => {
-                    let a = Query::recursive_evaluate(*x.clone(), ret_service.clone()).await?;
-                    let b = Query::recursive_evaluate(*b.clone(), ret_service.clone()).await?;
-                    let x: BTreeSet<OrderedFileID<T>> = a.difference(&b).cloned().collect();
-                    Ok(x)
-                }
-                (Formula::UnaryExpression(UnaryOp::NOT, a), _) => {
-                    let a = Query::recursive_evaluate(*a.clone(), ret_service.clone()).await?;
-                    let b = Query::recursive_evaluate(*y.clone(), ret_service.clone()).await?;
-                    let x: BTreeSet<OrderedFileID<T>> = b.difference(&a).cloned().collect();
-                    Ok(x)
+        Box::pin(async move {
+            match formula {
+                Formula::BinaryExpression(BinaryOp::AND, x, y) => match (*x.clone(), *y.clone()) {
+                    (_, Formula::UnaryExpression(UnaryOp::NOT, b)) => {
+                        let a = Query::<T>::recursive_evaluate(*x, ret_service.clone()).await?;
+                        let b = Query::<T>::recursive_evaluate(*b, ret_service).await?;
+                        Ok(a - b)
+                    }
+                    (Formula::UnaryExpression(UnaryOp::NOT, a), _) => {
+                        let a = Query::<T>::recursive_evaluate(*a, ret_service.clone()).await?;
+                        let b = Query::<T>::recursive_evaluate(*y, ret_service).await?;
+                        Ok(b - a)
+                    }
+                    (a, b) => {
+                        let a = Query::<T>::recursive_evaluate(a, ret_service.clone()).await?;
+                        let b = Query::<T>::recursive_evaluate(b, ret_service).await?;
+                        Ok(a & b)
+                    }
+                },
+                Formula::BinaryExpression(BinaryOp::OR, x, y) => {
+                    let a = Query::<T>::recursive_evaluate(*x, ret_service.clone()).await?;
+                    let b = Query::<T>::recursive_evaluate(*y, ret_service).await?;
+                    Ok(a | b)
                 }
-                (a, b) => {
-                    let a = Query::recursive_evaluate(a.clone(), ret_service.clone()).await?;
-                    let b = Query::recursive_evaluate(b.clone(), ret_service.clone()).await?;
-                    let x: BTreeSet<OrderedFileID<T>> = a.intersection(&b).cloned().collect();
-                    Ok(x)
+                Formula::BinaryExpression(BinaryOp::XOR, x, y) => {
+                    let a = Query::<T>::recursive_evaluate(*x, ret_service.clone()).await?;
+                    let b = Query::<T>::recursive_evaluate(*y, ret_service).await?;
+                    Ok(a ^ b)
                 }
-            },
-            Formula::BinaryExpression(BinaryOp::OR, x, y) => {
-                let a = Query::recursive_evaluate(*x.clone(), ret_service.clone()).await?;
-                let b = Query::recursive_evaluate(*y.clone(), ret_service.clone()).await?;
-                let x: BTreeSet<OrderedFileID<T>> = a.union(&b).cloned().collect();
-                Ok(x)
-            }
-            Formula::BinaryExpression(BinaryOp::XOR, x, y) => {
-                let a = Query::recursive_evaluate(*x.clone(), ret_service.clone()).await?;
-                let b = Query::recursive_evaluate(*y.clone(), ret_service.clone()).await?;
-                let x: BTreeSet<OrderedFileID<T>> = a.symmetric_difference(&b).cloned().collect();
-                Ok(x)
-            }
-            Formula::UnaryExpression(UnaryOp::NOT, x) => {
-                let a = ret_service.get_all().await?;
-                let b = Query::recursive_evaluate(*x.clone(), ret_service).await?;
-                let x: BTreeSet<OrderedFileID<T>> = a.difference(&b).cloned().collect();
-                Ok(x)
-            }
-            Formula::Proposition(p) => {
-                if let Some(tag) = p.tag {
-                    ret_service.get_files(tag.clone()).await.map_err(|_| QueryErr::KeyError)
-                } else {
-                    return Err(QueryErr::KeyError);
+                Formula::UnaryExpression(UnaryOp::NOT, x) => {
+                    let a = ret_service.get_all().await?;
+                    let b = Query::<T>::recursive_evaluate(*x, ret_service).await?;
+                    Ok(a - b)
                 }
+                Formula::Untagged => ret_service.get_untagged().await,
+                Formula::Proposition(p) => match p.tag {
+                    Some(tag) => ret_service.get_files(tag).await,
+                    None => ret_service.get_named(&p.name).await,
+                },
             }
-        }
+        })
     }
 
-    fn simplify(&mut self) -> () {
+    fn simplify(&mut self) {
         loop {
             let simplified_formula = Formula::recursive_simplify(self.formula.clone());
             self.formula = simplified_formula.0;
-            if simplified_formula.1 {
+            if !simplified_formula.1 {
                 break;
             }
         }
@@ -376,12 +440,66 @@ impl<T: FileOrder + Clone> Query<T> {
 }
 
 impl Formula {
+    fn canonical(&self) -> String {
+        match self {
+            Formula::Proposition(p) => match &p.tag {
+                Some(tag) => format!("#{}", tag.id()),
+                None => "#?".to_string(),
+            },
+            Formula::Untagged => "UNTAGGED".to_string(),
+            Formula::BinaryExpression(op, _, _) => {
+                let mut operands = Vec::new();
+                self.operands(op, &mut operands);
+                let mut operands: Vec<String> = operands.iter().map(|f| f.canonical()).collect();
+                operands.sort();
+                format!("({:?} {})", op, operands.join(" "))
+            }
+            Formula::UnaryExpression(op, x) => format!("({:?} {})", op, x.canonical()),
+        }
+    }
+
+    // Operands of a chain of the same binary operator, e.g. a, b, c for a AND (b AND c)
+    fn operands<'a>(&'a self, op: &BinaryOp, acc: &mut Vec<&'a Formula>) {
+        match self {
+            Formula::BinaryExpression(o, x, y) if o == op => {
+                x.operands(op, acc);
+                y.operands(op, acc);
+            }
+            f => acc.push(f),
+        }
+    }
+
+    fn unresolved<'a>(&'a self, acc: &mut Vec<(&'a str, (usize, usize))>) {
+        match self {
+            Formula::Proposition(p) if p.tag.is_none() => acc.push((&p.name, p.span)),
+            Formula::Proposition(_) | Formula::Untagged => {}
+            Formula::BinaryExpression(_, x, y) => {
+                x.unresolved(acc);
+                y.unresolved(acc);
+            }
+            Formula::UnaryExpression(_, x) => x.unresolved(acc),
+        }
+    }
+
+    // Conservative: false only when no file on the shelf can satisfy the formula
+    fn may_match(&self, workspace_id: WorkspaceId, summary: &ShelfSummary) -> bool {
+        match self {
+            Formula::Proposition(p) => summary.may_contain(workspace_id, &p.name),
+            Formula::Untagged => summary.untagged > 0,
+            Formula::BinaryExpression(BinaryOp::AND, x, y) => {
+                x.may_match(workspace_id, summary) && y.may_match(workspace_id, summary)
+            }
+            Formula::BinaryExpression(_, x, y) => {
+                x.may_match(workspace_id, summary) || y.may_match(workspace_id, summary)
+            }
+            Formula::UnaryExpression(UnaryOp::NOT, _) => summary.file_count > 0,
+        }
+    }
+
     fn recursive_simplify(formula: Formula) -> (Formula, bool) {
         //[/] Further simplification is possible but NP-Hard 
         match formula {
-            Formula::Proposition(_) => {
-                return (formula, false);
-            }
+            Formula::Proposition(_) | Formula::Untagged => (formula, false),
             Formula::BinaryExpression(BinaryOp::AND, x, y) => match *x.clone() {
                 // De Morgan's Law (AND)
                 Formula::UnaryExpression(UnaryOp::NOT, a) => match *y {
@@ -516,21 +634,233 @@ impl Formula {
     }
 }
 
-// TODO: define appropriate errors, include I/O, etc.
+// Failed query, with the part of the query or the shelf it is about
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum QueryErr {
-    SyntaxError, // The Query is incorrectly formatted
-    KeyError,    // The Query uses tags which do not exist
+    Syntax { offset: usize, expected: String }, // Byte offset the parser stopped at
+    UnknownTag { name: String, span: (usize, usize) },
+    WorkspaceNotFound(WorkspaceId),
+    ScopeNotFound(PathBuf), // Not in a local shelf of the workspace
+    Shelf(UpdateErr), // The files of a shelf could not be read
+    Unreachable(NodeId),
+    Cancelled,
+}
+
+impl From<UpdateErr> for QueryErr {
+    fn from(err: UpdateErr) -> Self {
+        match err {
+            UpdateErr::Cancelled => QueryErr::Cancelled,
+            UpdateErr::Unreachable(node) => QueryErr::Unreachable(node),
+            err => QueryErr::Shelf(err),
+        }
+    }
+}
+
+impl fmt::Display for QueryErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryErr::Syntax { offset, expected } => write!(f, "syntax error at {}, expected {}", offset, expected),
+            QueryErr::UnknownTag { name, span } => write!(f, "unknown tag \"{}\" at {}..{}", name, span.0, span.1),
+            QueryErr::WorkspaceNotFound(id) => write!(f, "unknown workspace {}", id),
+            QueryErr::ScopeNotFound(path) => write!(f, "{} is not in a local shelf of the workspace", path.display()),
+            QueryErr::Shelf(err) => write!(f, "{}", err),
+            QueryErr::Unreachable(node) => write!(f, "peer {} is unreachable", node),
+            QueryErr::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl From<QueryErr> for rpc::Error {
+    fn from(err: QueryErr) -> Self {
+        let error = rpc::Error {
+            message: err.to_string(),
+            ..Default::default()
+        };
+        match err {
+            QueryErr::Syntax { offset, expected } => rpc::Error {
+                code: rpc::ErrorCode::Syntax as i32,
+                start: offset as u32,
+                end: offset as u32,
+                expected,
+                ..error
+            },
+            QueryErr::UnknownTag { name, span } => rpc::Error {
+                code: rpc::ErrorCode::UnknownTag as i32,
+                tag: name,
+                start: span.0 as u32,
+                end: span.1 as u32,
+                ..error
+            },
+            QueryErr::WorkspaceNotFound(id) => rpc::Error {
+                code: rpc::ErrorCode::WorkspaceNotFound as i32,
+                workspace_id: id,
+                ..error
+            },
+            QueryErr::ScopeNotFound(path) => rpc::Error {
+                code: rpc::ErrorCode::PathNotFound as i32,
+                path: path.to_string_lossy().into_owned(),
+                ..error
+            },
+            QueryErr::Shelf(err) => err.into(),
+            QueryErr::Unreachable(node) => rpc::Error {
+                code: rpc::ErrorCode::Unreachable as i32,
+                node: node.as_bytes().to_vec(),
+                ..error
+            },
+            QueryErr::Cancelled => rpc::Error {
+                code: rpc::ErrorCode::Cancelled as i32,
+                ..error
+            },
+        }
+    }
 }
 
 //[!] Wrapper for a cacheservice.call() ?
 
+// Files are designated by ids, dense within the set of files the service retrieves from
 pub trait RetrieveService {
 
-    async fn get_files<T: FileOrder>(&self, tag: TagRef) -> Result<BTreeSet<OrderedFileID<T>>, QueryErr> {
-        todo!();
+    fn get_files(&self, tag: TagRef) -> impl Future<Output = Result<RoaringBitmap, QueryErr>> + Send;
+
+    // For names with no tag in the workspace yet, that other peers may have created
+    fn get_named(&self, name: &str) -> impl Future<Output = Result<RoaringBitmap, QueryErr>> + Send;
+
+    fn get_all(&self) -> impl Future<Output = Result<RoaringBitmap, QueryErr>> + Send;
+
+    fn get_untagged(&self) -> impl Future<Output = Result<RoaringBitmap, QueryErr>> + Send;
+
+    fn sort<T>(&self, files: RoaringBitmap, order: T) -> impl Future<Output = Result<BTreeSet<OrderedFileID<T>>, QueryErr>> + Send
+    where
+        T: FileOrder + Clone + Send + Sync + 'static,
+        OrderedFileID<T>: Ord;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tag sets by name over files named after their id
+    #[derive(Clone, Default)]
+    struct Sets {
+        tags: HashMap<String, RoaringBitmap>,
+        all: RoaringBitmap,
+    }
+
+    impl RetrieveService for Sets {
+        async fn get_files(&self, tag: TagRef) -> Result<RoaringBitmap, QueryErr> {
+            self.get_named(&tag.name()).await
+        }
+
+        async fn get_named(&self, name: &str) -> Result<RoaringBitmap, QueryErr> {
+            Ok(self.tags.get(name).cloned().unwrap_or_default())
+        }
+
+        async fn get_all(&self) -> Result<RoaringBitmap, QueryErr> {
+            Ok(self.all.clone())
+        }
+
+        async fn get_untagged(&self) -> Result<RoaringBitmap, QueryErr> {
+            Ok(self.tags.values().fold(self.all.clone(), |untagged, ids| untagged - ids))
+        }
+
+        async fn sort<T>(&self, files: RoaringBitmap, order: T) -> Result<BTreeSet<OrderedFileID<T>>, QueryErr>
+        where
+            T: FileOrder + Clone + Send + Sync + 'static,
+            OrderedFileID<T>: Ord,
+        {
+            let metadata = FileMetadata::from(&rpc::FileMetadata::default());
+            Ok(files
+                .iter()
+                .map(|id| OrderedFileID::new(FileID::local(PathBuf::from(id.to_string()), metadata.clone()), order.clone()))
+                .collect())
+        }
+    }
+
+    // a = {1, 2, 3}, b = {2, 3, 4} and c = {5} among files 0 to 6, in a new workspace
+    fn sets() -> (Sets, WorkspaceId) {
+        let workspace_id = rand::random::<u64>().max(1);
+        let mut sets = Sets {
+            all: (0..7).collect(),
+            ..Default::default()
+        };
+        for (name, ids) in [("a", vec![1, 2, 3]), ("b", vec![2, 3, 4]), ("c", vec![5])] {
+            TagManager::get_or_create(workspace_id, name).unwrap();
+            sets.tags.insert(name.to_string(), ids.into_iter().collect());
+        }
+        (sets, workspace_id)
+    }
+
+    async fn evaluate(sets: &Sets, workspace_id: WorkspaceId, query: &str) -> Vec<String> {
+        let order = Name { order: Order::Ascending };
+        let mut query = Query::new(query, order, workspace_id).unwrap();
+        let files = query.evaluate(sets.clone()).await.unwrap();
+        files.iter().map(|f| f.file_id().path().to_string_lossy().into_owned()).collect()
+    }
+
+    #[tokio::test]
+    async fn operators_combine_the_tag_bitmaps() {
+        let (sets, workspace_id) = sets();
+        let cases = [
+            (r#""a" AND "b""#, vec!["2", "3"]),
+            (r#""a" OR "c""#, vec!["1", "2", "3", "5"]),
+            (r#""a" XOR "b""#, vec!["1", "4"]),
+            (r#"NOT "a""#, vec!["0", "4", "5", "6"]),
+            (r#""a" AND NOT "b""#, vec!["1"]),
+            (r#"NOT "a" AND NOT "b""#, vec!["0", "5", "6"]),
+            (r#"("a" OR "b") AND NOT ("a" AND "b")"#, vec!["1", "4"]),
+        ];
+        for (query, expected) in cases {
+            assert_eq!(evaluate(&sets, workspace_id, query).await, expected, "{}", query);
+        }
+    }
+
+    #[tokio::test]
+    async fn untagged_combines_like_a_tag() {
+        let (sets, workspace_id) = sets();
+        let cases = [
+            ("UNTAGGED", vec!["0", "6"]),
+            (r#"UNTAGGED OR "c""#, vec!["0", "5", "6"]),
+            (r#"UNTAGGED AND "a""#, vec![]),
+            ("NOT UNTAGGED", vec!["1", "2", "3", "4", "5"]),
+        ];
+        for (query, expected) in cases {
+            assert_eq!(evaluate(&sets, workspace_id, query).await, expected, "{}", query);
+        }
+    }
+
+    // Offset and expectation of the syntax error query raises
+    fn syntax_error(query: &str) -> (usize, String) {
+        let order = Name { order: Order::Ascending };
+        match Query::new(query, order, rand::random::<u64>().max(1)) {
+            Err(QueryErr::Syntax { offset, expected }) => (offset, expected),
+            _ => panic!("{} should not parse", query),
+        }
     }
 
-    async fn get_all<T: FileOrder>(&self) -> Result<BTreeSet<OrderedFileID<T>>, QueryErr> {
-        todo!();
+    #[test]
+    fn errors_point_into_the_query() {
+        let operand = r#"one of "(", "NOT", "UNTAGGED", "\"""#;
+        assert_eq!(syntax_error(r#""a" AND"#), (7, operand.to_string()));
+        assert_eq!(syntax_error(r#""a" OR ) "b""#), (7, operand.to_string()));
+        assert_eq!(syntax_error(r#"("a""#).0, 4);
+
+        // Spans of unknown tags include their quotes
+        let (_, workspace_id) = sets();
+        let order = Name { order: Order::Ascending };
+        let query = Query::new(r#""a" AND NOT "zz" OR "b""#, order, workspace_id).unwrap();
+        assert_eq!(query.unresolved(), [("zz", (12, 16))]);
+
+        let err = rpc::Error::from(QueryErr::UnknownTag {
+            name: "zz".to_string(),
+            span: (12, 16),
+        });
+        assert_eq!(err.code, rpc::ErrorCode::UnknownTag as i32);
+        assert_eq!((err.tag.as_str(), err.start, err.end), ("zz", 12, 16));
+        let err = rpc::Error::from(QueryErr::Syntax {
+            offset: 7,
+            expected: operand.to_string(),
+        });
+        assert_eq!(err.code, rpc::ErrorCode::Syntax as i32);
+        assert_eq!((err.start, err.end, err.expected.as_str()), (7, 7, operand));
     }
 }
@@ -0,0 +1,212 @@
+use crate::persist::write_atomic;
+use crate::rpc::{self, MutationOutcome, MutationQueueState, MutationReport, QueuedMutation};
+use crate::sync::TagOp;
+use crate::workspace::ShelfId;
+use iroh::NodeId;
+use prost::Message;
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Durable queue of tag mutations addressed to remote shelves whose peer could not be
+// reached. Mutations are replayed in order once the peer is back; their outcome is kept
+// until the originating client collects it.
+#[derive(Debug)]
+pub struct MutationQueue {
+    path: PathBuf,
+    state: MutationQueueState,
+    unsaved: bool, // The last write failed
+}
+
+impl MutationQueue {
+    pub fn load(path: PathBuf) -> Self {
+        let state = std::fs::read(&path)
+            .ok()
+            .and_then(|buf| MutationQueueState::decode(&*buf).ok())
+            .unwrap_or_default();
+        MutationQueue {
+            path,
+            state,
+            unsaved: false,
+        }
+    }
+
+    fn persist(&mut self) -> io::Result<()> {
+        let res = write_atomic(&self.path, &self.state.encode_to_vec());
+        self.unsaved = res.is_err();
+        res
+    }
+
+    // Whether the queue on disk is behind, its last write having failed
+    pub fn unsaved(&self) -> bool {
+        self.unsaved
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn push(&mut self, client_id: u64, target: NodeId, op: &TagOp) -> io::Result<u64> {
+        let id = self.state.next_id;
+        self.state.next_id += 1;
+        self.state.pending.push(QueuedMutation {
+            id,
+            client_id,
+            target: target.as_bytes().to_vec(),
+            op: Some(op.into()),
+        });
+        self.persist()?;
+        Ok(id)
+    }
+
+    // Oldest mutation still waiting for target
+    pub fn front(&self, target: &NodeId) -> Option<QueuedMutation> {
+        self.state
+            .pending
+            .iter()
+            .find(|m| m.target == target.as_bytes())
+            .cloned()
+    }
+
+    pub fn resolve(&mut self, id: u64, outcome: MutationOutcome, reason: String) -> io::Result<()> {
+        let Some(idx) = self.state.pending.iter().position(|m| m.id == id) else {
+            return Ok(());
+        };
+        let mutation = self.state.pending.remove(idx);
+        self.state.reports.push(MutationReport {
+            id,
+            client_id: mutation.client_id,
+            op: mutation.op,
+            outcome: outcome as i32,
+            reason,
+        });
+        self.persist()
+    }
+
+    // Hand the reports of client_id over, forgetting them
+    pub fn take_reports(&mut self, client_id: u64) -> io::Result<Vec<MutationReport>> {
+        let (reports, rest) = std::mem::take(&mut self.state.reports)
+            .into_iter()
+            .partition(|r| r.client_id == client_id);
+        self.state.reports = rest;
+        self.persist()?;
+        Ok(reports)
+    }
+
+    pub fn pending_for(&self, client_id: u64) -> Vec<QueuedMutation> {
+        self.state
+            .pending
+            .iter()
+            .filter(|m| m.client_id == client_id)
+            .cloned()
+            .collect()
+    }
+
+    // Paths (relative to the shelf root) with mutations still in the queue
+    pub fn pending_paths(&self, shelf_id: ShelfId) -> HashSet<String> {
+        self.state
+            .pending
+            .iter()
+            .filter_map(|m| m.op.as_ref())
+            .filter(|op| op.shelf_id == shelf_id)
+            .map(|op| op.path.clone())
+            .collect()
+    }
+
+    pub fn mark_pending(&self, shelf_id: ShelfId, files: &mut [rpc::File]) {
+        let pending = self.pending_paths(shelf_id);
+        for file in files.iter_mut() {
+            file.pending = pending.contains(&file.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::{OpId, TagOpKind};
+    use iroh::SecretKey;
+
+    fn node(n: u8) -> NodeId {
+        SecretKey::from_bytes(&[n; 32]).public()
+    }
+
+    fn op(path: &str) -> TagOp {
+        TagOp {
+            id: OpId { lamport: 1, node: node(1) },
+            workspace_id: 1,
+            shelf_id: 2,
+            path: PathBuf::from(path),
+            tag: "foo".to_string(),
+            kind: TagOpKind::Attach,
+        }
+    }
+
+    fn queue() -> MutationQueue {
+        MutationQueue::load(std::env::temp_dir().join(format!("ebi-mutations-{}.pb", rand::random::<u64>())))
+    }
+
+    #[test]
+    fn mutations_are_replayed_in_order() {
+        let mut queue = queue();
+        let first = queue.push(7, node(2), &op("a")).unwrap();
+        let other = queue.push(7, node(3), &op("b")).unwrap();
+        let second = queue.push(8, node(2), &op("c")).unwrap();
+        assert_eq!(queue.front(&node(2)).map(|m| m.id), Some(first));
+        queue.resolve(first, MutationOutcome::Applied, String::new()).unwrap();
+        assert_eq!(queue.front(&node(2)).map(|m| m.id), Some(second));
+        assert_eq!(queue.front(&node(3)).map(|m| m.id), Some(other));
+        assert!(queue.front(&node(4)).is_none());
+        assert_eq!(queue.pending_paths(2), HashSet::from(["b".to_string(), "c".to_string()]));
+        assert!(queue.pending_paths(3).is_empty());
+    }
+
+    #[test]
+    fn reports_are_handed_over_once() {
+        let mut queue = queue();
+        let id = queue.push(7, node(2), &op("a")).unwrap();
+        queue.push(8, node(2), &op("b")).unwrap();
+        queue
+            .resolve(id, MutationOutcome::Rejected, "no file".to_string())
+            .unwrap();
+        // Resolving twice is a no-op
+        queue.resolve(id, MutationOutcome::Applied, String::new()).unwrap();
+        assert!(queue.take_reports(8).unwrap().is_empty());
+        let reports = queue.take_reports(7).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!((reports[0].outcome(), reports[0].reason.as_str()), (MutationOutcome::Rejected, "no file"));
+        assert!(queue.take_reports(7).unwrap().is_empty());
+        assert_eq!(queue.pending_for(8).len(), 1);
+    }
+
+    #[test]
+    fn persisted_queue_reloads() {
+        let mut saved = queue();
+        let id = saved.push(7, node(2), &op("a")).unwrap();
+        saved.push(7, node(2), &op("b")).unwrap();
+        saved.resolve(id, MutationOutcome::Applied, String::new()).unwrap();
+        assert!(!saved.unsaved());
+        let mut loaded = MutationQueue::load(saved.path().to_path_buf());
+        let pending = loaded.front(&node(2)).unwrap();
+        assert_eq!(pending.op.map(|op| op.path), Some("b".to_string()));
+        assert_eq!(loaded.take_reports(7).unwrap().len(), 1);
+        // Ids are never reused
+        assert!(loaded.push(7, node(2), &op("c")).unwrap() > pending.id);
+        std::fs::remove_file(saved.path()).unwrap();
+    }
+
+    #[test]
+    fn failed_writes_are_reported() {
+        let dir = std::env::temp_dir().join(format!("ebi-mutations-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("mutations.pb");
+        std::fs::create_dir(path.with_extension("tmp")).unwrap();
+        let mut queue = MutationQueue::load(path.clone());
+        assert!(queue.push(7, node(2), &op("a")).is_err());
+        assert!(queue.unsaved());
+        std::fs::remove_dir(path.with_extension("tmp")).unwrap();
+        queue.take_reports(7).unwrap();
+        assert!(!queue.unsaved());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
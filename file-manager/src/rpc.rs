@@ -1,15 +1,39 @@
 use std::convert::TryFrom;
 pub enum RequestCode {
     Query = 1,
+    CreateInvite = 2,
+    RedeemInvite = 3,
+    Tag = 4,
+    PeerStatus = 5,
+    MutationStatus = 6,
+    Validate = 7,
+    CacheStats = 8,
+    Pin = 9,
+    Workspace = 10,
+    CreateTag = 11,
+    Task = 12,
+    Error = 13, // Response to a failed request
     Echo = 42,
 }
 
 impl TryFrom<u8> for RequestCode {
     type Error = ();
 
-    fn try_from(v: u8) -> Result<Self, Self::Error> {
+    fn try_from(v: u8) -> Result<Self, ()> {
         match v {
             x if x == RequestCode::Query as u8 => Ok(RequestCode::Query),
+            x if x == RequestCode::CreateInvite as u8 => Ok(RequestCode::CreateInvite),
+            x if x == RequestCode::RedeemInvite as u8 => Ok(RequestCode::RedeemInvite),
+            x if x == RequestCode::Tag as u8 => Ok(RequestCode::Tag),
+            x if x == RequestCode::PeerStatus as u8 => Ok(RequestCode::PeerStatus),
+            x if x == RequestCode::MutationStatus as u8 => Ok(RequestCode::MutationStatus),
+            x if x == RequestCode::Validate as u8 => Ok(RequestCode::Validate),
+            x if x == RequestCode::CacheStats as u8 => Ok(RequestCode::CacheStats),
+            x if x == RequestCode::Pin as u8 => Ok(RequestCode::Pin),
+            x if x == RequestCode::Workspace as u8 => Ok(RequestCode::Workspace),
+            x if x == RequestCode::CreateTag as u8 => Ok(RequestCode::CreateTag),
+            x if x == RequestCode::Task as u8 => Ok(RequestCode::Task),
+            x if x == RequestCode::Error as u8 => Ok(RequestCode::Error),
             x if x == RequestCode::Echo as u8 => Ok(RequestCode::Echo),
             _ => Err(()),
         }
@@ -18,3 +42,13 @@ impl TryFrom<u8> for RequestCode {
 
 
 tonic::include_proto!("ebi_rpc");
+
+impl Error {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Error {
+            code: code as i32,
+            message: message.into(),
+            ..Default::default()
+        }
+    }
+}
@@ -1,51 +1,696 @@
 use tower::{Service};
-use std::{sync::Arc, sync::RwLock, future::Future, pin::Pin, task::{Context, Poll}};
-use crate::services::peer::PeerService;
-use crate::workspace::{Workspace, WorkspaceId};
+use std::{any::Any, sync::Arc, sync::RwLock, future::Future, pin::Pin, task::{Context, Poll}};
+use crate::services::peer::{PeerError, PeerService};
+use crate::content::{ContentStats, ContentStore};
+use std::io;
+use tokio::time::{interval, Duration};
+use crate::workspace::{ShelfId, WorkspaceId};
 use crate::tag::TagRef;
-use std::path::PathBuf;
-use std::collections::{BTreeSet, HashMap};
-use crate::query::{OrderedFileID, FileOrder};
+use crate::rpc;
+use iroh::NodeId;
+use std::path::{Component, Path, PathBuf};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use crate::query::{FileID, OrderedFileID, FileOrder, Query};
+use crate::lru::{Lru, LruStats};
+use prost::Message;
+use roaring::RoaringBitmap;
 
 
 #[derive(Clone)]
 pub struct CacheService {
     peer_service: PeerService,
-    workspaces: Arc<RwLock<HashMap<WorkspaceId, Workspace>>>
+    query_results: Arc<RwLock<Lru<QueryKey, QueryResult>>>,
+    remote_results: Arc<RwLock<Lru<RemoteKey, RemoteResult>>>,
+    remote_content: Arc<RwLock<Lru<RemoteFile, RemoteContent>>>,
+    disk: Arc<RwLock<ContentStore>>, // Behind remote_content, survives restarts
+    indices: Arc<RwLock<HashMap<WorkspaceId, WorkspaceIndex>>>,
 }
 
-enum RetrieveFiles<T: FileOrder + Clone> {
-    GetAll(WorkspaceId, T),
-    GetTag(WorkspaceId, T, TagRef),
+// Budgets in bytes, overridable through EBI_QUERY_CACHE, EBI_REMOTE_CACHE,
+// EBI_CONTENT_CACHE and EBI_DISK_CACHE (in MiB)
+#[derive(Debug, Clone, Copy)]
+pub struct CacheConfig {
+    pub query_budget: usize,
+    pub remote_budget: usize,
+    pub content_budget: usize,
+    pub disk_budget: usize,
 }
 
-enum Caching {
-    IsCacheValid(Option<TagRef>, HashCache),
+const MIB: usize = 1 << 20;
+const PIN_RETRY: Duration = Duration::from_secs(60);
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            query_budget: 64 * MIB,
+            remote_budget: 32 * MIB,
+            content_budget: 256 * MIB,
+            disk_budget: 4096 * MIB,
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn from_env() -> Self {
+        let budget = |var: &str, default: usize| {
+            std::env::var(var)
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .map_or(default, |mib| mib * MIB)
+        };
+        let default = CacheConfig::default();
+        CacheConfig {
+            query_budget: budget("EBI_QUERY_CACHE", default.query_budget),
+            remote_budget: budget("EBI_REMOTE_CACHE", default.remote_budget),
+            content_budget: budget("EBI_CONTENT_CACHE", default.content_budget),
+            disk_budget: budget("EBI_DISK_CACHE", default.disk_budget),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub queries: LruStats,
+    pub remote: LruStats,
+    pub content: LruStats,
+    pub disk: ContentStats,
+}
+
+// Workspace and canonical form of the query (formula and order, see Query::key)
+type QueryKey = (WorkspaceId, String);
+
+// Evaluated query, only valid for the workspace version it was computed at.
+// Holds a BTreeSet<OrderedFileID<T>> for the order T of the query.
+struct QueryResult {
+    version: u64,
+    files: Arc<dyn Any + Send + Sync>,
+}
+
+// Rough per-entry overhead of the sets and maps holding cached files
+const ENTRY_OVERHEAD: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum RemoteKey {
+    Query(NodeId, ShelfId, String),
+}
+
+type RemoteFile = (NodeId, ShelfId, PathBuf);
+
+// Remote shelf a workspace query has to be forwarded to
+#[derive(Debug, Clone)]
+pub struct RemoteTarget {
+    pub node: NodeId,
+    pub shelf_id: ShelfId,
+    pub version: u64,
+}
+
+// Result of a query on a remote shelf, valid as long as the shelf version is unchanged
+struct RemoteResult {
+    version: u64,
+    files: Vec<rpc::File>,
 }
-enum RetrieveData {
+
+impl RemoteResult {
+    fn weight(&self) -> usize {
+        self.files.iter().map(|f| f.encoded_len() + ENTRY_OVERHEAD).sum()
+    }
+}
+
+// Content of a remote file, valid as long as its modification time is unchanged
+struct RemoteContent {
+    modified: u64,
+    data: Arc<Vec<u8>>,
+}
+
+impl CacheService {
+    pub fn peer_service(&self) -> &PeerService {
+        &self.peer_service
+    }
+
+    // Remote shelves of the workspace that, according to their summary, may match query
+    pub async fn route<T: FileOrder + Clone>(&self, workspace_id: WorkspaceId, query: &Query<T>) -> Vec<RemoteTarget> {
+        self.peer_service
+            .remote_summaries
+            .read()
+            .await
+            .iter()
+            .filter(|((_, w_id), _)| *w_id == workspace_id)
+            .flat_map(|((node, _), shelves)| {
+                shelves
+                    .iter()
+                    .filter(|(_, summary)| query.may_match(workspace_id, summary))
+                    .map(|(shelf_id, summary)| RemoteTarget {
+                        node: *node,
+                        shelf_id: *shelf_id,
+                        version: summary.version,
+                    })
+            })
+            .collect()
+    }
+
+    // Whether some remote shelf of the workspace may hold files tagged name
+    pub async fn known_remotely(&self, workspace_id: WorkspaceId, name: &str) -> bool {
+        self.peer_service
+            .remote_summaries
+            .read()
+            .await
+            .iter()
+            .filter(|((_, w_id), _)| *w_id == workspace_id)
+            .any(|(_, shelves)| shelves.values().any(|summary| summary.may_contain(workspace_id, name)))
+    }
+
+    // Files queued for mutation on that shelf are flagged as pending
+    pub async fn cached_remote(&self, target: &RemoteTarget, query: &str) -> Option<Vec<rpc::File>> {
+        let key = RemoteKey::Query(target.node, target.shelf_id, query.to_string());
+        let mut files = self
+            .remote_results
+            .write()
+            .unwrap()
+            .get_valid(&key, |res| res.version == target.version)?
+            .files
+            .clone();
+        self.mark_pending(target, &mut files).await;
+        Some(files)
+    }
+
+    pub async fn mark_pending(&self, target: &RemoteTarget, files: &mut [rpc::File]) {
+        self.peer_service
+            .mutations
+            .read()
+            .await
+            .mark_pending(target.shelf_id, files);
+    }
+
+    pub fn store_remote(&self, target: &RemoteTarget, query: &str, files: Vec<rpc::File>) {
+        let res = RemoteResult {
+            version: target.version,
+            files,
+        };
+        let weight = res.weight();
+        self.remote_results.write().unwrap().insert(
+            RemoteKey::Query(target.node, target.shelf_id, query.to_string()),
+            res,
+            weight,
+        );
+    }
+
+    // modified comes from the current metadata of the file
+    pub fn cached_content(&self, node: NodeId, shelf_id: ShelfId, path: &Path, modified: u64) -> Option<Arc<Vec<u8>>> {
+        self.remote_content
+            .write()
+            .unwrap()
+            .get_valid(&(node, shelf_id, path.to_path_buf()), |c| c.modified == modified)
+            .map(|c| c.data.clone())
+    }
+
+    pub fn store_content(&self, node: NodeId, shelf_id: ShelfId, path: PathBuf, modified: u64, data: Arc<Vec<u8>>) {
+        let weight = data.len() + ENTRY_OVERHEAD;
+        self.remote_content
+            .write()
+            .unwrap()
+            .insert((node, shelf_id, path), RemoteContent { modified, data }, weight);
+    }
+
+    // Peer owning a remote shelf, falling back to the one cached files were pulled from
+    async fn owner(&self, workspace_id: WorkspaceId, shelf_id: ShelfId, path: &Path) -> Result<NodeId, CacheError> {
+        match self.peer_service.shelf_owner(workspace_id, shelf_id).await {
+            Some(node) => Ok(node),
+            None => self
+                .disk
+                .read()
+                .unwrap()
+                .owner(shelf_id, path)
+                .ok_or_else(|| CacheError::NotFound(path.to_path_buf())),
+        }
+    }
+
+    // Content of a file of a remote shelf. Cached copies are checked against the peer,
+    // which only sends the content if it changed, and served as is while it is offline.
+    async fn remote_file(&self, workspace_id: WorkspaceId, shelf_id: ShelfId, path: PathBuf) -> Result<Arc<Vec<u8>>, CacheError> {
+        let node = self.owner(workspace_id, shelf_id, &path).await?;
+        let key = (node, shelf_id, path);
+        let cached_modified = self.disk.read().unwrap().modified(&key);
+        let peer_srv = &self.peer_service;
+        match peer_srv.fetch_file(node, workspace_id, shelf_id, &key.2, cached_modified).await {
+            Ok(content) if content.unchanged => {
+                if let Some(data) = self.local_copy(&key, Some(content.modified))? {
+                    return Ok(data);
+                }
+                // Copy dropped in the meantime (evicted or corrupted)
+                let content = peer_srv
+                    .fetch_file(node, workspace_id, shelf_id, &key.2, None)
+                    .await
+                    .map_err(|err| CacheError::fetch(node, &key.2, err))?;
+                self.store_fetched(key, content)
+            }
+            Ok(content) => self.store_fetched(key, content),
+            Err(PeerError::Connection) => self.local_copy(&key, None)?.ok_or(CacheError::Offline(node)),
+            Err(err) => Err(CacheError::fetch(node, &key.2, err)),
+        }
+    }
+
+    // From memory first, then from disk. Without modified, any cached version will do.
+    fn local_copy(&self, key: &RemoteFile, modified: Option<u64>) -> Result<Option<Arc<Vec<u8>>>, CacheError> {
+        let Some(modified) = modified.or_else(|| self.disk.read().unwrap().modified(key)) else {
+            return Ok(None);
+        };
+        let (node, shelf_id, path) = key;
+        if let Some(data) = self.cached_content(*node, *shelf_id, path, modified) {
+            return Ok(Some(data));
+        }
+        let Some(data) = self.disk.write().unwrap().get(key, Some(modified))? else {
+            return Ok(None);
+        };
+        let data = Arc::new(data);
+        self.store_content(*node, *shelf_id, path.clone(), modified, data.clone());
+        Ok(Some(data))
+    }
+
+    fn store_fetched(&self, key: RemoteFile, content: rpc::FileContent) -> Result<Arc<Vec<u8>>, CacheError> {
+        self.disk
+            .write()
+            .unwrap()
+            .put(key.clone(), content.modified, &content.data)?;
+        let data = Arc::new(content.data);
+        let (node, shelf_id, path) = key;
+        self.store_content(node, shelf_id, path, content.modified, data.clone());
+        Ok(data)
+    }
+
+    // Keep (or stop keeping) a remote file available offline, returning whether its
+    // content is cached. Pinned files not cached yet are pulled as soon as possible.
+    pub async fn pin(&self, workspace_id: WorkspaceId, shelf_id: ShelfId, path: PathBuf, pinned: bool) -> Result<bool, CacheError> {
+        let node = self.owner(workspace_id, shelf_id, &path).await?;
+        let cached = self
+            .disk
+            .write()
+            .unwrap()
+            .pin(workspace_id, (node, shelf_id, path.clone()), pinned)?;
+        if pinned && !cached {
+            return Ok(self.remote_file(workspace_id, shelf_id, path).await.is_ok());
+        }
+        Ok(cached)
+    }
+
+    // Pull the pinned files still missing, whenever their peer is reachable
+    pub async fn run_pins(self) {
+        let mut ticker = interval(PIN_RETRY);
+        loop {
+            ticker.tick().await;
+            let missing = self.disk.read().unwrap().missing_pins();
+            for (workspace_id, (_, shelf_id, path)) in missing {
+                let _ = self.remote_file(workspace_id, shelf_id, path).await;
+            }
+        }
+    }
+
+    pub fn workspace_version(&self, workspace_id: WorkspaceId) -> u64 {
+        self.indices
+            .read()
+            .unwrap()
+            .get(&workspace_id)
+            .map_or(0, |index| index.version)
+    }
+
+    pub fn cached_query<T>(&self, workspace_id: WorkspaceId, key: &str) -> Option<BTreeSet<OrderedFileID<T>>>
+    where
+        T: FileOrder + Clone + Send + Sync + 'static,
+        OrderedFileID<T>: Ord,
+    {
+        let version = self.workspace_version(workspace_id);
+        self.query_results
+            .write()
+            .unwrap()
+            .get_valid(&(workspace_id, key.to_string()), |res| res.version == version)?
+            .files
+            .downcast_ref::<BTreeSet<OrderedFileID<T>>>()
+            .cloned()
+    }
+
+    // version is the workspace version read before evaluating the query
+    pub fn store_query<T>(&self, workspace_id: WorkspaceId, key: String, version: u64, files: BTreeSet<OrderedFileID<T>>)
+    where
+        T: FileOrder + Clone + Send + Sync + 'static,
+        OrderedFileID<T>: Ord,
+    {
+        let weight = files
+            .iter()
+            .map(|f| {
+                std::mem::size_of::<OrderedFileID<T>>()
+                    + f.file_id().path().as_os_str().len()
+                    + ENTRY_OVERHEAD
+            })
+            .sum::<usize>()
+            + key.len();
+        let res = QueryResult {
+            version,
+            files: Arc::new(files),
+        };
+        self.query_results
+            .write()
+            .unwrap()
+            .insert((workspace_id, key), res, weight);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            queries: self.query_results.read().unwrap().stats(),
+            remote: self.remote_results.read().unwrap().stats(),
+            content: self.remote_content.read().unwrap().stats(),
+            disk: self.disk.read().unwrap().stats(),
+        }
+    }
+}
+
+// Ids of the files of a workspace, as a whole, for one tag or without any
+pub enum RetrieveFiles {
+    All(WorkspaceId),
+    Tag(WorkspaceId, TagRef),
+    Untagged(WorkspaceId),
+}
+
+// Files designated by ids (see RetrieveFiles), sorted by order
+pub struct SortFiles<T> {
+    pub workspace_id: WorkspaceId,
+    pub files: RoaringBitmap,
+    pub order: T,
+}
+
+// Change to the files of a workspace, keeping the cached indices up to date.
+// Files come with their current tags and dtags, as a query sees them.
+pub enum CacheEvent {
+    Update(Vec<(FileID, HashSet<TagRef>)>), // After attach, detach or a partial refresh
+    Remove(Vec<PathBuf>),
+    Rebuild(Vec<(FileID, HashSet<TagRef>)>), // Replace the whole content of the workspace
+}
+
+const MAX_CHANGES: usize = 4096;
+
+// Removed files leave their id unused, the index is rebuilt with dense ids once at least
+// that many are, making up half of them
+const MAX_UNUSED_IDS: usize = 1024;
+
+// Membership change of a file, in a tag (or in the whole workspace for None)
+#[derive(Debug, Clone)]
+struct Change {
+    version: u64,
+    tag: Option<TagRef>,
+    file: FileID,
+    added: bool,
+}
+
+// Files are designated by dense ids, never reused until the next rebuild
+#[derive(Default)]
+struct WorkspaceIndex {
+    ids: HashMap<PathBuf, u32>,
+    entries: Vec<Option<(FileID, HashSet<TagRef>)>>,
+    all: RoaringBitmap,
+    tags: HashMap<TagRef, RoaringBitmap>,
+    untagged: RoaringBitmap, // Files with neither tags nor dtags
+    version: u64,
+    all_version: u64, // Version of the last change to the set of files
+    tag_versions: HashMap<TagRef, u64>,
+    changes: VecDeque<Change>, // Most recent changes, to answer validations with deltas
+    horizon: u64, // Deltas can only be computed from versions >= horizon
+}
+
+impl WorkspaceIndex {
+    fn record(&mut self, tag: Option<TagRef>, file: &FileID, added: bool) {
+        match &tag {
+            Some(tag) => {
+                self.tag_versions.insert(tag.clone(), self.version);
+            }
+            None => self.all_version = self.version,
+        }
+        self.changes.push_back(Change {
+            version: self.version,
+            tag,
+            file: file.clone(),
+            added,
+        });
+        if self.changes.len() > MAX_CHANGES {
+            if let Some(change) = self.changes.pop_front() {
+                self.horizon = change.version;
+            }
+        }
+    }
+
+    fn set_bits(&mut self, id: u32, tags: &HashSet<TagRef>, present: bool) {
+        if tags.is_empty() && present {
+            self.untagged.insert(id);
+        } else {
+            self.untagged.remove(id);
+        }
+        for tag in tags {
+            if present {
+                self.tags.entry(tag.clone()).or_default().insert(id);
+            } else if let Some(bits) = self.tags.get_mut(tag) {
+                bits.remove(id);
+                if bits.is_empty() {
+                    self.tags.remove(tag);
+                }
+            }
+        }
+    }
+
+    fn upsert(&mut self, file: FileID, tags: HashSet<TagRef>) {
+        self.version += 1;
+        let id = match self.ids.get(file.path()) {
+            Some(id) => *id,
+            None => {
+                let id = self.entries.len() as u32;
+                self.ids.insert(file.path().clone(), id);
+                self.entries.push(None);
+                id
+            }
+        };
+        let (changed, old_tags) = match self.entries[id as usize].take() {
+            Some((old, old_tags)) => (old.metadata() != file.metadata(), old_tags),
+            None => (true, HashSet::new()),
+        };
+        self.set_bits(id, &old_tags, false);
+        self.set_bits(id, &tags, true);
+        self.all.insert(id);
+
+        // New files and metadata changes alter every result the file is in, so they are
+        // recorded as re-additions. Otherwise only the tags gained or lost changed.
+        if changed {
+            self.record(None, &file, true);
+        }
+        for tag in tags.union(&old_tags) {
+            match (tags.contains(tag), old_tags.contains(tag)) {
+                (true, false) => self.record(Some(tag.clone()), &file, true),
+                (false, true) => self.record(Some(tag.clone()), &file, false),
+                _ if changed => self.record(Some(tag.clone()), &file, true),
+                _ => {}
+            }
+        }
+        self.entries[id as usize] = Some((file, tags));
+    }
+
+    fn remove(&mut self, path: &PathBuf) {
+        let Some(id) = self.ids.remove(path) else {
+            return;
+        };
+        let Some((old, old_tags)) = self.entries[id as usize].take() else {
+            return;
+        };
+        self.version += 1;
+        self.set_bits(id, &old_tags, false);
+        self.all.remove(id);
+        self.record(None, &old, false);
+        for tag in old_tags {
+            self.record(Some(tag), &old, false);
+        }
+    }
+
+    fn apply(&mut self, event: CacheEvent) {
+        match event {
+            CacheEvent::Update(files) => {
+                for (file, tags) in files {
+                    self.upsert(file, tags);
+                }
+            }
+            CacheEvent::Remove(paths) => {
+                for path in paths {
+                    self.remove(&path);
+                }
+                let unused = self.entries.len() - self.ids.len();
+                if unused >= MAX_UNUSED_IDS && unused * 2 >= self.entries.len() {
+                    let files = self.entries.drain(..).flatten().collect();
+                    self.apply(CacheEvent::Rebuild(files));
+                }
+            }
+            CacheEvent::Rebuild(files) => {
+                // Ids are compacted again
+                self.ids.clear();
+                self.entries.clear();
+                self.all.clear();
+                self.tags.clear();
+                self.untagged.clear();
+                for (file, tags) in files {
+                    let id = self.entries.len() as u32;
+                    self.set_bits(id, &tags, true);
+                    self.all.insert(id);
+                    self.ids.insert(file.path().clone(), id);
+                    self.entries.push(Some((file, tags)));
+                }
+                // Every cached result is now invalid
+                self.version += 1;
+                self.all_version = self.version;
+                self.tag_versions.clear();
+                self.changes.clear();
+                self.horizon = self.version;
+            }
+        }
+    }
+
+    fn current(&self, tag: &Option<TagRef>) -> u64 {
+        match tag {
+            // Tags never seen changed since the last rebuild
+            Some(tag) => self.tag_versions.get(tag).cloned().unwrap_or(self.horizon),
+            None => self.all_version.max(self.horizon),
+        }
+    }
+
+    fn validate(&self, tag: Option<TagRef>, last_seen: u64) -> CacheValidity {
+        let version = self.current(&tag);
+        if last_seen == version {
+            return CacheValidity::Valid;
+        }
+        if last_seen < self.horizon || last_seen > self.version {
+            return CacheValidity::Invalid { version };
+        }
+        // Net effect of the changes since last_seen, per path
+        let mut delta: HashMap<PathBuf, (FileID, bool)> = HashMap::new();
+        self.changes
+            .iter()
+            .filter(|c| c.version > last_seen && c.tag == tag)
+            .for_each(|c| {
+                delta.insert(c.file.path().clone(), (c.file.clone(), c.added));
+            });
+        let (added, removed): (Vec<_>, Vec<_>) = delta.into_values().partition(|(_, added)| *added);
+        CacheValidity::Delta {
+            version,
+            added: added.into_iter().map(|(file, _)| file).collect(),
+            removed: removed.into_iter().map(|(file, _)| file.path().clone()).collect(),
+        }
+    }
+
+    fn sort<T>(&self, files: &RoaringBitmap, order: &T) -> BTreeSet<OrderedFileID<T>>
+    where
+        T: FileOrder + Clone,
+        OrderedFileID<T>: Ord,
+    {
+        files
+            .iter()
+            .filter_map(|id| self.entries.get(id as usize)?.as_ref())
+            .map(|(file, _)| OrderedFileID::new(file.clone(), order.clone()))
+            .collect()
+    }
+}
+
+impl CacheService {
+    pub fn new(peer_service: PeerService, config: CacheConfig, data_dir: &Path) -> io::Result<Self> {
+        let disk = ContentStore::open(data_dir.join("content"), config.disk_budget as u64)?;
+        Ok(CacheService {
+            peer_service,
+            query_results: Arc::new(RwLock::new(Lru::new(config.query_budget))),
+            remote_results: Arc::new(RwLock::new(Lru::new(config.remote_budget))),
+            remote_content: Arc::new(RwLock::new(Lru::new(config.content_budget))),
+            disk: Arc::new(RwLock::new(disk)),
+            indices: Arc::new(RwLock::new(HashMap::new())),
+        })
+    }
+
+    pub fn update(&self, workspace_id: WorkspaceId, event: CacheEvent) {
+        self.indices
+            .write()
+            .unwrap()
+            .entry(workspace_id)
+            .or_default()
+            .apply(event);
+    }
+}
+
+pub enum Caching {
+    IsCacheValid(WorkspaceId, Option<TagRef>, HashCache), // None: the set of all files
+}
+
+#[derive(Debug)]
+pub enum CacheValidity {
+    Valid,
+    Delta {
+        version: u64,
+        added: Vec<FileID>,
+        removed: Vec<PathBuf>,
+    },
+    Invalid { version: u64 }, // Too old (or unknown) to compute a delta, fetch everything again
+}
+pub enum RetrieveData {
     GetDir(PathBuf),
-    GetFile(PathBuf),
+    GetFile(WorkspaceId, ShelfId, PathBuf), // Path relative to the shelf root
 }
 enum RetrieveInfo {
     GetFileInfo(PathBuf),
     GetDirInfo(PathBuf)
 }
 
-struct HashCache {
-    hash: u64
+pub struct HashCache {
+    pub hash: u64
 }
 
 enum CommandRes<T: FileOrder + Clone> {
     OrderedFiles(BTreeSet<OrderedFileID<T>>)
 }
 
-enum CacheError {
-    WorkspaceNotFound,
+#[derive(Debug)]
+pub enum CacheError {
+    WorkspaceNotFound(WorkspaceId),
+    NotFound(PathBuf),
+    Offline(NodeId), // Not cached, and the peer owning the file is unreachable
+    Peer(NodeId, PeerError), // The peer failed to send the file
+    NotAFile(PathBuf),
+    Io(io::Error),
 }
 
-impl<T: FileOrder + Clone> Service<RetrieveFiles<T>> for CacheService {
+// Path of the file at path (relative to root) on a local shelf, refusing anything that
+// resolves outside of it, such as ".." components or symbolic links
+async fn shelf_file(root: &Path, path: &Path) -> Result<PathBuf, CacheError> {
+    let not_found = || CacheError::NotFound(path.to_path_buf());
+    if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(not_found());
+    }
+    let root = tokio::fs::canonicalize(root).await?;
+    let full = tokio::fs::canonicalize(root.join(path)).await.map_err(|_| not_found())?;
+    if !full.starts_with(&root) {
+        return Err(not_found());
+    }
+    if !tokio::fs::metadata(&full).await?.is_file() {
+        return Err(CacheError::NotAFile(path.to_path_buf()));
+    }
+    Ok(full)
+}
 
-    type Response = BTreeSet<OrderedFileID<T>>;
+impl From<io::Error> for CacheError {
+    fn from(err: io::Error) -> Self {
+        CacheError::Io(err)
+    }
+}
+
+impl CacheError {
+    // Failed fetch of the file at path from node
+    fn fetch(node: NodeId, path: &Path, err: PeerError) -> Self {
+        match err {
+            PeerError::NotFound => CacheError::NotFound(path.to_path_buf()),
+            PeerError::Connection => CacheError::Offline(node),
+            err => CacheError::Peer(node, err),
+        }
+    }
+}
+
+impl Service<RetrieveData> for CacheService {
+    type Response = Arc<Vec<u8>>;
     type Error = CacheError;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
@@ -53,21 +698,228 @@ impl<T: FileOrder + Clone> Service<RetrieveFiles<T>> for CacheService {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, req: RetrieveFiles<T>) -> Self::Future {
+    fn call(&mut self, req: RetrieveData) -> Self::Future {
+        let cache = self.clone();
         Box::pin(async move {
             match req {
-                RetrieveFiles::GetAll(work_id, ord) => {
-                    if let Some(workspace) = &self.workspaces.read().unwrap().get(&work_id) {
-                        todo!();
-                    } else {
-                        return CacheError::WorkspaceNotFound;
+                RetrieveData::GetFile(work_id, shelf_id, path) => {
+                    let shelf = cache.peer_service.shelves.read().await.get(&shelf_id).cloned();
+                    match shelf {
+                        Some(shelf) => {
+                            let path = shelf_file(shelf.root_path(), &path).await?;
+                            Ok(Arc::new(tokio::fs::read(path).await?))
+                        }
+                        None => cache.remote_file(work_id, shelf_id, path).await,
                     }
                 }
-                RetrieveFiles::GetTag(work_id, ord, tag) => {
+                // Directories have no content to retrieve
+                RetrieveData::GetDir(path) => Err(CacheError::NotAFile(path)),
+            }
+        })
+    }
+}
+
+impl Service<Caching> for CacheService {
+    type Response = CacheValidity;
+    type Error = CacheError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Caching) -> Self::Future {
+        let indices = self.indices.clone();
+        Box::pin(async move {
+            match req {
+                Caching::IsCacheValid(work_id, tag, HashCache { hash }) => {
+                    let indices = indices.read().unwrap();
+                    let index = indices.get(&work_id).ok_or(CacheError::WorkspaceNotFound(work_id))?;
+                    Ok(index.validate(tag, hash))
                 }
             }
-            todo!();
-        });
-        todo!();
+        })
+    }
+}
+
+impl Service<RetrieveFiles> for CacheService {
+    type Response = RoaringBitmap;
+    type Error = CacheError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: RetrieveFiles) -> Self::Future {
+        let indices = self.indices.clone();
+        Box::pin(async move {
+            let indices = indices.read().unwrap();
+            match req {
+                RetrieveFiles::All(work_id) => {
+                    let index = indices.get(&work_id).ok_or(CacheError::WorkspaceNotFound(work_id))?;
+                    Ok(index.all.clone())
+                }
+                RetrieveFiles::Tag(work_id, tag) => {
+                    let index = indices.get(&work_id).ok_or(CacheError::WorkspaceNotFound(work_id))?;
+                    Ok(index.tags.get(&tag).cloned().unwrap_or_default())
+                }
+                RetrieveFiles::Untagged(work_id) => {
+                    let index = indices.get(&work_id).ok_or(CacheError::WorkspaceNotFound(work_id))?;
+                    Ok(index.untagged.clone())
+                }
+            }
+        })
+    }
+}
+
+impl<T> Service<SortFiles<T>> for CacheService
+where
+    T: FileOrder + Clone + Send + Sync + 'static,
+    OrderedFileID<T>: Ord,
+{
+    type Response = BTreeSet<OrderedFileID<T>>;
+    type Error = CacheError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: SortFiles<T>) -> Self::Future {
+        let indices = self.indices.clone();
+        Box::pin(async move {
+            let indices = indices.read().unwrap();
+            let index = indices.get(&req.workspace_id).ok_or(CacheError::WorkspaceNotFound(req.workspace_id))?;
+            Ok(index.sort(&req.files, &req.order))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shelf::file::FileMetadata;
+    use crate::tag::TagManager;
+
+    fn file(path: &str) -> FileID {
+        FileID::local(PathBuf::from(path), FileMetadata::from(&rpc::FileMetadata::default()))
+    }
+
+    fn tag(name: &str) -> TagRef {
+        TagManager::get_or_create(rand::random::<u64>().max(1), name).unwrap()
+    }
+
+    fn paths(files: &[FileID]) -> Vec<PathBuf> {
+        let mut paths: Vec<_> = files.iter().map(|f| f.path().clone()).collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn bitmaps_follow_the_files() {
+        let (foo, bar) = (tag("foo"), tag("bar"));
+        let mut index = WorkspaceIndex::default();
+        index.apply(CacheEvent::Update(vec![
+            (file("/a"), HashSet::from([foo.clone()])),
+            (file("/b"), HashSet::from([foo.clone(), bar.clone()])),
+            (file("/c"), HashSet::new()),
+        ]));
+        assert_eq!(index.all.len(), 3);
+        assert_eq!(index.tags[&foo].len(), 2);
+        assert_eq!(index.untagged.iter().collect::<Vec<_>>(), vec![index.ids[Path::new("/c")]]);
+        index.apply(CacheEvent::Update(vec![(file("/b"), HashSet::from([bar.clone()]))]));
+        index.apply(CacheEvent::Remove(vec![PathBuf::from("/a"), PathBuf::from("/missing")]));
+        assert!(!index.tags.contains_key(&foo));
+        assert_eq!(index.tags[&bar].len(), 1);
+        assert_eq!(index.all.len(), 2);
+        // Ids of removed files are not reused before the next rebuild
+        index.apply(CacheEvent::Update(vec![(file("/d"), HashSet::new())]));
+        assert_eq!(index.ids[Path::new("/d")], 3);
+        index.apply(CacheEvent::Rebuild(vec![(file("/d"), HashSet::new())]));
+        assert_eq!(index.ids[Path::new("/d")], 0);
+        assert_eq!(index.untagged.len(), 1);
+    }
+
+    #[test]
+    fn validations_answer_with_deltas() {
+        let foo = tag("foo");
+        let mut index = WorkspaceIndex::default();
+        index.apply(CacheEvent::Rebuild(vec![(file("/a"), HashSet::from([foo.clone()]))]));
+        let seen = index.current(&Some(foo.clone()));
+        assert!(matches!(index.validate(Some(foo.clone()), seen), CacheValidity::Valid));
+        index.apply(CacheEvent::Update(vec![(file("/b"), HashSet::from([foo.clone()]))]));
+        index.apply(CacheEvent::Update(vec![(file("/a"), HashSet::new())]));
+        match index.validate(Some(foo.clone()), seen) {
+            CacheValidity::Delta { version, added, removed } => {
+                assert_eq!(version, index.version);
+                assert_eq!(paths(&added), vec![PathBuf::from("/b")]);
+                assert_eq!(removed, vec![PathBuf::from("/a")]);
+            }
+            other => panic!("expected a delta, got {:?}", other),
+        }
+        // Tag changes leave the set of all files as it was
+        match index.validate(None, seen) {
+            CacheValidity::Delta { added, removed, .. } => {
+                assert_eq!(paths(&added), vec![PathBuf::from("/b")]);
+                assert!(removed.is_empty());
+            }
+            other => panic!("expected a delta, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn only_the_tags_that_changed_are_recorded() {
+        let (foo, bar) = (tag("foo"), tag("bar"));
+        let mut index = WorkspaceIndex::default();
+        index.apply(CacheEvent::Update(vec![(file("/a"), HashSet::from([foo.clone()]))]));
+        let (seen_all, seen_foo) = (index.current(&None), index.current(&Some(foo.clone())));
+        index.apply(CacheEvent::Update(vec![(file("/a"), HashSet::from([foo.clone(), bar.clone()]))]));
+        assert!(matches!(index.validate(None, seen_all), CacheValidity::Valid));
+        assert!(matches!(index.validate(Some(foo.clone()), seen_foo), CacheValidity::Valid));
+        // Metadata changes reach every set the file is in
+        let metadata = rpc::FileMetadata {
+            size: 3,
+            ..Default::default()
+        };
+        index.apply(CacheEvent::Update(vec![(
+            FileID::local(PathBuf::from("/a"), FileMetadata::from(&metadata)),
+            HashSet::from([foo.clone(), bar.clone()]),
+        )]));
+        assert!(matches!(index.validate(None, seen_all), CacheValidity::Delta { .. }));
+        assert!(matches!(index.validate(Some(foo.clone()), seen_foo), CacheValidity::Delta { .. }));
+    }
+
+    #[test]
+    fn ids_are_compacted_once_mostly_unused() {
+        let mut index = WorkspaceIndex::default();
+        let files: Vec<_> = (0..2 * MAX_UNUSED_IDS).map(|i| (file(&format!("/{}", i)), HashSet::new())).collect();
+        index.apply(CacheEvent::Update(files));
+        let removed: Vec<_> = (1..MAX_UNUSED_IDS).map(|i| PathBuf::from(format!("/{}", i))).collect();
+        index.apply(CacheEvent::Remove(removed));
+        assert_eq!(index.entries.len(), 2 * MAX_UNUSED_IDS);
+        index.apply(CacheEvent::Remove(vec![PathBuf::from(format!("/{}", MAX_UNUSED_IDS))]));
+        assert_eq!(index.entries.len(), MAX_UNUSED_IDS);
+        assert_eq!(index.all.max(), Some(MAX_UNUSED_IDS as u32 - 1));
+        assert_eq!(index.untagged.len(), MAX_UNUSED_IDS as u64);
+    }
+
+    #[test]
+    fn validations_past_the_horizon_are_invalid() {
+        let foo = tag("foo");
+        let mut index = WorkspaceIndex::default();
+        index.apply(CacheEvent::Update(vec![(file("/a"), HashSet::from([foo.clone()]))]));
+        let seen = index.current(&Some(foo.clone()));
+        index.apply(CacheEvent::Rebuild(Vec::new()));
+        assert!(matches!(index.validate(Some(foo.clone()), seen), CacheValidity::Invalid { .. }));
+        assert!(matches!(index.validate(None, index.version + 1), CacheValidity::Invalid { .. }));
+        // Changes dropped from the window move the horizon forward
+        let seen = index.current(&None);
+        for i in 0..MAX_CHANGES {
+            index.apply(CacheEvent::Update(vec![(file(&format!("/{}", i)), HashSet::new())]));
+        }
+        index.apply(CacheEvent::Update(vec![(file("/last"), HashSet::new())]));
+        assert!(index.horizon > seen);
+        assert!(matches!(index.validate(None, seen), CacheValidity::Invalid { .. }));
     }
 }
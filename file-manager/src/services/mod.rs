@@ -2,3 +2,4 @@ pub mod query;
 pub mod peer;
 pub mod cache;
 pub mod rpc;
+pub mod workspace;
@@ -1,20 +1,105 @@
 use tower::{Service};
-use tokio::sync::{RwLock, Mutex};
-use std::{sync::Arc, future::Future, pin::Pin, task::{Context, Poll}};
+use tokio::sync::{mpsc, oneshot, RwLock, Mutex};
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc}, future::Future, pin::Pin, task::{Context, Poll}};
 use iroh::{
     endpoint::Connection,
-    protocol::{ProtocolHandler, Router},
-    Endpoint, NodeId,
+    Endpoint, NodeAddr, NodeId,
 };
+use iroh_base::ticket::{self, NodeTicket, Ticket};
+use rand::RngCore;
+use prost::Message;
+use chrono::Utc;
+use tokio::time::{interval, timeout, Duration};
 use std::net::SocketAddr;
 use tokio::net::{TcpStream};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use crate::rpc::{self, PeerState, PendingInvitation, TrustedPeer, FetchFile, FileContent, Invitation, MutationAck, MutationOutcome, PeerRequest, PeerResponse, Ping, QueryResponse, Redeem, RedeemAck, RetrieveTag, SummaryRequest, SyncRequest, TagOps, peer_request, peer_response};
+use crate::persist::write_atomic;
+use crate::query::FileID;
+use crate::shelf::shelf::{LocalShelfRef, UpdateErr};
+use crate::shelf::summary::ShelfSummary;
+use crate::tag::TagManager;
+use crate::queue::MutationQueue;
+use crate::sync::{Merge, TagLog, TagOp, TagOpKind};
+use crate::workspace::{ShelfId, WorkspaceId};
+use crate::ALPN;
 
+const DEFAULT_INVITE_TTL: u64 = 60 * 60; // seconds
+const MAX_PEER_MSG: usize = 16 * 1024 * 1024;
+const MAX_FILE_CONTENT: u64 = MAX_PEER_MSG as u64 - 1024; // Files are sent in a single message
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: u64 = 5 * 60; // seconds
+const APPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub type InviteSecret = [u8; 32];
+pub type ShelfSummaries = HashMap<ShelfId, ShelfSummary>;
 
 #[derive(Clone)]
 pub struct PeerService {
     pub peers: Arc<RwLock<HashMap<NodeId, Connection>>>,
-    pub clients: Arc<RwLock<Vec<Client>>>
+    pub clients: Arc<RwLock<Vec<Client>>>,
+    pub endpoint: Endpoint,
+    pub invitations: Arc<RwLock<HashMap<InviteSecret, PendingInvite>>>,
+    pub trusted: Arc<RwLock<HashMap<NodeId, HashSet<WorkspaceId>>>>,
+    pub tag_log: Arc<RwLock<TagLog>>,
+    pub applier: mpsc::UnboundedSender<Apply>, // Tag ops for the local shelves, applied in order
+    applier_ops: Arc<std::sync::Mutex<Option<mpsc::UnboundedReceiver<Apply>>>>, // Until the applier takes them
+    pub status: Arc<RwLock<HashMap<NodeId, PeerStatus>>>,
+    pub summaries: Arc<RwLock<HashMap<WorkspaceId, ShelfSummaries>>>, // Local shelves
+    pub remote_summaries: Arc<RwLock<HashMap<(NodeId, WorkspaceId), ShelfSummaries>>>,
+    pub mutations: Arc<RwLock<MutationQueue>>, // Mutations for remote shelves not yet delivered
+    pub shelves: Arc<RwLock<HashMap<ShelfId, LocalShelfRef>>>, // Local shelves peers may read
+    replay_lock: Arc<Mutex<()>>,
+    path: PathBuf, // Trusted peers and pending invitations
+    unsaved: Arc<AtomicBool>, // The last write of the peer state failed
+}
+
+// Tag op handed to the applier of the local shelves
+pub enum Apply {
+    // Replicated from a peer, already recorded in the tag log
+    Merged(TagOp),
+    // Recorded in the tag log only once its local shelf accepted it, the outcome going
+    // back to the request
+    Request(TagOp, oneshot::Sender<Result<bool, UpdateErr>>),
+}
+
+// Liveness of a known (trusted) peer, maintained by the peer manager
+#[derive(Debug, Clone)]
+pub struct PeerStatus {
+    pub addr: NodeAddr,
+    pub online: bool,
+    pub last_seen: Option<u64>,
+    pub failures: u32,
+    pub next_retry: u64,
+}
+
+impl PeerStatus {
+    fn new(addr: NodeAddr) -> Self {
+        PeerStatus {
+            addr,
+            online: false,
+            last_seen: None,
+            failures: 0,
+            next_retry: 0,
+        }
+    }
+
+    fn seen(&mut self) {
+        self.online = true;
+        self.last_seen = Some(now());
+        self.failures = 0;
+    }
+
+    // Exponential backoff before the next reconnection attempt
+    fn failed(&mut self) {
+        self.online = false;
+        self.failures = self.failures.saturating_add(1);
+        let backoff = 2u64.saturating_pow(self.failures).min(MAX_BACKOFF);
+        self.next_retry = now() + backoff;
+    }
 }
 
 pub struct Client {
@@ -23,20 +108,1062 @@ pub struct Client {
     pub stream: Arc<Mutex<TcpStream>>
 }
 
-impl Service<String> for PeerService {
+// Invitation minted by this daemon, waiting to be redeemed (once) by a peer
+#[derive(Debug, Clone)]
+pub struct PendingInvite {
+    pub workspace_id: WorkspaceId,
+    pub expires: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct InviteTicket {
+    pub node: NodeAddr,
+    pub secret: InviteSecret,
+    pub workspace_id: WorkspaceId,
+    pub workspace_name: String,
+    pub expires: u64, // Unix timestamp (seconds)
+}
+
+impl Ticket for InviteTicket {
+    const KIND: &'static str = "ebiinvite";
+
+    fn to_bytes(&self) -> Vec<u8> {
+        Invitation {
+            node: NodeTicket::new(self.node.clone()).to_bytes(),
+            secret: self.secret.to_vec(),
+            workspace_id: self.workspace_id,
+            expires: self.expires,
+            workspace_name: self.workspace_name.clone(),
+        }
+        .encode_to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ticket::Error> {
+        let inv = Invitation::decode(bytes).map_err(|_| ticket::Error::Verify("malformed invitation"))?;
+        let node = NodeTicket::from_bytes(&inv.node)?;
+        let secret = inv
+            .secret
+            .try_into()
+            .map_err(|_| ticket::Error::Verify("invalid invitation secret"))?;
+        Ok(InviteTicket {
+            node: node.node_addr().clone(),
+            secret,
+            workspace_id: inv.workspace_id,
+            workspace_name: inv.workspace_name,
+            expires: inv.expires,
+        })
+    }
+}
+
+pub struct CreateInvite {
+    pub workspace_id: WorkspaceId,
+    pub workspace_name: String,
+    pub ttl: Option<u64>,
+}
+
+pub struct RedeemInvite {
+    pub ticket: String,
+}
+
+// Tag operation issued by a local client on a shared or remote shelf
+pub struct UpdateTag {
+    pub client_id: u64,
+    pub owner: Option<NodeId>, // Peer owning the shelf, if known by the caller
+    pub workspace_id: WorkspaceId,
+    pub shelf_id: ShelfId,
+    pub path: PathBuf,
+    pub tag: String,
+    pub kind: TagOpKind,
+}
+
+#[derive(Debug)]
+pub struct TagUpdate {
+    pub changed: bool,
+    pub pending: Option<u64>, // Id of the queued mutation, for remote shelves
+}
+
+// Request received from a peer over the ebi ALPN
+pub struct PeerMsg {
+    pub from: NodeId,
+    pub request: PeerRequest,
+}
+
+#[derive(Debug)]
+pub enum PeerError {
+    InvalidTicket,
+    Expired,
+    Rejected,
+    Untrusted,
+    Connection,
+    Malformed,
+    Queue, // The mutation queue could not be persisted
+    Persist, // The trusted peers and invitations could not be persisted
+    NotFound,
+    Update(UpdateErr), // The op could not be applied to its local shelf, and was not recorded
+}
+
+fn now() -> u64 {
+    Utc::now().timestamp() as u64
+}
+
+impl PeerService {
+    pub fn new(
+        endpoint: Endpoint,
+        peers: Arc<RwLock<HashMap<NodeId, Connection>>>,
+        clients: Arc<RwLock<Vec<Client>>>,
+        data_dir: &Path,
+    ) -> Self {
+        let (applier, applier_ops) = mpsc::unbounded_channel();
+        let path = data_dir.join("peers.pb");
+        let state = std::fs::read(&path)
+            .ok()
+            .and_then(|buf| PeerState::decode(&*buf).ok())
+            .unwrap_or_default();
+        let mut trusted = HashMap::new();
+        let mut status = HashMap::new();
+        for peer in state.trusted {
+            let Ok(node) = NodeTicket::from_bytes(&peer.node) else {
+                continue;
+            };
+            let addr = node.node_addr().clone();
+            trusted.insert(addr.node_id, peer.workspaces.into_iter().collect());
+            // Last known addresses, so the manager can reconnect right after a restart
+            status.insert(addr.node_id, PeerStatus::new(addr));
+        }
+        let invitations = state
+            .invitations
+            .into_iter()
+            .filter_map(|inv| {
+                let secret = InviteSecret::try_from(inv.secret).ok()?;
+                Some((
+                    secret,
+                    PendingInvite {
+                        workspace_id: inv.workspace_id,
+                        expires: inv.expires,
+                    },
+                ))
+            })
+            .collect();
+        PeerService {
+            peers,
+            clients,
+            tag_log: Arc::new(RwLock::new(TagLog::load(endpoint.node_id(), data_dir.join("taglog.pb")))),
+            endpoint,
+            invitations: Arc::new(RwLock::new(invitations)),
+            trusted: Arc::new(RwLock::new(trusted)),
+            applier,
+            applier_ops: Arc::new(std::sync::Mutex::new(Some(applier_ops))),
+            status: Arc::new(RwLock::new(status)),
+            summaries: Arc::new(RwLock::new(HashMap::new())),
+            remote_summaries: Arc::new(RwLock::new(HashMap::new())),
+            mutations: Arc::new(RwLock::new(MutationQueue::load(data_dir.join("mutations.pb")))),
+            shelves: Arc::new(RwLock::new(HashMap::new())),
+            replay_lock: Arc::new(Mutex::new(())),
+            path,
+            unsaved: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    async fn persist(&self) -> io::Result<()> {
+        let res = self.write_state().await;
+        self.unsaved.store(res.is_err(), Ordering::Relaxed);
+        res
+    }
+
+    // State files whose last write failed, so that what is on disk is behind
+    pub async fn unsaved(&self) -> Vec<String> {
+        let mut unsaved = Vec::new();
+        if self.unsaved.load(Ordering::Relaxed) {
+            unsaved.push(self.path.display().to_string());
+        }
+        let tag_log = self.tag_log.read().await;
+        if tag_log.unsaved() {
+            unsaved.push(tag_log.path().display().to_string());
+        }
+        drop(tag_log);
+        let mutations = self.mutations.read().await;
+        if mutations.unsaved() {
+            unsaved.push(mutations.path().display().to_string());
+        }
+        unsaved
+    }
+
+    // Ops for the local shelves, taken once by the applier
+    pub fn take_applier(&self) -> Option<mpsc::UnboundedReceiver<Apply>> {
+        self.applier_ops.lock().unwrap().take()
+    }
+
+    // Have op applied to its local shelf, and recorded only if the shelf accepted it.
+    // An op still running after APPLY_TIMEOUT may complete later, the peers then
+    // getting it through their next sync.
+    async fn apply(&self, op: TagOp) -> Result<bool, UpdateErr> {
+        let (tx, rx) = oneshot::channel();
+        self.applier
+            .send(Apply::Request(op, tx))
+            .map_err(|_| UpdateErr::Cancelled)?;
+        match timeout(APPLY_TIMEOUT, rx).await {
+            Ok(res) => res.map_err(|_| UpdateErr::Cancelled)?,
+            Err(_) => Err(UpdateErr::TimedOut),
+        }
+    }
+
+    // Record an op its local shelf accepted, and send it to the peers unless a later
+    // one already won
+    pub async fn record(&self, op: TagOp) -> Result<(), UpdateErr> {
+        let mut log = self.tag_log.write().await;
+        if log.merge_one(op.clone()) == Merge::Superseded {
+            return Ok(());
+        }
+        log.persist()
+            .map_err(|err| UpdateErr::Io(log.path().to_path_buf(), err.kind()))?;
+        drop(log);
+        let peer_srv = self.clone();
+        tokio::spawn(async move { peer_srv.push(vec![op]).await });
+        Ok(())
+    }
+
+    async fn write_state(&self) -> io::Result<()> {
+        let status = self.status.read().await;
+        let trusted = self
+            .trusted
+            .read()
+            .await
+            .iter()
+            .map(|(node_id, workspaces)| {
+                let addr = status.get(node_id).map_or_else(|| (*node_id).into(), |s| s.addr.clone());
+                TrustedPeer {
+                    node: NodeTicket::new(addr).to_bytes(),
+                    workspaces: workspaces.iter().cloned().collect(),
+                }
+            })
+            .collect();
+        let invitations = self
+            .invitations
+            .read()
+            .await
+            .iter()
+            .map(|(secret, invite)| PendingInvitation {
+                secret: secret.to_vec(),
+                workspace_id: invite.workspace_id,
+                expires: invite.expires,
+            })
+            .collect();
+        let state = PeerState { trusted, invitations };
+        write_atomic(&self.path, &state.encode_to_vec())
+    }
+
+    // Drop the invitations that expired without being redeemed
+    async fn prune_invitations(&self) {
+        let mut invitations = self.invitations.write().await;
+        let before = invitations.len();
+        invitations.retain(|_, invite| invite.expires >= now());
+        let pruned = invitations.len() != before;
+        drop(invitations);
+        if pruned && self.persist().await.is_err() {
+            println!("Failed to persist the peer state");
+        }
+    }
+
+    // Peer owning shelf_id, None if the shelf is local or unknown
+    pub async fn shelf_owner(&self, workspace_id: WorkspaceId, shelf_id: ShelfId) -> Option<NodeId> {
+        self.remote_summaries
+            .read()
+            .await
+            .iter()
+            .find(|((_, w_id), shelves)| *w_id == workspace_id && shelves.contains_key(&shelf_id))
+            .map(|((node_id, _), _)| *node_id)
+    }
+
+    // Deliver the queued mutations for node_id, in order, stopping at the first failure
+    pub async fn replay(&self, node_id: NodeId) {
+        let _guard = self.replay_lock.lock().await;
+        loop {
+            let Some(conn) = self.peers.read().await.get(&node_id).cloned() else {
+                return;
+            };
+            let Some(mutation) = self.mutations.read().await.front(&node_id) else {
+                return;
+            };
+            let request = PeerRequest {
+                request: Some(peer_request::Request::Mutate(mutation.op.clone().unwrap_or_default())),
+            };
+            let (outcome, reason) = match PeerService::request(&conn, request).await {
+                Ok(PeerResponse {
+                    response: Some(peer_response::Response::MutationAck(ack)),
+                }) => (ack.outcome(), ack.reason),
+                Ok(_) => (MutationOutcome::Rejected, "unexpected response".to_string()),
+                Err(_) => return, // Still unreachable, retried on the next connection
+            };
+            if self
+                .mutations
+                .write()
+                .await
+                .resolve(mutation.id, outcome, reason)
+                .is_err()
+            {
+                println!("Failed to persist the mutation queue");
+            }
+        }
+    }
+
+    // Apply a mutation sent by the peer that queued it for one of our shelves
+    async fn mutate(&self, from: NodeId, op: rpc::TagOp) -> MutationAck {
+        let reject = |reason: &str| MutationAck {
+            outcome: MutationOutcome::Rejected as i32,
+            reason: reason.to_string(),
+        };
+        let op = match TagOp::try_from(op) {
+            Ok(op) => op,
+            Err(err) => return reject(&err.to_string()),
+        };
+        if !self.is_trusted(&from, &op.workspace_id).await {
+            return reject("untrusted peer");
+        }
+        let owned = self
+            .summaries
+            .read()
+            .await
+            .get(&op.workspace_id)
+            .is_some_and(|shelves| shelves.contains_key(&op.shelf_id));
+        if !owned {
+            return reject("unknown shelf");
+        }
+        let (outcome, reason) = match self.apply(op).await {
+            Ok(true) => (MutationOutcome::Applied, String::new()),
+            Ok(false) => (MutationOutcome::Unchanged, String::new()),
+            Err(err @ UpdateErr::Conflict { .. }) => (MutationOutcome::Conflict, err.to_string()),
+            Err(err) => (MutationOutcome::Rejected, err.to_string()),
+        };
+        MutationAck {
+            outcome: outcome as i32,
+            reason,
+        }
+    }
+
+    // Record the summary of a local shelf and forward it to the peers sharing its workspace
+    pub async fn publish_summary(&self, workspace_id: WorkspaceId, shelf_id: ShelfId, shelf: &LocalShelfRef, summary: ShelfSummary) {
+        self.shelves.write().await.insert(shelf_id, shelf.clone());
+        let msg = rpc::ShelfSummaries {
+            workspace_id,
+            summaries: vec![summary.to_rpc(shelf_id)],
+        };
+        self.summaries
+            .write()
+            .await
+            .entry(workspace_id)
+            .or_default()
+            .insert(shelf_id, summary);
+
+        let peers = self.peers.read().await.clone();
+        for (node_id, conn) in peers {
+            if !self.is_trusted(&node_id, &workspace_id).await {
+                continue;
+            }
+            let request = PeerRequest {
+                request: Some(peer_request::Request::Summaries(msg.clone())),
+            };
+            let _ = PeerService::request(&conn, request).await;
+        }
+    }
+
+    // Local shelf of the workspace, if from may read it
+    async fn shared_shelf(&self, from: NodeId, workspace_id: WorkspaceId, shelf_id: ShelfId) -> Option<LocalShelfRef> {
+        if !self.is_trusted(&from, &workspace_id).await {
+            return None;
+        }
+        let owned = self
+            .summaries
+            .read()
+            .await
+            .get(&workspace_id)
+            .is_some_and(|shelves| shelves.contains_key(&shelf_id));
+        self.shelves.read().await.get(&shelf_id).cloned().filter(|_| owned)
+    }
+
+    // Content of a file of one of our shelves, for a peer sharing its workspace
+    async fn serve_file(&self, from: NodeId, req: FetchFile) -> FileContent {
+        let not_found = FileContent::default();
+        let Some(shelf) = self.shared_shelf(from, req.workspace_id, req.shelf_id).await else {
+            return not_found;
+        };
+        // The tree may load directories, and the checks touch the disk
+        tokio::task::spawn_blocking(move || {
+            let root = shelf.root_path();
+            let path = PathBuf::from(&req.path);
+            if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+                return not_found;
+            }
+            // Only files the shelf knows, and nothing that resolves outside of its root
+            // (such as through a symbolic link)
+            let path = root.join(path);
+            if !shelf.contains(&path) {
+                return not_found;
+            }
+            let (Ok(root), Ok(path)) = (root.canonicalize(), path.canonicalize()) else {
+                return not_found;
+            };
+            if !path.starts_with(&root) {
+                return not_found;
+            }
+            let Ok(meta) = std::fs::metadata(&path) else {
+                return not_found;
+            };
+            if !meta.is_file() || meta.len() > MAX_FILE_CONTENT {
+                return not_found;
+            }
+            let modified = meta
+                .modified()
+                .map_or(0, |t| chrono::DateTime::<Utc>::from(t).timestamp().max(0) as u64);
+            if req.cached_modified != 0 && req.cached_modified == modified {
+                return FileContent {
+                    found: true,
+                    modified,
+                    unchanged: true,
+                    ..Default::default()
+                };
+            }
+            let Ok(data) = std::fs::read(&path) else {
+                return not_found;
+            };
+            FileContent {
+                found: true,
+                modified,
+                data,
+                unchanged: false,
+            }
+        })
+        .await
+        .unwrap_or_default()
+    }
+
+    async fn serve_retrieve(&self, from: NodeId, req: RetrieveTag) -> QueryResponse {
+        let Some(shelf) = self.shared_shelf(from, req.workspace_id, req.shelf_id).await else {
+            return QueryResponse::default();
+        };
+        let tag = match (req.untagged, req.tag.is_empty()) {
+            (false, false) => match TagManager::retrieve_tag(req.workspace_id, &req.tag) {
+                Some(tag) => Some(tag),
+                None => return QueryResponse::default(),
+            },
+            _ => None,
+        };
+        let files = tokio::task::spawn_blocking(move || {
+            let scope = shelf.scope(shelf.root_path())?;
+            let ids = match &tag {
+                Some(tag) => scope.tagged(tag),
+                None if req.untagged => scope.untagged(),
+                None => scope.all(),
+            };
+            Ok::<_, UpdateErr>(
+                scope
+                    .files(&ids)
+                    .iter()
+                    .map(|file| {
+                        let file = file.file_ref.read().unwrap();
+                        rpc::File::from(&FileID::local(file.path().clone(), file.metadata().clone()))
+                    })
+                    .collect(),
+            )
+        })
+        .await;
+        QueryResponse {
+            files: files.ok().and_then(Result::ok).unwrap_or_default(),
+        }
+    }
+
+    // Files of a shelf of node carrying tag
+    pub async fn retrieve(&self, node: NodeId, workspace_id: WorkspaceId, shelf_id: ShelfId, tag: String) -> Result<Vec<rpc::File>, PeerError> {
+        self.retrieve_files(node, RetrieveTag {
+            workspace_id,
+            shelf_id,
+            tag,
+            untagged: false,
+        })
+        .await
+    }
+
+    // Every file of a shelf of node, or only the untagged ones
+    pub async fn retrieve_all(&self, node: NodeId, workspace_id: WorkspaceId, shelf_id: ShelfId, untagged: bool) -> Result<Vec<rpc::File>, PeerError> {
+        self.retrieve_files(node, RetrieveTag {
+            workspace_id,
+            shelf_id,
+            tag: String::new(),
+            untagged,
+        })
+        .await
+    }
+
+    async fn retrieve_files(&self, node: NodeId, req: RetrieveTag) -> Result<Vec<rpc::File>, PeerError> {
+        let Some(conn) = self.peers.read().await.get(&node).cloned() else {
+            return Err(PeerError::Connection);
+        };
+        let request = PeerRequest {
+            request: Some(peer_request::Request::Retrieve(req)),
+        };
+        match PeerService::request(&conn, request).await?.response {
+            Some(peer_response::Response::Files(res)) => Ok(res.files),
+            _ => Err(PeerError::Malformed),
+        }
+    }
+
+    // With cached_modified set, the content is only sent if the file changed since
+    pub async fn fetch_file(&self, node: NodeId, workspace_id: WorkspaceId, shelf_id: ShelfId, path: &Path, cached_modified: Option<u64>) -> Result<FileContent, PeerError> {
+        let Some(conn) = self.peers.read().await.get(&node).cloned() else {
+            return Err(PeerError::Connection);
+        };
+        let request = PeerRequest {
+            request: Some(peer_request::Request::Fetch(FetchFile {
+                workspace_id,
+                shelf_id,
+                path: path.to_string_lossy().into_owned(),
+                cached_modified: cached_modified.unwrap_or(0),
+            })),
+        };
+        match PeerService::request(&conn, request).await?.response {
+            Some(peer_response::Response::Content(content)) if content.found => Ok(content),
+            Some(peer_response::Response::Content(_)) => Err(PeerError::NotFound),
+            _ => Err(PeerError::Malformed),
+        }
+    }
+
+    // Pull the summaries of every shelf node exposes in the workspaces shared with it
+    pub async fn fetch_summaries(&self, node_id: NodeId) -> Result<(), PeerError> {
+        let Some(conn) = self.peers.read().await.get(&node_id).cloned() else {
+            return Err(PeerError::Connection);
+        };
+        let workspaces = self.trusted.read().await.get(&node_id).cloned().unwrap_or_default();
+        for workspace_id in workspaces {
+            let request = PeerRequest {
+                request: Some(peer_request::Request::GetSummaries(SummaryRequest { workspace_id })),
+            };
+            if let Some(peer_response::Response::Summaries(msg)) =
+                PeerService::request(&conn, request).await?.response
+            {
+                self.store_summaries(node_id, msg, true).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn store_summaries(&self, from: NodeId, msg: rpc::ShelfSummaries, replace: bool) {
+        if !self.is_trusted(&from, &msg.workspace_id).await {
+            return;
+        }
+        let mut remote = self.remote_summaries.write().await;
+        let shelves = remote.entry((from, msg.workspace_id)).or_default();
+        if replace {
+            shelves.clear();
+        }
+        for summary in msg.summaries {
+            shelves.insert(summary.shelf_id, summary.into());
+        }
+    }
+
+    // Register a newly established connection: serve it, mark the peer online and catch up
+    pub async fn connected(&self, conn: Connection) {
+        let Ok(node_id) = conn.remote_node_id() else {
+            return;
+        };
+        if let Some(old) = self.peers.write().await.insert(node_id, conn.clone()) {
+            if old.stable_id() != conn.stable_id() {
+                old.close(0u32.into(), b"replaced");
+            }
+        }
+        let addr = self.endpoint.remote_info(node_id).map(NodeAddr::from);
+        let moved = {
+            let mut status = self.status.write().await;
+            let status = status
+                .entry(node_id)
+                .or_insert_with(|| PeerStatus::new(node_id.into()));
+            status.seen();
+            match addr {
+                Some(addr) if !addr.is_empty() && addr != status.addr => {
+                    status.addr = addr;
+                    true
+                }
+                _ => false,
+            }
+        };
+        if moved && self.trusted.read().await.contains_key(&node_id) && self.persist().await.is_err() {
+            println!("Failed to persist the peer state");
+        }
+        tokio::spawn(handle_peer(conn, self.clone()));
+        let _ = self.sync_with(node_id).await;
+        let _ = self.fetch_summaries(node_id).await;
+        self.replay(node_id).await;
+    }
+
+    // Drop conn if it is still the active connection to node_id
+    async fn disconnected(&self, node_id: NodeId, conn: &Connection) {
+        let mut peers = self.peers.write().await;
+        if peers.get(&node_id).is_some_and(|c| c.stable_id() == conn.stable_id()) {
+            peers.remove(&node_id);
+            if let Some(status) = self.status.write().await.get_mut(&node_id) {
+                status.online = false;
+            }
+        }
+    }
+
+    async fn heartbeat(&self, node_id: NodeId, conn: Connection) {
+        let request = PeerRequest {
+            request: Some(peer_request::Request::Ping(Ping { timestamp: now() })),
+        };
+        match timeout(HEARTBEAT_TIMEOUT, PeerService::request(&conn, request)).await {
+            Ok(Ok(_)) => {
+                if let Some(status) = self.status.write().await.get_mut(&node_id) {
+                    status.seen();
+                }
+                // Anti-entropy: picks up the ops whose push was lost
+                let _ = self.sync_with(node_id).await;
+            }
+            _ => {
+                conn.close(0u32.into(), b"heartbeat timeout");
+                self.disconnected(node_id, &conn).await;
+                if let Some(status) = self.status.write().await.get_mut(&node_id) {
+                    status.failed();
+                }
+            }
+        }
+    }
+
+    async fn reconnect(&self, node_id: NodeId) {
+        let Some(addr) = self.status.read().await.get(&node_id).map(|s| s.addr.clone()) else {
+            return;
+        };
+        match self.endpoint.connect(addr, ALPN).await {
+            Ok(conn) => self.connected(conn).await,
+            Err(_) => {
+                if let Some(status) = self.status.write().await.get_mut(&node_id) {
+                    status.failed();
+                }
+            }
+        }
+    }
+
+    // Keep connections to trusted peers alive: heartbeat the connected ones, reconnect
+    // (with backoff) to the others. The first round runs right away, so the peers known
+    // before a restart are reached again and their queued mutations replayed.
+    pub async fn run_manager(self) {
+        let mut ticker = interval(HEARTBEAT_INTERVAL);
+        loop {
+            ticker.tick().await;
+            self.prune_invitations().await;
+            let known = self.trusted.read().await.keys().cloned().collect::<Vec<NodeId>>();
+            for node_id in known {
+                let conn = self.peers.read().await.get(&node_id).cloned();
+                let peer_srv = self.clone();
+                match conn {
+                    Some(conn) if conn.close_reason().is_none() => {
+                        tokio::spawn(async move { peer_srv.heartbeat(node_id, conn).await });
+                    }
+                    Some(conn) => {
+                        self.disconnected(node_id, &conn).await;
+                    }
+                    None => {
+                        let mut status = self.status.write().await;
+                        let status = status
+                            .entry(node_id)
+                            .or_insert_with(|| PeerStatus::new(node_id.into()));
+                        if status.next_retry <= now() {
+                            // Hold off further attempts until this one resolves
+                            status.next_retry = now() + MAX_BACKOFF;
+                            tokio::spawn(async move { peer_srv.reconnect(node_id).await });
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub async fn is_trusted(&self, node: &NodeId, workspace_id: &WorkspaceId) -> bool {
+        self.trusted
+            .read()
+            .await
+            .get(node)
+            .is_some_and(|ws| ws.contains(workspace_id))
+    }
+
+    // Send a single request to the peer behind conn and wait for its response
+    pub async fn request(conn: &Connection, req: PeerRequest) -> Result<PeerResponse, PeerError> {
+        let (mut send, mut recv) = conn.open_bi().await.map_err(|_| PeerError::Connection)?;
+        send.write_all(&req.encode_to_vec())
+            .await
+            .map_err(|_| PeerError::Connection)?;
+        send.finish().map_err(|_| PeerError::Connection)?;
+        let buf = recv
+            .read_to_end(MAX_PEER_MSG)
+            .await
+            .map_err(|_| PeerError::Connection)?;
+        PeerResponse::decode(&*buf).map_err(|_| PeerError::Malformed)
+    }
+
+    // Pull every tag operation recorded by node after the prefix of its log we already
+    // merged, for each workspace shared with it
+    pub async fn sync_with(&self, node: NodeId) -> Result<(), PeerError> {
+        let Some(conn) = self.peers.read().await.get(&node).cloned() else {
+            return Err(PeerError::Connection);
+        };
+        let workspaces = self.trusted.read().await.get(&node).cloned().unwrap_or_default();
+        for workspace_id in workspaces {
+            let after = self.tag_log.read().await.acked(node, workspace_id);
+            let request = PeerRequest {
+                request: Some(peer_request::Request::Sync(SyncRequest { workspace_id, after })),
+            };
+            if let Some(peer_response::Response::Ops(ops)) =
+                PeerService::request(&conn, request).await?.response
+            {
+                let seq = ops.seq;
+                let ops = self.trusted_ops(node, ops).await;
+                let mut log = self.tag_log.write().await;
+                let effective = log.sync(node, workspace_id, ops, seq);
+                log.persist().map_err(|_| PeerError::Persist)?;
+                drop(log);
+                for op in effective {
+                    let _ = self.applier.send(Apply::Merged(op));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Ops of a peer message for the workspaces from is trusted for
+    async fn trusted_ops(&self, from: NodeId, ops: TagOps) -> Vec<TagOp> {
+        let trusted = self.trusted.read().await.get(&from).cloned().unwrap_or_default();
+        ops.ops
+            .into_iter()
+            .filter_map(|op| TagOp::try_from(op).ok())
+            .filter(|op| trusted.contains(&op.workspace_id))
+            .collect()
+    }
+
+    // Merge ops pushed by a peer. They do not advance its acknowledged prefix: a push
+    // that got lost is resent by the next sync.
+    async fn receive(&self, from: NodeId, ops: TagOps) {
+        let ops = self.trusted_ops(from, ops).await;
+        let mut log = self.tag_log.write().await;
+        let effective = log.merge(ops);
+        if !effective.is_empty() && log.persist().is_err() {
+            println!("Failed to persist the tag log");
+        }
+        drop(log);
+        for op in effective {
+            let _ = self.applier.send(Apply::Merged(op));
+        }
+    }
+
+    // Send ops to every connected peer trusted for their workspace
+    async fn push(&self, ops: Vec<TagOp>) {
+        let peers = self.peers.read().await.clone();
+        let trusted = self.trusted.read().await.clone();
+        for (node, conn) in peers {
+            let ops = ops
+                .iter()
+                .filter(|op| trusted.get(&node).is_some_and(|ws| ws.contains(&op.workspace_id)))
+                .map(|op| op.into())
+                .collect::<Vec<_>>();
+            if ops.is_empty() {
+                continue;
+            }
+            let request = PeerRequest {
+                request: Some(peer_request::Request::Push(TagOps { ops, seq: 0 })),
+            };
+            // Best effort: peers that missed it catch up through their next sync_with
+            let _ = PeerService::request(&conn, request).await;
+        }
+    }
+
+    async fn redeem(&self, from: NodeId, secret: Vec<u8>) -> PeerResponse {
+        let reject = PeerResponse {
+            response: Some(peer_response::Response::RedeemAck(RedeemAck {
+                accepted: false,
+                workspace_id: 0,
+            })),
+        };
+        let Ok(secret) = InviteSecret::try_from(secret) else {
+            return reject;
+        };
+        self.prune_invitations().await;
+        // Invitations are one-time: consume it
+        let Some(invite) = self.invitations.write().await.remove(&secret) else {
+            return reject;
+        };
+        self.trusted
+            .write()
+            .await
+            .entry(from)
+            .or_default()
+            .insert(invite.workspace_id);
+        if self.persist().await.is_err() {
+            println!("Failed to persist the peer state");
+        }
+        PeerResponse {
+            response: Some(peer_response::Response::RedeemAck(RedeemAck {
+                accepted: true,
+                workspace_id: invite.workspace_id,
+            })),
+        }
+    }
+}
+
+// Serve requests coming from a peer until the connection is closed
+pub async fn handle_peer(conn: Connection, service: PeerService) {
+    let Ok(from) = conn.remote_node_id() else {
+        return;
+    };
+    while let Ok((mut send, mut recv)) = conn.accept_bi().await {
+        let Ok(buf) = recv.read_to_end(MAX_PEER_MSG).await else {
+            continue;
+        };
+        let Ok(request) = PeerRequest::decode(&*buf) else {
+            continue;
+        };
+        if let Some(status) = service.status.write().await.get_mut(&from) {
+            status.seen();
+        }
+        // Served concurrently, so that a mutation waiting on its shelf does not hold the
+        // heartbeats queued behind it
+        let mut service = service.clone();
+        tokio::spawn(async move {
+            if let Ok(response) = service.call(PeerMsg { from, request }).await {
+                let _ = send.write_all(&response.encode_to_vec()).await;
+                let _ = send.finish();
+            }
+        });
+    }
+    service.disconnected(from, &conn).await;
+    println!("Peer disconnected: {}", from);
+}
 
-    type Response = ();
-    type Error = ();
+impl Service<PeerMsg> for PeerService {
+    type Response = PeerResponse;
+    type Error = PeerError;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, req: String) -> Self::Future {
+    fn call(&mut self, req: PeerMsg) -> Self::Future {
+        let peer_srv = self.clone();
+        Box::pin(async move {
+            match req.request.request {
+                Some(peer_request::Request::Redeem(Redeem { secret })) => {
+                    Ok(peer_srv.redeem(req.from, secret).await)
+                }
+                Some(peer_request::Request::Sync(SyncRequest { workspace_id, after })) => {
+                    if !peer_srv.is_trusted(&req.from, &workspace_id).await {
+                        return Err(PeerError::Untrusted);
+                    }
+                    let (ops, seq) = peer_srv.tag_log.read().await.missing(workspace_id, after);
+                    Ok(PeerResponse {
+                        response: Some(peer_response::Response::Ops(TagOps {
+                            ops: ops.iter().map(|op| op.into()).collect(),
+                            seq,
+                        })),
+                    })
+                }
+                Some(peer_request::Request::GetSummaries(SummaryRequest { workspace_id })) => {
+                    if !peer_srv.is_trusted(&req.from, &workspace_id).await {
+                        return Err(PeerError::Untrusted);
+                    }
+                    let summaries = peer_srv
+                        .summaries
+                        .read()
+                        .await
+                        .get(&workspace_id)
+                        .map(|shelves| {
+                            shelves
+                                .iter()
+                                .map(|(shelf_id, summary)| summary.to_rpc(*shelf_id))
+                                .collect()
+                        })
+                        .unwrap_or_default();
+                    Ok(PeerResponse {
+                        response: Some(peer_response::Response::Summaries(rpc::ShelfSummaries {
+                            workspace_id,
+                            summaries,
+                        })),
+                    })
+                }
+                Some(peer_request::Request::Summaries(msg)) => {
+                    peer_srv.store_summaries(req.from, msg, false).await;
+                    Ok(PeerResponse {
+                        response: Some(peer_response::Response::Summaries(rpc::ShelfSummaries::default())),
+                    })
+                }
+                Some(peer_request::Request::Mutate(op)) => Ok(PeerResponse {
+                    response: Some(peer_response::Response::MutationAck(
+                        peer_srv.mutate(req.from, op).await,
+                    )),
+                }),
+                Some(peer_request::Request::Fetch(fetch)) => Ok(PeerResponse {
+                    response: Some(peer_response::Response::Content(
+                        peer_srv.serve_file(req.from, fetch).await,
+                    )),
+                }),
+                Some(peer_request::Request::Retrieve(retrieve)) => Ok(PeerResponse {
+                    response: Some(peer_response::Response::Files(
+                        peer_srv.serve_retrieve(req.from, retrieve).await,
+                    )),
+                }),
+                Some(peer_request::Request::Ping(ping)) => Ok(PeerResponse {
+                    response: Some(peer_response::Response::Pong(ping)),
+                }),
+                Some(peer_request::Request::Push(ops)) => {
+                    peer_srv.receive(req.from, ops).await;
+                    Ok(PeerResponse {
+                        response: Some(peer_response::Response::Ops(TagOps::default())),
+                    })
+                }
+                None => Err(PeerError::Malformed),
+            }
+        })
+    }
+}
+
+impl Service<UpdateTag> for PeerService {
+    type Response = TagUpdate;
+    type Error = PeerError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: UpdateTag) -> Self::Future {
+        let peer_srv = self.clone();
         Box::pin(async move {
-        });
-        todo!()
+            let owner = match req.owner {
+                Some(owner) => Some(owner),
+                None => peer_srv.shelf_owner(req.workspace_id, req.shelf_id).await,
+            };
+            if let Some(owner) = owner {
+                // Remote shelf: the owning peer decides, queue until it acknowledges
+                let op = {
+                    let mut log = peer_srv.tag_log.write().await;
+                    let op = log.stamp(req.workspace_id, req.shelf_id, req.path, req.tag, req.kind);
+                    // The clock must never go back, or the stamp could be reused
+                    log.persist().map_err(|_| PeerError::Persist)?;
+                    op
+                };
+                let id = peer_srv
+                    .mutations
+                    .write()
+                    .await
+                    .push(req.client_id, owner, &op)
+                    .map_err(|_| PeerError::Queue)?;
+                tokio::spawn(async move { peer_srv.replay(owner).await });
+                return Ok(TagUpdate {
+                    changed: false,
+                    pending: Some(id),
+                });
+            }
+
+            // Local shelf: recorded and sent to the peers only once the shelf accepted it
+            let op = peer_srv
+                .tag_log
+                .write()
+                .await
+                .stamp(req.workspace_id, req.shelf_id, req.path, req.tag, req.kind);
+            let changed = peer_srv.apply(op).await.map_err(PeerError::Update)?;
+            Ok(TagUpdate {
+                changed,
+                pending: None,
+            })
+        })
+    }
+}
+
+impl Service<CreateInvite> for PeerService {
+    type Response = InviteTicket;
+    type Error = PeerError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: CreateInvite) -> Self::Future {
+        let peer_srv = self.clone();
+        Box::pin(async move {
+            let node = peer_srv
+                .endpoint
+                .node_addr()
+                .await
+                .map_err(|_| PeerError::Connection)?;
+            let mut secret: InviteSecret = [0; 32];
+            rand::rngs::OsRng.fill_bytes(&mut secret);
+            let expires = now() + req.ttl.unwrap_or(DEFAULT_INVITE_TTL);
+            peer_srv.invitations.write().await.insert(
+                secret,
+                PendingInvite {
+                    workspace_id: req.workspace_id,
+                    expires,
+                },
+            );
+            // An invitation lost on restart could not be redeemed
+            peer_srv.persist().await.map_err(|_| PeerError::Persist)?;
+            Ok(InviteTicket {
+                node,
+                secret,
+                workspace_id: req.workspace_id,
+                workspace_name: req.workspace_name,
+                expires,
+            })
+        })
+    }
+}
+
+impl Service<RedeemInvite> for PeerService {
+    type Response = InviteTicket; // The redeemed ticket, once the inviter accepted it
+    type Error = PeerError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: RedeemInvite) -> Self::Future {
+        let peer_srv = self.clone();
+        Box::pin(async move {
+            let ticket =
+                InviteTicket::deserialize(req.ticket.trim()).map_err(|_| PeerError::InvalidTicket)?;
+            if ticket.expires < now() {
+                return Err(PeerError::Expired);
+            }
+            let node_id = ticket.node.node_id;
+            let conn = peer_srv
+                .endpoint
+                .connect(ticket.node.clone(), ALPN)
+                .await
+                .map_err(|_| PeerError::Connection)?;
+            let request = PeerRequest {
+                request: Some(peer_request::Request::Redeem(Redeem {
+                    secret: ticket.secret.to_vec(),
+                })),
+            };
+            match PeerService::request(&conn, request).await?.response {
+                Some(peer_response::Response::RedeemAck(RedeemAck { accepted: true, .. })) => {
+                    peer_srv
+                        .trusted
+                        .write()
+                        .await
+                        .entry(node_id)
+                        .or_default()
+                        .insert(ticket.workspace_id);
+                    peer_srv
+                        .status
+                        .write()
+                        .await
+                        .insert(node_id, PeerStatus::new(ticket.node.clone()));
+                    if peer_srv.persist().await.is_err() {
+                        println!("Failed to persist the peer state");
+                    }
+                    peer_srv.connected(conn).await;
+                    Ok(ticket)
+                }
+                _ => Err(PeerError::Rejected),
+            }
+        })
     }
 }
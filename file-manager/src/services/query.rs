@@ -1,91 +1,277 @@
 use tower::{Service};
-use std::{sync::Arc, future::Future, pin::Pin, task::{Context, Poll}};
-use crate::query::{Query, FileOrder, RetrieveService, QueryErr, OrderedFileID};
-use crate::services::cache::CacheService;
-use crate::services::peer::PeerService;
+use std::sync::Arc;
+use crate::query::{Query, FileOrder, RetrieveService, QueryErr, OrderedFileID, FileID};
+use crate::services::cache::{CacheService, RemoteTarget, RetrieveFiles, SortFiles};
+use roaring::RoaringBitmap;
+use crate::services::peer::{PeerError, PeerService};
+use crate::shelf::file::FileMetadata;
+use crate::shelf::shelf::Scope;
 use std::collections::BTreeSet;
-use crate::workspace::WorkspaceId;
+use crate::workspace::{ShelfId, WorkspaceId};
+use iroh::NodeId;
+use std::path::PathBuf;
+use std::sync::Mutex;
 use crate::tag::TagRef;
+use crate::rpc;
 use std::collections::HashMap;
-use tokio::task::JoinHandle;
-
-type TaskID = u64;
+use std::fmt::Debug;
 
+// Retrieves from the workspace index, or from the directory of a local shelf the
+// query is scoped to
 #[derive(Clone)]
-struct QueryService
-{
-    retrieve_serv: Retrieve,
-    peer: PeerService,
-    tasks: Arc<HashMap<TaskID, JoinHandle<()>>>
+pub struct Retrieve {
+    cache: CacheService,
+    workspace_id: WorkspaceId,
+    scope: Option<Arc<Scope>>,
 }
 
-#[derive(Clone)]
-struct Retrieve {
-    cache: CacheService,
+impl Retrieve {
+    pub fn new(cache: CacheService, workspace_id: WorkspaceId) -> Self {
+        Retrieve {
+            cache,
+            workspace_id,
+            scope: None,
+        }
+    }
+
+    pub fn scoped(self, scope: Scope) -> Self {
+        Retrieve {
+            scope: Some(Arc::new(scope)),
+            ..self
+        }
+    }
+
+    // Files of every shelf of the workspace matching query: local shelves through the
+    // workspace index, remote shelves that may match through their last known results,
+    // or by asking their peer. Scoped queries are answered by their directory alone, and
+    // not cached.
+    pub async fn files<T>(&self, query_str: &str, order: T) -> Result<Vec<rpc::File>, QueryErr>
+    where
+        T: FileOrder + Clone + Debug + Send + Sync + 'static,
+        OrderedFileID<T>: Ord,
+    {
+        let mut query = Query::new(query_str, order.clone(), self.workspace_id)?;
+        // Names no peer knows are reported, the others only match remote files
+        for (name, span) in query.unresolved() {
+            if !self.cache.known_remotely(self.workspace_id, name).await {
+                return Err(QueryErr::UnknownTag {
+                    name: name.to_string(),
+                    span,
+                });
+            }
+        }
+        if self.scope.is_some() {
+            let files = query.evaluate(self.clone()).await?;
+            return Ok(files.iter().map(|f| f.file_id().into()).collect());
+        }
+        let targets = self.cache.route(self.workspace_id, &query).await;
+        let mut files: Vec<rpc::File> = self
+            .query(query)
+            .await?
+            .iter()
+            .map(|f| f.file_id().into())
+            .collect();
+        for target in targets {
+            if let Some(remote) = self.cache.cached_remote(&target, query_str).await {
+                files.extend(remote);
+                continue;
+            }
+            let query = Query::new(query_str, order.clone(), self.workspace_id)?;
+            let retrieve = RemoteRetrieve::new(self.cache.peer_service().clone(), self.workspace_id, &target);
+            match retrieve.evaluate(query).await {
+                Ok(mut remote) => {
+                    self.cache.store_remote(&target, query_str, remote.clone());
+                    self.cache.mark_pending(&target, &mut remote).await;
+                    files.extend(remote);
+                }
+                // Offline peers only leave their shelves out of the result
+                Err(QueryErr::Unreachable(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(files)
+    }
+
+    // Evaluate query, reusing the last result computed at the current workspace version
+    async fn query<T>(&self, mut query: Query<T>) -> Result<BTreeSet<OrderedFileID<T>>, QueryErr>
+    where
+        T: FileOrder + Clone + Debug + Send + Sync + 'static,
+        OrderedFileID<T>: Ord,
+    {
+        let key = query.key();
+        if let Some(files) = self.cache.cached_query(self.workspace_id, &key) {
+            return Ok(files);
+        }
+        let version = self.cache.workspace_version(self.workspace_id);
+        let files = query.evaluate(self.clone()).await?;
+        self.cache.store_query(self.workspace_id, key, version, files.clone());
+        Ok(files)
+    }
 }
 
 impl RetrieveService for Retrieve {
-    async fn get_files<T: FileOrder>(&self, tag: TagRef) -> Result<BTreeSet<OrderedFileID<T>>, QueryErr> {
-        todo!();
+    async fn get_files(&self, tag: TagRef) -> Result<RoaringBitmap, QueryErr> {
+        if let Some(scope) = &self.scope {
+            return Ok(scope.tagged(&tag));
+        }
+        self.cache
+            .clone()
+            .call(RetrieveFiles::Tag(self.workspace_id, tag))
+            .await
+            .map_err(|_| QueryErr::WorkspaceNotFound(self.workspace_id))
     }
 
-    async fn get_all<T: FileOrder>(&self) -> Result<BTreeSet<OrderedFileID<T>>, QueryErr> {
-        todo!();
+    // No local file carries a tag the workspace does not know
+    async fn get_named(&self, _name: &str) -> Result<RoaringBitmap, QueryErr> {
+        Ok(RoaringBitmap::new())
+    }
+
+    async fn get_all(&self) -> Result<RoaringBitmap, QueryErr> {
+        if let Some(scope) = &self.scope {
+            return Ok(scope.all());
+        }
+        self.cache
+            .clone()
+            .call(RetrieveFiles::All(self.workspace_id))
+            .await
+            .map_err(|_| QueryErr::WorkspaceNotFound(self.workspace_id))
     }
-}
 
+    async fn get_untagged(&self) -> Result<RoaringBitmap, QueryErr> {
+        if let Some(scope) = &self.scope {
+            return Ok(scope.untagged());
+        }
+        self.cache
+            .clone()
+            .call(RetrieveFiles::Untagged(self.workspace_id))
+            .await
+            .map_err(|_| QueryErr::WorkspaceNotFound(self.workspace_id))
+    }
 
-struct Request<'a, FileOrd: FileOrder> {
-    query: String,
-    ord: &'a FileOrd,
-    workspace_id: WorkspaceId,
-    partial: bool,
-    client_id: u64
+    async fn sort<T>(&self, files: RoaringBitmap, order: T) -> Result<BTreeSet<OrderedFileID<T>>, QueryErr>
+    where
+        T: FileOrder + Clone + Send + Sync + 'static,
+        OrderedFileID<T>: Ord,
+    {
+        if let Some(scope) = &self.scope {
+            return Ok(scope
+                .files(&files)
+                .iter()
+                .map(|file| {
+                    let file = file.file_ref.read().unwrap();
+                    OrderedFileID::new(FileID::local(file.path().clone(), file.metadata().clone()), order.clone())
+                })
+                .collect());
+        }
+        self.cache
+            .clone()
+            .call(SortFiles {
+                workspace_id: self.workspace_id,
+                files,
+                order,
+            })
+            .await
+            .map_err(|_| QueryErr::WorkspaceNotFound(self.workspace_id))
+    }
 }
 
+type RemoteFiles = (HashMap<String, u32>, Vec<rpc::File>); // Ids by path, files by id
 
-impl<'b, FileOrd: Clone + FileOrder + Send> Service<Request<'b, FileOrd>> for QueryService 
-where  OrderedFileID<FileOrd>: Ord, &'b FileOrd: Send
-{
+// Evaluates a query over a shelf of another peer, fetching the files of each tag (or all
+// of them, or the untagged ones) from it. Files get ids local to the evaluation.
+struct RemoteRetrieve {
+    peer: PeerService,
+    node: NodeId,
+    workspace_id: WorkspaceId,
+    shelf_id: ShelfId,
+    files: Arc<Mutex<RemoteFiles>>,
+}
 
-    type Response = ();
-    type Error = ();
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'b>>;
+impl RemoteRetrieve {
+    fn new(peer: PeerService, workspace_id: WorkspaceId, target: &RemoteTarget) -> Self {
+        RemoteRetrieve {
+            peer,
+            node: target.node,
+            workspace_id,
+            shelf_id: target.shelf_id,
+            files: Arc::new(Mutex::new(RemoteFiles::default())),
+        }
+    }
 
-    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
+    async fn evaluate<T>(&self, mut query: Query<T>) -> Result<Vec<rpc::File>, QueryErr>
+    where
+        T: FileOrder + Clone + Debug + Send + Sync + 'static,
+        OrderedFileID<T>: Ord,
+    {
+        let files = query.evaluate(self.clone()).await?;
+        let (ids, all) = &*self.files.lock().unwrap();
+        Ok(files
+            .iter()
+            .filter_map(|f| ids.get(&*f.file_id().path().to_string_lossy()))
+            .map(|id| all[*id as usize].clone())
+            .collect())
     }
 
-    fn call(&mut self, req: Request<'b, FileOrd>) -> Self::Future {
-        todo!();
-        Box::pin(async move {
-            let query_str = req.query;
-            let mut query = Query::new(&query_str, req.ord.clone()).map_err(|_| ())?;
-            // Ok until now
-            //query.evaluate(self.retrieve_serv);
+    fn ids(&self, files: Result<Vec<rpc::File>, PeerError>) -> Result<RoaringBitmap, QueryErr> {
+        let files = files.map_err(|_| QueryErr::Unreachable(self.node))?;
+        let (ids, all) = &mut *self.files.lock().unwrap();
+        Ok(files
+            .into_iter()
+            .map(|file| {
+                *ids.entry(file.path.clone()).or_insert_with(|| {
+                    all.push(file);
+                    all.len() as u32 - 1
+                })
+            })
+            .collect())
+    }
+}
 
-            let peer_service = &self.peer.clone();
-            let join_handle: JoinHandle<()> = tokio::spawn(async move {
-                //query.evaluate(self.retrieve_serv);
-                //peer_service.call();
-            }); 
-            Ok(()) })
+impl Clone for RemoteRetrieve {
+    fn clone(&self) -> Self {
+        RemoteRetrieve {
+            peer: self.peer.clone(),
+            files: self.files.clone(),
+            ..*self
+        }
     }
 }
 
-impl<FileOrd: Clone + FileOrder> Service<(Query<FileOrd>, WorkspaceId)> for QueryService {
-    type Response = ();
-    type Error = ();
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+impl RetrieveService for RemoteRetrieve {
+    async fn get_files(&self, tag: TagRef) -> Result<RoaringBitmap, QueryErr> {
+        self.get_named(&tag.name()).await
+    }
 
-    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
+    // Tags are matched by name on the peer
+    async fn get_named(&self, name: &str) -> Result<RoaringBitmap, QueryErr> {
+        let files = self.peer.retrieve(self.node, self.workspace_id, self.shelf_id, name.to_string()).await;
+        self.ids(files)
     }
 
-    fn call(&mut self, req: (Query<FileOrd>, WorkspaceId)) -> Self::Future {
+    async fn get_all(&self) -> Result<RoaringBitmap, QueryErr> {
+        let files = self.peer.retrieve_all(self.node, self.workspace_id, self.shelf_id, false).await;
+        self.ids(files)
+    }
 
-        Box::pin(async move {
-        });
-        todo!()
+    async fn get_untagged(&self) -> Result<RoaringBitmap, QueryErr> {
+        let files = self.peer.retrieve_all(self.node, self.workspace_id, self.shelf_id, true).await;
+        self.ids(files)
+    }
+
+    async fn sort<T>(&self, files: RoaringBitmap, order: T) -> Result<BTreeSet<OrderedFileID<T>>, QueryErr>
+    where
+        T: FileOrder + Clone + Send + Sync + 'static,
+        OrderedFileID<T>: Ord,
+    {
+        let (_, all) = &*self.files.lock().unwrap();
+        Ok(files
+            .iter()
+            .filter_map(|id| all.get(id as usize))
+            .map(|file| {
+                let metadata = FileMetadata::from(&file.metadata.unwrap_or_default());
+                OrderedFileID::new(FileID::remote(self.node, PathBuf::from(&file.path), metadata), order.clone())
+            })
+            .collect())
     }
 }
+
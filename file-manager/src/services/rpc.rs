@@ -1,43 +1,673 @@
-use crate::rpc::{QueryRequest, QueryResponse, EchoData};
-use crate::services::peer::PeerService;
+use crate::rpc::{QueryRequest, QueryResponse, EchoData, InviteRequest, InviteResponse, RedeemRequest, RedeemResponse, TagRequest, TagResponse, PeerStatusRequest, PeerStatusResponse, PeerInfo, MutationStatusRequest, MutationStatusResponse, ValidateRequest, ValidateResponse, CacheStatsRequest, CacheStatsResponse, CacheUsage, PinRequest, PinResponse, CreateTagRequest, CreateTagResponse, TaskRequest, TaskProgress};
+use crate::content::ContentStats;
+use crate::lru::LruStats;
+use crate::query::{Accessed, Created, Modified, Name, Order, QueryErr, Size};
+use crate::rpc::{self, ErrorCode, FileOrd, WorkspaceRequest, WorkspaceResponse, workspace_request};
+use crate::services::query::Retrieve;
+use crate::services::workspace::{self, TagPath, WorkspaceError, WorkspaceService};
+use crate::workspace::WorkspaceId;
+use iroh::NodeId;
+use crate::services::cache::{CacheError, CacheService, CacheValidity, Caching, HashCache};
+use crate::tag::{TagErr, TagManager, TagScope};
+use crate::services::peer::{PeerError, PeerService, CreateInvite, RedeemInvite, UpdateTag};
+use crate::sync::TagOpKind;
+use std::path::PathBuf;
+use iroh_base::ticket::Ticket;
 use std::future::Future;
 use tokio::task::JoinHandle;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tower::Service;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, OnceLock, RwLock};
+use tokio::sync::mpsc;
+use tokio::time::{interval, sleep, Duration};
+use crate::shelf::node::ScanProgress;
+use crate::shelf::shelf::UpdateErr;
+use crate::workspace::{ShelfId, ShelfLocation};
 use std::fs::File;
 use std::io::Write;
 
 #[derive(Clone)]
 pub struct RpcService {
     pub peer_service: PeerService,
-    pub tasks: Arc<HashMap<TaskID, JoinHandle<()>>>
+    pub cache_service: CacheService,
+    pub workspace_service: WorkspaceService,
+    pub tasks: Arc<RwLock<HashMap<TaskID, Task>>>
 }
 pub type TaskID = u64;
 
+// Operation started by a request and outliving it, such as the scan of a new local
+// shelf. Kept in the task table until a client saw it over.
+pub struct Task {
+    pub handle: JoinHandle<()>,
+    pub progress: Arc<ScanProgress>, // Cancelled through its flag
+    pub outcome: Arc<OnceLock<Result<ShelfId, rpc::Error>>>, // Set once over
+}
+
+const PROGRESS_PERIOD: Duration = Duration::from_millis(200);
+const FINISHED_TASK_TTL: Duration = Duration::from_secs(60);
+
 
 impl Service<QueryRequest> for RpcService {
     type Response = QueryResponse;
-    type Error = ();
+    type Error = rpc::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
     fn call(&mut self, req: QueryRequest) -> Self::Future  {
-        todo!();
+        let mut retrieve = Retrieve::new(self.cache_service.clone(), req.workspace_id as WorkspaceId);
+        let work_srv = self.workspace_service.clone();
+        Box::pin(async move {
+            // Checked first, as tags of unknown workspaces would not resolve either
+            let scope = {
+                let workspaces = work_srv.workspaces.read().await;
+                let workspace = workspaces
+                    .get(&req.workspace_id)
+                    .ok_or(QueryErr::WorkspaceNotFound(req.workspace_id))?;
+                let path = PathBuf::from(&req.scope);
+                match workspace.shelf_of(&path).map(|info| &info.location) {
+                    _ if req.scope.is_empty() => None,
+                    Some(ShelfLocation::Local(shelf)) => Some((shelf.clone(), path)),
+                    _ => return Err(QueryErr::ScopeNotFound(path).into()),
+                }
+            };
+            if let Some((shelf, path)) = scope {
+                let scope = tokio::task::spawn_blocking(move || shelf.scope(&path))
+                    .await
+                    .map_err(|_| QueryErr::Cancelled)?
+                    .map_err(QueryErr::from)?;
+                retrieve = retrieve.scoped(scope);
+            }
+            let order = if req.ascending { Order::Ascending } else { Order::Descending };
+            let files = match req.file_ord() {
+                FileOrd::Name => retrieve.files(&req.query, Name { order }).await,
+                FileOrd::Size => retrieve.files(&req.query, Size { order }).await,
+                FileOrd::Modified => retrieve.files(&req.query, Modified { order }).await,
+                FileOrd::Accessed => retrieve.files(&req.query, Accessed { order }).await,
+                FileOrd::Created => retrieve.files(&req.query, Created { order }).await,
+            }?;
+            Ok(QueryResponse { files })
+        })
+    }
+}
+
+impl Service<InviteRequest> for RpcService {
+    type Response = InviteResponse;
+    type Error = rpc::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: InviteRequest) -> Self::Future {
+        let mut peer_srv = self.peer_service.clone();
+        let work_srv = self.workspace_service.clone();
+        Box::pin(async move {
+            let ttl = (req.ttl_secs != 0).then_some(req.ttl_secs);
+            let workspace_name = work_srv
+                .workspaces
+                .read()
+                .await
+                .get(&req.workspace_id)
+                .map(|workspace| workspace.name.clone())
+                .ok_or(WorkspaceError::WorkspaceNotFound(req.workspace_id))?;
+            let ticket = peer_srv
+                .call(CreateInvite {
+                    workspace_id: req.workspace_id,
+                    workspace_name,
+                    ttl,
+                })
+                .await?;
+            Ok(InviteResponse {
+                ticket: ticket.serialize(),
+                expires: ticket.expires,
+            })
+        })
+    }
+}
+
+impl Service<RedeemRequest> for RpcService {
+    type Response = RedeemResponse;
+    type Error = rpc::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: RedeemRequest) -> Self::Future {
+        let mut peer_srv = self.peer_service.clone();
+        let mut work_srv = self.workspace_service.clone();
+        Box::pin(async move {
+            let ticket = peer_srv
+                .call(RedeemInvite { ticket: req.ticket })
+                .await?;
+            // Same id on both daemons, so tag operations and summaries line up
+            work_srv
+                .call(workspace::WorkspaceRequest::Join {
+                    workspace_id: ticket.workspace_id,
+                    name: ticket.workspace_name,
+                })
+                .await?;
+            Ok(RedeemResponse {
+                node_id: ticket.node.node_id.as_bytes().to_vec(),
+                workspace_id: ticket.workspace_id,
+            })
+        })
+    }
+}
+
+impl Service<TagRequest> for RpcService {
+    type Response = TagResponse;
+    type Error = rpc::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: TagRequest) -> Self::Future {
+        let mut peer_srv = self.peer_service.clone();
+        let mut work_srv = self.workspace_service.clone();
+        Box::pin(async move {
+            let kind: TagOpKind = req.kind().into();
+            let update = if req.shelf_id == 0 {
+                work_srv
+                    .call(TagPath {
+                        client_id: req.client_id,
+                        workspace_id: req.workspace_id,
+                        path: PathBuf::from(req.path),
+                        tag: req.tag,
+                        kind,
+                    })
+                    .await?
+            } else {
+                peer_srv
+                    .call(UpdateTag {
+                        client_id: req.client_id,
+                        owner: None,
+                        workspace_id: req.workspace_id,
+                        shelf_id: req.shelf_id,
+                        path: PathBuf::from(req.path),
+                        tag: req.tag,
+                        kind,
+                    })
+                    .await?
+            };
+            Ok(TagResponse {
+                changed: update.changed,
+                pending: update.pending.is_some(),
+                mutation_id: update.pending.unwrap_or(0),
+            })
+        })
+    }
+}
+
+impl Service<WorkspaceRequest> for RpcService {
+    type Response = WorkspaceResponse;
+    type Error = rpc::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: WorkspaceRequest) -> Self::Future {
+        let mut work_srv = self.workspace_service.clone();
+        let tasks = self.tasks.clone();
+        Box::pin(async move {
+            let op = req
+                .op
+                .ok_or_else(|| rpc::Error::new(ErrorCode::InvalidRequest, "missing workspace operation"))?;
+            let req = match op {
+                workspace_request::Op::Create(name) => workspace::WorkspaceRequest::Create(name),
+                workspace_request::Op::Delete(workspace_id) => workspace::WorkspaceRequest::Delete(workspace_id),
+                // Scanned in the background, followed through the task table
+                workspace_request::Op::AddShelf(add) if add.node.is_empty() => {
+                    let progress = Arc::new(ScanProgress::default());
+                    let req = workspace::WorkspaceRequest::AddLocal {
+                        workspace_id: add.workspace_id,
+                        path: PathBuf::from(add.path),
+                        ignore: add.ignore,
+                        skip_hidden: add.skip_hidden,
+                        loaded_depth: (add.loaded_depth > 0).then_some(add.loaded_depth as usize),
+                        progress: progress.clone(),
+                    };
+                    let outcome = Arc::new(OnceLock::new());
+                    let task_outcome = outcome.clone();
+                    let task_id = rand::random::<u64>().max(1);
+                    let task_table = tasks.clone();
+                    let handle = tokio::spawn(async move {
+                        let res = match work_srv.call(req).await {
+                            Ok(workspace::WorkspaceResponse::ShelfAdded(shelf_id)) => Ok(shelf_id),
+                            Ok(res) => Err(rpc::Error::new(ErrorCode::Internal, format!("unexpected response {:?}", res))),
+                            Err(err) => Err(err.into()),
+                        };
+                        let _ = task_outcome.set(res);
+                        // Kept for the clients following it late, then dropped whether followed or not
+                        sleep(FINISHED_TASK_TTL).await;
+                        task_table.write().unwrap().remove(&task_id);
+                    });
+                    tasks.write().unwrap().insert(task_id, Task { handle, progress, outcome });
+                    return Ok(WorkspaceResponse {
+                        task_id,
+                        ..Default::default()
+                    });
+                }
+                workspace_request::Op::AddShelf(add) => {
+                    let node = <[u8; 32]>::try_from(add.node)
+                        .ok()
+                        .and_then(|node| NodeId::from_bytes(&node).ok())
+                        .ok_or_else(|| rpc::Error::new(ErrorCode::InvalidRequest, "invalid node id"))?;
+                    workspace::WorkspaceRequest::AddRemote {
+                        workspace_id: add.workspace_id,
+                        node,
+                        shelf_id: add.shelf_id,
+                        root_path: PathBuf::from(add.path),
+                    }
+                }
+                workspace_request::Op::RemoveShelf(remove) => workspace::WorkspaceRequest::RemoveShelf {
+                    workspace_id: remove.workspace_id,
+                    shelf_id: remove.shelf_id,
+                },
+                workspace_request::Op::List(_) => workspace::WorkspaceRequest::List,
+                workspace_request::Op::Check(check) => workspace::WorkspaceRequest::Check {
+                    workspace_id: check.workspace_id,
+                    shelf_id: (check.shelf_id != 0).then_some(check.shelf_id),
+                    repair: check.repair,
+                },
+                workspace_request::Op::Refresh(refresh) => workspace::WorkspaceRequest::Refresh {
+                    workspace_id: refresh.workspace_id,
+                    shelf_id: (refresh.shelf_id != 0).then_some(refresh.shelf_id),
+                },
+            };
+            let response = match work_srv.call(req).await? {
+                workspace::WorkspaceResponse::Created(workspace_id) => WorkspaceResponse {
+                    workspace_id,
+                    ..Default::default()
+                },
+                workspace::WorkspaceResponse::ShelfAdded(shelf_id) => WorkspaceResponse {
+                    shelf_id,
+                    ..Default::default()
+                },
+                workspace::WorkspaceResponse::Removed => WorkspaceResponse::default(),
+                workspace::WorkspaceResponse::List(workspaces) => WorkspaceResponse {
+                    workspaces,
+                    ..Default::default()
+                },
+                workspace::WorkspaceResponse::Checked(checks) => WorkspaceResponse {
+                    checks,
+                    ..Default::default()
+                },
+                workspace::WorkspaceResponse::Refreshed(refreshed) => WorkspaceResponse {
+                    refreshed,
+                    ..Default::default()
+                },
+            };
+            Ok(response)
+        })
+    }
+}
+
+impl Service<TaskRequest> for RpcService {
+    type Response = mpsc::Receiver<TaskProgress>;
+    type Error = rpc::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    // Reports are sent whenever the progress changed, the last one once the task is over
+    fn call(&mut self, req: TaskRequest) -> Self::Future {
+        let tasks = self.tasks.clone();
+        Box::pin(async move {
+            let (progress, outcome) = {
+                let tasks = tasks.read().unwrap();
+                let task = tasks
+                    .get(&req.task_id)
+                    .ok_or_else(|| rpc::Error::new(ErrorCode::InvalidRequest, format!("unknown task {}", req.task_id)))?;
+                if req.cancel {
+                    task.progress.cancelled.store(true, Ordering::Relaxed);
+                }
+                (task.progress.clone(), task.outcome.clone())
+            };
+            let (tx, rx) = mpsc::channel(16);
+            tokio::spawn(async move {
+                let mut ticker = interval(PROGRESS_PERIOD);
+                let mut last = None;
+                loop {
+                    ticker.tick().await;
+                    let over = outcome.get().cloned();
+                    let report = TaskProgress {
+                        task_id: req.task_id,
+                        scan: Some(progress.to_rpc()),
+                        done: over.is_some(),
+                        shelf_id: over.as_ref().and_then(|res| res.as_ref().ok()).cloned().unwrap_or(0),
+                        error: over.as_ref().and_then(|res| res.as_ref().err()).cloned(),
+                    };
+                    if last.as_ref() != Some(&report) {
+                        if tx.send(report.clone()).await.is_err() {
+                            return;
+                        }
+                        last = Some(report);
+                    }
+                    if over.is_some() {
+                        return;
+                    }
+                }
+            });
+            Ok(rx)
+        })
+    }
+}
+
+impl Service<PeerStatusRequest> for RpcService {
+    type Response = PeerStatusResponse;
+    type Error = rpc::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: PeerStatusRequest) -> Self::Future {
+        let peer_srv = self.peer_service.clone();
+        Box::pin(async move {
+            let status = peer_srv.status.read().await;
+            let trusted = peer_srv.trusted.read().await;
+            let peers = trusted
+                .iter()
+                .map(|(node_id, workspaces)| {
+                    let status = status.get(node_id);
+                    PeerInfo {
+                        node_id: node_id.as_bytes().to_vec(),
+                        online: status.is_some_and(|s| s.online),
+                        last_seen: status.and_then(|s| s.last_seen).unwrap_or(0),
+                        failures: status.map_or(0, |s| s.failures),
+                        workspaces: workspaces.iter().cloned().collect(),
+                    }
+                })
+                .collect::<Vec<_>>();
+            drop((status, trusted));
+            Ok(PeerStatusResponse {
+                peers,
+                unsaved: peer_srv.unsaved().await,
+            })
+        })
+    }
+}
+
+impl Service<MutationStatusRequest> for RpcService {
+    type Response = MutationStatusResponse;
+    type Error = rpc::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: MutationStatusRequest) -> Self::Future {
+        let peer_srv = self.peer_service.clone();
+        Box::pin(async move {
+            let mut queue = peer_srv.mutations.write().await;
+            let reports = queue
+                .take_reports(req.client_id)
+                .map_err(|_| rpc::Error::new(ErrorCode::Io, "the mutation queue could not be persisted"))?;
+            Ok(MutationStatusResponse {
+                reports,
+                pending: queue.pending_for(req.client_id),
+            })
+        })
+    }
+}
+
+impl Service<ValidateRequest> for RpcService {
+    type Response = ValidateResponse;
+    type Error = rpc::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: ValidateRequest) -> Self::Future {
+        let mut cache_srv = self.cache_service.clone();
+        Box::pin(async move {
+            let tag = match req.tag.as_str() {
+                "" => None,
+                name => Some(
+                    TagManager::retrieve_tag(req.workspace_id, name)
+                        .ok_or_else(|| rpc::Error::from(UpdateErr::UnknownTag(name.to_string())))?,
+                ),
+            };
+            let validity = cache_srv
+                .call(Caching::IsCacheValid(req.workspace_id, tag, HashCache { hash: req.hash }))
+                .await?;
+            Ok(match validity {
+                CacheValidity::Valid => ValidateResponse {
+                    valid: true,
+                    hash: req.hash,
+                    ..Default::default()
+                },
+                CacheValidity::Delta { version, added, removed } => ValidateResponse {
+                    valid: false,
+                    hash: version,
+                    added: added.iter().map(|file| file.into()).collect(),
+                    removed: removed
+                        .iter()
+                        .map(|path| path.to_string_lossy().into_owned())
+                        .collect(),
+                    full_resync: false,
+                },
+                CacheValidity::Invalid { version } => ValidateResponse {
+                    valid: false,
+                    hash: version,
+                    full_resync: true,
+                    ..Default::default()
+                },
+            })
+        })
+    }
+}
+
+impl Service<CacheStatsRequest> for RpcService {
+    type Response = CacheStatsResponse;
+    type Error = rpc::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: CacheStatsRequest) -> Self::Future {
+        let stats = self.cache_service.stats();
+        Box::pin(async move {
+            Ok(CacheStatsResponse {
+                queries: Some(stats.queries.into()),
+                remote: Some(stats.remote.into()),
+                content: Some(stats.content.into()),
+                disk: Some(stats.disk.into()),
+            })
+        })
+    }
+}
+
+impl Service<PinRequest> for RpcService {
+    type Response = PinResponse;
+    type Error = rpc::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: PinRequest) -> Self::Future {
+        let cache_srv = self.cache_service.clone();
+        Box::pin(async move {
+            let cached = cache_srv
+                .pin(req.workspace_id, req.shelf_id, PathBuf::from(req.path), req.pin)
+                .await?;
+            Ok(PinResponse { cached })
+        })
+    }
+}
+
+impl Service<CreateTagRequest> for RpcService {
+    type Response = CreateTagResponse;
+    type Error = rpc::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: CreateTagRequest) -> Self::Future {
+        Box::pin(async move {
+            let parent = match req.parent {
+                0 => None,
+                id => Some(
+                    TagManager::get(id)
+                        .ok_or_else(|| rpc::Error::from(UpdateErr::UnknownTag(format!("#{}", id))))?,
+                ),
+            };
+            let scope = match req.workspaces.is_empty() {
+                true => TagScope::Global,
+                false => TagScope::Workspaces(req.workspaces.into_iter().collect()),
+            };
+            let tag = TagManager::create_tag(&req.name, req.priority, parent, scope)?;
+            Ok(CreateTagResponse { id: tag.id() })
+        })
+    }
+}
+
+impl From<LruStats> for CacheUsage {
+    fn from(stats: LruStats) -> Self {
+        CacheUsage {
+            hits: stats.hits,
+            misses: stats.misses,
+            evictions: stats.evictions,
+            entries: stats.entries,
+            used: stats.used,
+            budget: stats.budget,
+            corrupted: 0,
+        }
+    }
+}
+
+impl From<ContentStats> for CacheUsage {
+    fn from(stats: ContentStats) -> Self {
+        CacheUsage {
+            hits: stats.hits,
+            misses: stats.misses,
+            evictions: stats.evictions,
+            entries: stats.entries,
+            used: stats.used,
+            budget: stats.budget,
+            corrupted: stats.corrupted,
+        }
+    }
+}
+
+impl From<WorkspaceError> for rpc::Error {
+    fn from(err: WorkspaceError) -> Self {
+        match err {
+            WorkspaceError::WorkspaceNotFound(workspace_id) => rpc::Error {
+                workspace_id,
+                ..rpc::Error::new(ErrorCode::WorkspaceNotFound, format!("unknown workspace {}", workspace_id))
+            },
+            WorkspaceError::ShelfNotFound(shelf_id) => {
+                rpc::Error::new(ErrorCode::ShelfNotFound, format!("unknown shelf {}", shelf_id))
+            }
+            WorkspaceError::PathNotFound(path) => rpc::Error {
+                path: path.to_string_lossy().into_owned(),
+                ..rpc::Error::new(ErrorCode::PathNotFound, format!("{} does not exist or is in no shelf of the workspace", path.display()))
+            },
+            WorkspaceError::Overlap(path) => rpc::Error {
+                path: path.to_string_lossy().into_owned(),
+                ..rpc::Error::new(ErrorCode::Overlap, format!("{} is, contains or lies in a local shelf", path.display()))
+            },
+            WorkspaceError::InvalidPattern(pattern) => {
+                rpc::Error::new(ErrorCode::InvalidRequest, format!("invalid ignore pattern \"{}\"", pattern))
+            }
+            WorkspaceError::Cancelled => rpc::Error::new(ErrorCode::Cancelled, "cancelled"),
+            WorkspaceError::Io(err) => {
+                rpc::Error::new(ErrorCode::Io, format!("could not read or persist the workspaces: {}", err))
+            }
+            WorkspaceError::Peer(err) => err.into(),
+        }
+    }
+}
+
+impl From<PeerError> for rpc::Error {
+    fn from(err: PeerError) -> Self {
+        match err {
+            PeerError::InvalidTicket => rpc::Error::new(ErrorCode::InvalidTicket, "malformed invite ticket"),
+            PeerError::Expired => rpc::Error::new(ErrorCode::InvalidTicket, "expired invite ticket"),
+            PeerError::Rejected => rpc::Error::new(ErrorCode::InvalidTicket, "invite rejected by the peer"),
+            PeerError::Untrusted => rpc::Error::new(ErrorCode::Untrusted, "peer not trusted with the workspace"),
+            PeerError::Connection => rpc::Error::new(ErrorCode::Unreachable, "the peer could not be reached"),
+            PeerError::Malformed => rpc::Error::new(ErrorCode::Internal, "malformed response from the peer"),
+            PeerError::Queue => rpc::Error::new(ErrorCode::Io, "the mutation queue could not be persisted"),
+            PeerError::Persist => rpc::Error::new(ErrorCode::Io, "the peer state could not be persisted"),
+            PeerError::NotFound => rpc::Error::new(ErrorCode::FileNotFound, "not found on the peer"),
+            PeerError::Update(err) => err.into(),
+        }
+    }
+}
+
+impl From<CacheError> for rpc::Error {
+    fn from(err: CacheError) -> Self {
+        match err {
+            CacheError::WorkspaceNotFound(workspace_id) => rpc::Error {
+                workspace_id,
+                ..rpc::Error::new(ErrorCode::WorkspaceNotFound, format!("unknown workspace {}", workspace_id))
+            },
+            CacheError::NotFound(path) => rpc::Error {
+                path: path.to_string_lossy().into_owned(),
+                ..rpc::Error::new(ErrorCode::FileNotFound, format!("no file at {} in the shelf", path.display()))
+            },
+            CacheError::NotAFile(path) => rpc::Error {
+                path: path.to_string_lossy().into_owned(),
+                ..rpc::Error::new(ErrorCode::InvalidRequest, format!("{} is not a regular file", path.display()))
+            },
+            CacheError::Offline(node) => rpc::Error {
+                node: node.as_bytes().to_vec(),
+                ..rpc::Error::new(ErrorCode::Unreachable, "not cached, and the peer owning it is unreachable")
+            },
+            CacheError::Peer(node, err) => rpc::Error {
+                node: node.as_bytes().to_vec(),
+                ..err.into()
+            },
+            CacheError::Io(err) => rpc::Error::new(ErrorCode::Io, format!("could not read or write the cache: {}", err)),
+        }
+    }
+}
+
+impl From<TagErr> for rpc::Error {
+    fn from(err: TagErr) -> Self {
+        match err {
+            TagErr::NameClash => {
+                rpc::Error::new(ErrorCode::NameClash, "a tag of that name is visible in one of the workspaces")
+            }
+            TagErr::EmptyScope => rpc::Error::new(ErrorCode::InvalidRequest, "empty tag scope"),
+            TagErr::Io => rpc::Error::new(ErrorCode::Io, "could not persist the tags"),
+        }
     }
 }
 
 impl Service<EchoData> for RpcService {
     type Response = EchoData;
-    type Error = ();
+    type Error = rpc::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
@@ -46,8 +676,7 @@ impl Service<EchoData> for RpcService {
             let data = &req.data[0];
             //let mut file = File::create("output.bin").unwrap();
             //file.write_all(&data).unwrap();
-            let mut res: Vec<Vec<u8>> = Vec::new();
-            res.push(data.clone());
+            let res: Vec<Vec<u8>> = vec![data.clone()];
             println!("sending");
             Ok(
                 EchoData {
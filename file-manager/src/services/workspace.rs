@@ -0,0 +1,661 @@
+use tower::{Service};
+use tokio::sync::RwLock;
+use std::{sync::Arc, future::Future, pin::Pin, task::{Context, Poll}};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use iroh::NodeId;
+use prost::Message;
+use crate::persist::write_atomic;
+use crate::rpc::{self, WorkspaceDefs};
+use crate::services::cache::{CacheEvent, CacheService};
+use crate::services::peer::{Apply, PeerError, PeerService, TagUpdate, UpdateTag};
+use crate::shelf::remote::RemoteShelf;
+use crate::shelf::node::{CheckPolicy, ScanConfig, ScanProgress};
+use crate::shelf::shelf::{LocalShelf, LocalShelfRef, UpdateErr};
+use crate::sync::{TagOp, TagOpKind};
+use crate::workspace::{tags_path, ShelfId, ShelfLocation, Workspace, WorkspaceId};
+
+// Owns the workspaces of the daemon and keeps their local shelves, the cache indices
+// and the published shelf summaries in sync
+#[derive(Clone)]
+pub struct WorkspaceService {
+    pub workspaces: Arc<RwLock<HashMap<WorkspaceId, Workspace>>>,
+    peer_service: PeerService,
+    cache_service: CacheService,
+    scan_config: ScanConfig,
+    path: PathBuf,
+    tags_dir: PathBuf, // Tag state of each local shelf
+    unsaved: Arc<std::sync::Mutex<HashSet<ShelfId>>>, // Local shelves whose tag state failed to persist
+}
+
+pub enum WorkspaceRequest {
+    Create(String),
+    // Bind a workspace shared by a peer under its id, creating it if needed
+    Join {
+        workspace_id: WorkspaceId,
+        name: String,
+    },
+    Delete(WorkspaceId),
+    AddLocal {
+        workspace_id: WorkspaceId,
+        path: PathBuf,
+        ignore: Vec<String>,
+        skip_hidden: bool,
+        loaded_depth: Option<usize>,
+        progress: Arc<ScanProgress>,
+    },
+    AddRemote {
+        workspace_id: WorkspaceId,
+        node: NodeId,
+        shelf_id: ShelfId, // Id of the shelf on its peer
+        root_path: PathBuf,
+    },
+    RemoveShelf {
+        workspace_id: WorkspaceId,
+        shelf_id: ShelfId,
+    },
+    List,
+    Check {
+        workspace_id: WorkspaceId,
+        shelf_id: Option<ShelfId>, // Every local shelf of the workspace if None
+        repair: bool,
+    },
+    Refresh {
+        workspace_id: WorkspaceId,
+        shelf_id: Option<ShelfId>, // Every local shelf of the workspace if None
+    },
+}
+
+#[derive(Debug)]
+pub enum WorkspaceResponse {
+    Created(WorkspaceId),
+    ShelfAdded(ShelfId),
+    Removed,
+    List(Vec<rpc::WorkspaceDef>),
+    Checked(Vec<rpc::ShelfCheck>),
+    Refreshed(Vec<rpc::ShelfRefresh>),
+}
+
+// Tag operation on a file designated by its absolute path, dispatched to the shelf of
+// the workspace covering it
+pub struct TagPath {
+    pub client_id: u64,
+    pub workspace_id: WorkspaceId,
+    pub path: PathBuf,
+    pub tag: String,
+    pub kind: TagOpKind,
+}
+
+#[derive(Debug)]
+pub enum WorkspaceError {
+    WorkspaceNotFound(WorkspaceId),
+    ShelfNotFound(ShelfId),
+    PathNotFound(PathBuf), // Missing, or no shelf of the workspace covers it
+    Overlap(PathBuf), // The path is, contains or lies in a local shelf of some workspace
+    InvalidPattern(String), // Ignore pattern that could not be parsed
+    Cancelled,
+    Io(io::Error),
+    Peer(PeerError),
+}
+
+impl From<io::Error> for WorkspaceError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::Interrupted => WorkspaceError::Cancelled,
+            _ => WorkspaceError::Io(err),
+        }
+    }
+}
+
+impl From<tokio::task::JoinError> for WorkspaceError {
+    fn from(err: tokio::task::JoinError) -> Self {
+        WorkspaceError::Io(io::Error::other(err))
+    }
+}
+
+type LocalEntry = (ShelfId, PathBuf, LocalShelfRef); // Id and root path of a local shelf
+
+fn local_shelves(workspace: &Workspace, shelf_id: Option<ShelfId>) -> Vec<LocalEntry> {
+    workspace
+        .shelves()
+        .iter()
+        .filter(|info| shelf_id.is_none_or(|id| id == info.id))
+        .filter_map(|info| match &info.location {
+            ShelfLocation::Local(shelf) => Some((info.id, info.root_path.clone(), shelf.clone())),
+            ShelfLocation::Remote(_) => None,
+        })
+        .collect()
+}
+
+fn new_id() -> u64 {
+    rand::random::<u64>().max(1) // 0 stands for "unset" in requests
+}
+
+impl WorkspaceService {
+    // Load the persisted workspaces, scanning their local shelves
+    pub async fn load(peer_service: PeerService, cache_service: CacheService, scan_config: ScanConfig, data_dir: &Path) -> io::Result<Self> {
+        let path = data_dir.join("workspaces.pb");
+        let tags_dir = data_dir.join("shelves");
+        std::fs::create_dir_all(&tags_dir)?;
+        let defs = match std::fs::read(&path) {
+            Ok(buf) => WorkspaceDefs::decode(&*buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => WorkspaceDefs::default(),
+            Err(err) => return Err(err),
+        };
+        let peer = peer_service.clone();
+        let config = scan_config.clone();
+        let dir = tags_dir.clone();
+        let loaded = tokio::task::spawn_blocking(move || {
+            let mut roots = Vec::new();
+            defs.workspaces
+                .into_iter()
+                .map(|def| Workspace::from_rpc(def, &peer, &config, &dir, &mut roots))
+                .collect::<Vec<_>>()
+        })
+        .await
+        .map_err(io::Error::other)?;
+
+        let service = WorkspaceService {
+            workspaces: Arc::new(RwLock::new(HashMap::new())),
+            peer_service,
+            cache_service,
+            scan_config,
+            path,
+            tags_dir,
+            unsaved: Arc::new(std::sync::Mutex::new(HashSet::new())),
+        };
+        for (workspace, failed) in loaded {
+            for (root, err) in failed {
+                println!("Could not load shelf {}: {:?}", root.display(), err);
+            }
+            let shelves = local_shelves(&workspace, None);
+            if service.scan_config.check != CheckPolicy::Off {
+                let repair = service.scan_config.check == CheckPolicy::Repair;
+                for check in service.check(workspace.id, shelves.clone(), repair).await {
+                    for v in &check.violations {
+                        println!(
+                            "Shelf {} violates {} at {} (tag {:?}): expected {}, found {}",
+                            check.shelf_id, v.invariant, v.path, v.tag, v.expected, v.found
+                        );
+                    }
+                }
+            }
+            for (shelf_id, root_path, shelf) in shelves {
+                service.shelf_added(workspace.id, shelf_id, root_path, shelf).await;
+            }
+            service.workspaces.write().await.insert(workspace.id, workspace);
+        }
+        Ok(service)
+    }
+
+    async fn persist(&self) -> io::Result<()> {
+        let defs = WorkspaceDefs {
+            workspaces: self.workspaces.read().await.values().map(Workspace::to_rpc).collect(),
+        };
+        write_atomic(&self.path, &defs.encode_to_vec())
+    }
+
+    // Shelves whose last write failed are reported by List until the next one succeeds
+    async fn persist_tags(&self, shelf_id: ShelfId, shelf: LocalShelfRef) -> io::Result<()> {
+        let path = tags_path(&self.tags_dir, shelf_id);
+        let res = tokio::task::spawn_blocking(move || shelf.persist_tags(&path))
+            .await
+            .map_err(io::Error::other)
+            .and_then(|res| res);
+        let mut unsaved = self.unsaved.lock().unwrap();
+        match res.is_err() {
+            true => unsaved.insert(shelf_id),
+            false => unsaved.remove(&shelf_id),
+        };
+        res
+    }
+
+    // Whether path overlaps a local shelf of any workspace
+    async fn overlap(&self, path: &Path) -> bool {
+        self.workspaces
+            .read()
+            .await
+            .values()
+            .any(|workspace| workspace.overlap(path).is_some())
+    }
+
+    // Local shelves of the workspace (or the one given), so they can be worked on
+    // without holding the lock
+    async fn local_shelves(&self, workspace_id: WorkspaceId, shelf_id: Option<ShelfId>) -> Result<Vec<LocalEntry>, WorkspaceError> {
+        let workspaces = self.workspaces.read().await;
+        let workspace = workspaces
+            .get(&workspace_id)
+            .ok_or(WorkspaceError::WorkspaceNotFound(workspace_id))?;
+        if let Some(id) = shelf_id.filter(|id| workspace.shelf(*id).is_none()) {
+            return Err(WorkspaceError::ShelfNotFound(id));
+        }
+        Ok(local_shelves(workspace, shelf_id))
+    }
+
+    // Index the files of a new local shelf and announce it to the peers
+    async fn shelf_added(&self, workspace_id: WorkspaceId, shelf_id: ShelfId, root_path: PathBuf, shelf: LocalShelfRef) {
+        let indexed = shelf.clone();
+        let Ok((entries, summary)) = tokio::task::spawn_blocking(move || {
+            (indexed.entries(&root_path).unwrap_or_default(), indexed.summary(workspace_id))
+        })
+        .await
+        else {
+            return;
+        };
+        self.cache_service.update(workspace_id, CacheEvent::Update(entries));
+        self.peer_service
+            .publish_summary(workspace_id, shelf_id, &shelf, summary)
+            .await;
+    }
+
+    // Check the given shelves of the workspace, re-indexing and republishing the ones
+    // repaired
+    async fn check(&self, workspace_id: WorkspaceId, shelves: Vec<LocalEntry>, repair: bool) -> Vec<rpc::ShelfCheck> {
+        let mut checks = Vec::new();
+        for (id, root_path, shelf) in shelves {
+            let checked = shelf.clone();
+            let violations = tokio::task::spawn_blocking(move || checked.check(repair))
+                .await
+                .unwrap_or_default();
+            let repaired = repair && !violations.is_empty();
+            if repaired {
+                self.shelf_added(workspace_id, id, root_path, shelf).await;
+            }
+            checks.push(rpc::ShelfCheck {
+                shelf_id: id,
+                violations: violations.iter().map(rpc::Violation::from).collect(),
+                repaired,
+            });
+        }
+        checks
+    }
+
+    // Rescan the local shelves of the workspace (or the one given), passing what changed
+    // on to the cache index and the peers
+    async fn refresh(&self, workspace_id: WorkspaceId, shelf_id: Option<ShelfId>) -> Result<Vec<rpc::ShelfRefresh>, WorkspaceError> {
+        let shelves = self.local_shelves(workspace_id, shelf_id).await?;
+        let mut refreshed = Vec::new();
+        for (id, _, shelf) in shelves {
+            let refresh_shelf = shelf.clone();
+            let (delta, summary) = tokio::task::spawn_blocking(move || {
+                refresh_shelf.refresh().map(|delta| (delta, refresh_shelf.summary(workspace_id)))
+            })
+            .await??;
+            refreshed.push(rpc::ShelfRefresh {
+                shelf_id: id,
+                removed: delta.removed.len() as u64,
+                updated: delta.updated.len() as u64,
+            });
+            if delta.is_empty() {
+                continue;
+            }
+            // Tags of the files gone are dropped
+            self.persist_tags(id, shelf.clone()).await?;
+            if !delta.removed.is_empty() {
+                self.cache_service.update(workspace_id, CacheEvent::Remove(delta.removed));
+            }
+            if !delta.updated.is_empty() {
+                self.cache_service.update(workspace_id, CacheEvent::Update(delta.updated));
+            }
+            self.peer_service
+                .publish_summary(workspace_id, id, &shelf, summary)
+                .await;
+        }
+        Ok(refreshed)
+    }
+
+    async fn shelf_removed(&self, workspace_id: WorkspaceId, location: ShelfLocation, root_path: &Path, shelf_id: ShelfId) {
+        if let ShelfLocation::Local(shelf) = location {
+            let root_path = root_path.to_path_buf();
+            let paths = tokio::task::spawn_blocking(move || {
+                shelf
+                    .entries(&root_path)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(file, _)| file.path().clone())
+                    .collect()
+            })
+            .await
+            .unwrap_or_default();
+            self.cache_service.update(workspace_id, CacheEvent::Remove(paths));
+            if let Some(shelves) = self.peer_service.summaries.write().await.get_mut(&workspace_id) {
+                shelves.remove(&shelf_id);
+            }
+            self.peer_service.shelves.write().await.remove(&shelf_id);
+            self.unsaved.lock().unwrap().remove(&shelf_id);
+            let _ = tokio::fs::remove_file(tags_path(&self.tags_dir, shelf_id)).await;
+        }
+    }
+
+    // Apply the tag operations to the local shelf they target, in order, then refresh the
+    // cache and the summary of that shelf. Replicated ops are already recorded in the tag
+    // log, requests only once their shelf accepted them.
+    pub async fn run_applier(self) {
+        let Some(mut ops) = self.peer_service.take_applier() else {
+            return;
+        };
+        while let Some(op) = ops.recv().await {
+            match op {
+                Apply::Merged(op) => {
+                    // Superseded while queued: its successor is on its way
+                    if self.peer_service.tag_log.read().await.is_winner(&op) {
+                        self.apply(&op).await;
+                    }
+                }
+                Apply::Request(op, reply) => {
+                    let _ = reply.send(self.apply_request(op).await);
+                }
+            }
+        }
+    }
+
+    async fn apply_request(&self, op: TagOp) -> Result<bool, UpdateErr> {
+        if self.peer_service.tag_log.read().await.superseded(&op) {
+            return Err(UpdateErr::Conflict {
+                path: op.path,
+                tag: op.tag,
+            });
+        }
+        let res = self
+            .apply(&op)
+            .await
+            .unwrap_or_else(|| Err(UpdateErr::PathNotFound(op.path.clone())));
+        match &res {
+            Ok(_) => {}
+            // Applied, only the tags of the shelf could not be written
+            Err(UpdateErr::Io(path, _)) if *path == tags_path(&self.tags_dir, op.shelf_id) => {}
+            Err(_) => return res,
+        }
+        self.peer_service.record(op).await?;
+        res
+    }
+
+    // Unload the idle directories of the lazy local shelves. The files of the directories
+    // loaded since the last pass are indexed again, now that their metadata is known;
+    // the unloaded ones stay indexed, untagged as they are.
+    pub async fn run_evictor(self) {
+        let mut interval = tokio::time::interval(self.scan_config.evict_after.max(Duration::from_secs(1)) / 2);
+        loop {
+            interval.tick().await;
+            let shelves: Vec<_> = self
+                .workspaces
+                .read()
+                .await
+                .iter()
+                .flat_map(|(workspace_id, workspace)| workspace.shelves().iter().map(move |info| (*workspace_id, info)))
+                .filter_map(|(workspace_id, info)| match &info.location {
+                    ShelfLocation::Local(shelf) => Some((workspace_id, shelf.clone())),
+                    ShelfLocation::Remote(_) => None,
+                })
+                .collect();
+            for (workspace_id, shelf) in shelves {
+                let cache_service = self.cache_service.clone();
+                let _ = tokio::task::spawn_blocking(move || {
+                    let loaded = shelf.take_loaded();
+                    if !loaded.is_empty() {
+                        cache_service.update(workspace_id, CacheEvent::Update(loaded));
+                    }
+                    shelf.evict()
+                })
+                .await;
+            }
+        }
+    }
+
+    // The shelf is cloned out of the workspaces first, so that they stay unlocked while
+    // the operation runs. None for ops on shelves that are not local here.
+    async fn apply(&self, op: &TagOp) -> Option<Result<bool, UpdateErr>> {
+        let (shelf, root_path, applied) = {
+            let workspaces = self.workspaces.read().await;
+            let workspace = workspaces.get(&op.workspace_id)?;
+            let info = workspace.shelf(op.shelf_id)?;
+            let ShelfLocation::Local(shelf) = &info.location else {
+                return None;
+            };
+            (shelf.clone(), info.root_path.clone(), workspace.apply(op))
+        };
+        match applied.await {
+            Ok(true) => {}
+            Ok(false) => return Some(Ok(false)),
+            Err(err) => {
+                println!("Could not apply {:?}: {}", op, err);
+                return Some(Err(err));
+            }
+        }
+        let (workspace_id, updated) = (op.workspace_id, shelf.clone());
+        let path = root_path.join(&op.path);
+        if let Ok((entries, summary)) =
+            tokio::task::spawn_blocking(move || (updated.entries(&path), updated.summary(workspace_id))).await
+        {
+            if let Ok(entries) = entries {
+                self.cache_service.update(op.workspace_id, CacheEvent::Update(entries));
+            }
+            self.peer_service
+                .publish_summary(op.workspace_id, op.shelf_id, &shelf, summary)
+                .await;
+        }
+        // The shelf changed even so: the tags are written again along with its next change
+        if let Err(err) = self.persist_tags(op.shelf_id, shelf).await {
+            println!("Failed to persist the tags of shelf {}", op.shelf_id);
+            return Some(Err(UpdateErr::Io(tags_path(&self.tags_dir, op.shelf_id), err.kind())));
+        }
+        Some(Ok(true))
+    }
+}
+
+impl Service<WorkspaceRequest> for WorkspaceService {
+    type Response = WorkspaceResponse;
+    type Error = WorkspaceError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: WorkspaceRequest) -> Self::Future {
+        let work_srv = self.clone();
+        Box::pin(async move {
+            let response = match req {
+                WorkspaceRequest::Create(name) => {
+                    let id = new_id();
+                    work_srv.workspaces.write().await.insert(id, Workspace::new(id, name));
+                    WorkspaceResponse::Created(id)
+                }
+                WorkspaceRequest::Join { workspace_id, name } => {
+                    work_srv
+                        .workspaces
+                        .write()
+                        .await
+                        .entry(workspace_id)
+                        .or_insert_with(|| Workspace::new(workspace_id, name));
+                    WorkspaceResponse::Created(workspace_id)
+                }
+                WorkspaceRequest::Delete(workspace_id) => {
+                    let workspace = work_srv
+                        .workspaces
+                        .write()
+                        .await
+                        .remove(&workspace_id)
+                        .ok_or(WorkspaceError::WorkspaceNotFound(workspace_id))?;
+                    let mut workspace = workspace;
+                    for shelf_id in workspace.shelves().iter().map(|s| s.id).collect::<Vec<_>>() {
+                        if let Some(info) = workspace.remove_shelf(shelf_id) {
+                            work_srv
+                                .shelf_removed(workspace_id, info.location, &info.root_path, shelf_id)
+                                .await;
+                        }
+                    }
+                    WorkspaceResponse::Removed
+                }
+                WorkspaceRequest::AddLocal {
+                    workspace_id,
+                    path,
+                    ignore,
+                    skip_hidden,
+                    loaded_depth,
+                    progress,
+                } => {
+                    if !work_srv.workspaces.read().await.contains_key(&workspace_id) {
+                        return Err(WorkspaceError::WorkspaceNotFound(workspace_id));
+                    }
+                    let config = ScanConfig {
+                        ignore,
+                        skip_hidden,
+                        loaded_depth,
+                        ..work_srv.scan_config.clone()
+                    };
+                    if let Some(pattern) = config.invalid_ignore() {
+                        return Err(WorkspaceError::InvalidPattern(pattern.clone()));
+                    }
+                    let path = match std::fs::canonicalize(&path) {
+                        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                            return Err(WorkspaceError::PathNotFound(path));
+                        }
+                        res => res?,
+                    };
+                    if work_srv.overlap(&path).await {
+                        return Err(WorkspaceError::Overlap(path));
+                    }
+                    let scan_path = path.clone();
+                    let shelf = tokio::task::spawn_blocking(move || LocalShelf::scan(scan_path, config, progress))
+                        .await??;
+                    let shelf_id = new_id();
+                    let shelf = {
+                        let mut workspaces = work_srv.workspaces.write().await;
+                        // Checked again, another shelf may have been added during the scan
+                        if workspaces.values().any(|w| w.overlap(&path).is_some()) {
+                            return Err(WorkspaceError::Overlap(path));
+                        }
+                        workspaces
+                            .get_mut(&workspace_id)
+                            .ok_or(WorkspaceError::WorkspaceNotFound(workspace_id))?
+                            .add_local(shelf_id, path.clone(), shelf)
+                    };
+                    work_srv.shelf_added(workspace_id, shelf_id, path, shelf).await;
+                    WorkspaceResponse::ShelfAdded(shelf_id)
+                }
+                WorkspaceRequest::AddRemote {
+                    workspace_id,
+                    node,
+                    shelf_id,
+                    root_path,
+                } => {
+                    let mut workspaces = work_srv.workspaces.write().await;
+                    let workspace = workspaces
+                        .get_mut(&workspace_id)
+                        .ok_or(WorkspaceError::WorkspaceNotFound(workspace_id))?;
+                    let shelf = RemoteShelf::new(work_srv.peer_service.clone(), node, workspace_id, shelf_id);
+                    workspace.add_remote(shelf_id, root_path, shelf);
+                    WorkspaceResponse::ShelfAdded(shelf_id)
+                }
+                WorkspaceRequest::RemoveShelf {
+                    workspace_id,
+                    shelf_id,
+                } => {
+                    let info = work_srv
+                        .workspaces
+                        .write()
+                        .await
+                        .get_mut(&workspace_id)
+                        .ok_or(WorkspaceError::WorkspaceNotFound(workspace_id))?
+                        .remove_shelf(shelf_id)
+                        .ok_or(WorkspaceError::ShelfNotFound(shelf_id))?;
+                    work_srv
+                        .shelf_removed(workspace_id, info.location, &info.root_path, shelf_id)
+                        .await;
+                    WorkspaceResponse::Removed
+                }
+                WorkspaceRequest::List => {
+                    let (mut defs, shelves) = {
+                        let workspaces = work_srv.workspaces.read().await;
+                        let shelves: Vec<_> = workspaces
+                            .values()
+                            .flat_map(|workspace| {
+                                local_shelves(workspace, None)
+                                    .into_iter()
+                                    .map(|(id, _, shelf)| (workspace.id, id, shelf))
+                            })
+                            .collect();
+                        (workspaces.values().map(Workspace::to_rpc).collect::<Vec<_>>(), shelves)
+                    };
+                    // Counted off the async threads, walking the shelves may take a while
+                    let untagged: HashMap<ShelfId, u64> = tokio::task::spawn_blocking(move || {
+                        shelves
+                            .into_iter()
+                            .map(|(workspace_id, id, shelf)| (id, shelf.summary(workspace_id).untagged))
+                            .collect()
+                    })
+                    .await?;
+                    let unsaved = work_srv.unsaved.lock().unwrap().clone();
+                    for shelf in defs.iter_mut().flat_map(|def| def.shelves.iter_mut()) {
+                        shelf.unsaved = unsaved.contains(&shelf.id);
+                        shelf.untagged = untagged.get(&shelf.id).copied().unwrap_or_default();
+                    }
+                    return Ok(WorkspaceResponse::List(defs));
+                }
+                WorkspaceRequest::Check {
+                    workspace_id,
+                    shelf_id,
+                    repair,
+                } => {
+                    let shelves = work_srv.local_shelves(workspace_id, shelf_id).await?;
+                    return Ok(WorkspaceResponse::Checked(work_srv.check(workspace_id, shelves, repair).await));
+                }
+                WorkspaceRequest::Refresh {
+                    workspace_id,
+                    shelf_id,
+                } => {
+                    return Ok(WorkspaceResponse::Refreshed(work_srv.refresh(workspace_id, shelf_id).await?));
+                }
+            };
+            work_srv.persist().await?;
+            Ok(response)
+        })
+    }
+}
+
+impl Service<TagPath> for WorkspaceService {
+    type Response = TagUpdate;
+    type Error = WorkspaceError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: TagPath) -> Self::Future {
+        let work_srv = self.clone();
+        Box::pin(async move {
+            let (shelf_id, owner, path) = {
+                let workspaces = work_srv.workspaces.read().await;
+                let workspace = workspaces
+                    .get(&req.workspace_id)
+                    .ok_or(WorkspaceError::WorkspaceNotFound(req.workspace_id))?;
+                let not_covered = || WorkspaceError::PathNotFound(req.path.clone());
+                let shelf = workspace.shelf_of(&req.path).ok_or_else(not_covered)?;
+                let owner = match &shelf.location {
+                    ShelfLocation::Remote(remote) => Some(remote.node()),
+                    ShelfLocation::Local(_) => None,
+                };
+                (shelf.id, owner, shelf.relative(&req.path).ok_or_else(not_covered)?)
+            };
+            // Local shelves are updated by the applier, remote ones by their peer
+            work_srv
+                .peer_service
+                .clone()
+                .call(UpdateTag {
+                    client_id: req.client_id,
+                    owner,
+                    workspace_id: req.workspace_id,
+                    shelf_id,
+                    path,
+                    tag: req.tag,
+                    kind: req.kind,
+                })
+                .await
+                .map_err(WorkspaceError::Peer)
+        })
+    }
+}
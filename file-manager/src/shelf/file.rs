@@ -1,3 +1,4 @@
+use crate::rpc;
 use crate::tag::TagRef;
 use chrono::{DateTime, Utc};
 use std::cmp::Ordering;
@@ -7,24 +8,24 @@ use std::os::unix::fs::MetadataExt;
 #[cfg(windows)]
 use std::os::windows::fs::MetadataExt;
 use std::path::PathBuf;
-use std::rc::Rc;
-use std::sync::RwLock;
+use std::sync::Arc;
+use std::sync::{OnceLock, RwLock};
 
 #[derive(Debug, Clone)]
 pub struct FileRef {
-    pub file_ref: Rc<RwLock<File>>,
+    pub id: u32, // Index in the file table of the shelf tree
+    pub file_ref: Arc<RwLock<File>>,
 }
 
 #[derive(Debug)]
 pub struct File {
     path: PathBuf,
     hash: u64,
-    metadata: FileMetadata,
-    tags: BTreeSet<TagRef>,
-    dtags: BTreeSet<TagRef>,
+    metadata: OnceLock<FileMetadata>, // Read on first use when not known at scan time
+    tags: BTreeSet<TagRef>, // dtags are inherited from the directories above the file
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct FileMetadata {
     pub size: u64,
     pub readonly: bool,
@@ -35,15 +36,15 @@ pub struct FileMetadata {
     pub windows: Option<WindowsMetadata>,
 }
 
-#[derive(Debug, Clone)]
-struct UnixMetadata {
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnixMetadata {
     permissions: u32,
     uid: u32,
     gid: u32,
 }
 
-#[derive(Debug, Clone)]
-struct WindowsMetadata {
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowsMetadata {
     attributes: u32,
 }
 
@@ -90,6 +91,49 @@ impl FileMetadata {
     }
 }
 
+// Metadata of a remote file, as reported by its peer
+impl From<&rpc::FileMetadata> for FileMetadata {
+    fn from(meta: &rpc::FileMetadata) -> Self {
+        let time = |secs: u64| DateTime::<Utc>::from_timestamp(secs as i64, 0).filter(|_| secs != 0);
+        FileMetadata {
+            size: meta.size,
+            readonly: meta.readonly,
+            modified: time(meta.modified),
+            accessed: time(meta.accessed),
+            created: time(meta.created),
+            unix: meta.unix.as_ref().map(|unix| UnixMetadata {
+                permissions: unix.permissions,
+                uid: unix.uid,
+                gid: unix.gid,
+            }),
+            windows: meta.windows.as_ref().map(|windows| WindowsMetadata {
+                attributes: windows.attributes,
+            }),
+        }
+    }
+}
+
+impl From<&FileMetadata> for rpc::FileMetadata {
+    fn from(meta: &FileMetadata) -> Self {
+        let secs = |t: Option<DateTime<Utc>>| t.map_or(0, |t| t.timestamp().max(0) as u64);
+        rpc::FileMetadata {
+            size: meta.size,
+            readonly: meta.readonly,
+            modified: secs(meta.modified),
+            accessed: secs(meta.accessed),
+            created: secs(meta.created),
+            unix: meta.unix.as_ref().map(|unix| rpc::UnixMetadata {
+                permissions: unix.permissions,
+                uid: unix.uid,
+                gid: unix.gid,
+            }),
+            windows: meta.windows.as_ref().map(|windows| rpc::WindowsMetadata {
+                attributes: windows.attributes,
+            }),
+        }
+    }
+}
+
 impl PartialEq for FileRef {
     fn eq(&self, other: &Self) -> bool {
         self.file_ref.read().unwrap().path == other.file_ref.read().unwrap().path
@@ -100,25 +144,19 @@ impl Eq for FileRef {}
 
 impl PartialOrd for FileRef {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(
-            self.file_ref
-                .read()
-                .unwrap()
-                .metadata
-                .modified
-                .cmp(&other.file_ref.read().unwrap().metadata.modified),
-        )
+        Some(self.cmp(other))
     }
 }
 
+// By modification time, then path so that files modified at the same time are not
+// merged in sets
 impl Ord for FileRef {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.file_ref
-            .read()
-            .unwrap()
-            .metadata
-            .modified
-            .cmp(&other.file_ref.read().unwrap().metadata.modified)
+        if Arc::ptr_eq(&self.file_ref, &other.file_ref) {
+            return Ordering::Equal;
+        }
+        let (file, other) = (self.file_ref.read().unwrap(), other.file_ref.read().unwrap());
+        (file.metadata().modified, &file.path).cmp(&(other.metadata().modified, &other.path))
     }
 }
 
@@ -126,31 +164,33 @@ impl File {
     pub fn new(
         path: PathBuf,
         tags: BTreeSet<TagRef>,
-        dtags: BTreeSet<TagRef>,
-        metadata: FileMetadata,
+        metadata: Option<FileMetadata>,
     ) -> Self {
         File {
             path,
             hash: 0,
-            metadata,
+            metadata: metadata.map(OnceLock::from).unwrap_or_default(),
             tags,
-            dtags,
         }
     }
 
-    pub fn attach(&mut self, tag: TagRef) -> bool {
-        self.tags.insert(tag)
+    pub fn path(&self) -> &PathBuf {
+        &self.path
     }
 
-    pub fn detach(&mut self, tag: TagRef) -> bool {
-        self.tags.remove(&tag)
+    pub fn metadata(&self) -> &FileMetadata {
+        self.metadata.get_or_init(|| FileMetadata::new(&self.path))
     }
 
-    pub fn attach_dtag(&mut self, tag: TagRef) -> bool {
-        self.dtags.insert(tag)
+    pub fn tags(&self) -> &BTreeSet<TagRef> {
+        &self.tags
     }
 
-    pub fn detach_dtag(&mut self, tag: TagRef) -> bool {
-        self.dtags.remove(&tag)
+    pub fn attach(&mut self, tag: TagRef) -> bool {
+        self.tags.insert(tag)
+    }
+
+    pub fn detach(&mut self, tag: TagRef) -> bool {
+        self.tags.remove(&tag)
     }
 }
@@ -1,3 +1,5 @@
 pub mod file;
-mod node;
+pub mod node;
+pub mod remote;
 pub mod shelf;
+pub mod summary;
@@ -1,59 +1,945 @@
+use crate::rpc;
 use crate::shelf::file::{File, FileMetadata, FileRef};
 use crate::tag::TagRef;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use rayon::prelude::*;
+use roaring::RoaringBitmap;
 use std::collections::{BTreeSet, HashMap, HashSet};
 use std::io;
-use std::path::PathBuf;
-use std::rc::Rc;
-use std::sync::RwLock;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+// Nodes are locked one at a time, parents before children, so readers only ever wait
+// on the node being updated
+pub type NodeRef = Arc<RwLock<Node>>;
+
+// How symbolic links met while scanning are handled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    Skip,
+    #[default]
+    Follow, // Links back to a directory being scanned are reported, not followed
+    Record, // Kept as links, their target is not scanned
+}
+
+// Consistency check of the shelves loaded at startup
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CheckPolicy {
+    Off,
+    #[default]
+    Report,
+    Repair, // Report, then rebuild the aggregates found wrong
+}
+
+// Overridable through EBI_SYMLINKS (skip, follow or record), EBI_CROSS_DEVICES,
+// EBI_EVICT_AFTER (seconds) and EBI_CHECK (off, report or repair). Ignore patterns, the hidden files policy and the loaded
+// depth are set per shelf.
+#[derive(Debug, Clone, Default)]
+pub struct ScanConfig {
+    pub symlinks: SymlinkPolicy,
+    pub cross_devices: bool, // Descend into directories on another file system
+    pub ignore: Vec<String>, // Same syntax as .ebiignore files, relative to the shelf root
+    pub skip_hidden: bool, // Leave out entries whose name starts with a dot
+    // Levels of directories loaded up front, the root being the first. Deeper ones are
+    // loaded on first access, and their file metadata on first use. None loads it all.
+    pub loaded_depth: Option<usize>,
+    pub evict_after: Duration, // Idle time after which lazily loaded directories are unloaded
+    pub check: CheckPolicy,
+}
+
+impl ScanConfig {
+    pub fn from_env() -> Self {
+        let symlinks = match std::env::var("EBI_SYMLINKS").as_deref() {
+            Ok("skip") => SymlinkPolicy::Skip,
+            Ok("record") => SymlinkPolicy::Record,
+            _ => SymlinkPolicy::Follow,
+        };
+        let check = match std::env::var("EBI_CHECK").as_deref() {
+            Ok("off") => CheckPolicy::Off,
+            Ok("repair") => CheckPolicy::Repair,
+            _ => CheckPolicy::Report,
+        };
+        ScanConfig {
+            symlinks,
+            check,
+            cross_devices: std::env::var("EBI_CROSS_DEVICES").is_ok_and(|v| v == "1"),
+            evict_after: Duration::from_secs(
+                std::env::var("EBI_EVICT_AFTER")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(600),
+            ),
+            ..Default::default()
+        }
+    }
+
+    // First ignore pattern that could not be parsed, if any
+    pub fn invalid_ignore(&self) -> Option<&String> {
+        let mut builder = GitignoreBuilder::new("");
+        self.ignore.iter().find(|pattern| builder.add_line(None, pattern).is_err())
+    }
+}
+
+// Entry left out of the tree
+#[derive(Debug, Clone)]
+pub enum ScanError {
+    Inaccessible(PathBuf, io::ErrorKind),
+    Cycle(PathBuf), // Link to a directory above it
+    InvalidRules(PathBuf), // .ebiignore file with lines that could not be parsed
+}
+
+impl ScanError {
+    pub fn path(&self) -> &Path {
+        match self {
+            ScanError::Inaccessible(path, _) | ScanError::Cycle(path) | ScanError::InvalidRules(path) => path,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn device(meta: &std::fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.dev())
+}
+
+#[cfg(not(unix))]
+fn device(_meta: &std::fs::Metadata) -> Option<u64> {
+    None
+}
+
+const IGNORE_FILE: &str = ".ebiignore";
+
+// Ignore rules in effect in a directory: the patterns of the shelf, then the .ebiignore
+// files from the root down. As with gitignore, the deepest matching rule wins.
+#[derive(Clone)]
+struct Rules {
+    matchers: Vec<Arc<Gitignore>>,
+    skip_hidden: bool,
+}
+
+impl Rules {
+    fn new(root: &Path, config: &ScanConfig, errors: &mut Vec<ScanError>) -> Self {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in &config.ignore {
+            // Patterns were checked when the shelf was added
+            let _ = builder.add_line(None, pattern);
+        }
+        let rules = Rules {
+            matchers: vec![Arc::new(builder.build().unwrap_or_else(|_| Gitignore::empty()))],
+            skip_hidden: config.skip_hidden,
+        };
+        rules.enter(root, errors)
+    }
+
+    // Rules below dir, adding its .ebiignore file if any
+    fn enter(&self, dir: &Path, errors: &mut Vec<ScanError>) -> Self {
+        let file = dir.join(IGNORE_FILE);
+        if !file.is_file() {
+            return self.clone();
+        }
+        let (matcher, err) = Gitignore::new(&file);
+        if err.is_some() {
+            errors.push(ScanError::InvalidRules(file));
+        }
+        let mut rules = self.clone();
+        rules.matchers.push(Arc::new(matcher));
+        rules
+    }
+
+    fn ignores(&self, path: &Path, is_dir: bool) -> bool {
+        let hidden = path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if self.skip_hidden && hidden {
+            return true;
+        }
+        for matcher in self.matchers.iter().rev() {
+            match matcher.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+}
+
+// Whether path, below root, is left out of the shelf by its config or .ebiignore files
+pub fn ignored(root: &Path, config: &ScanConfig, path: &Path) -> bool {
+    let Ok(rel_path) = path.strip_prefix(root) else {
+        return false;
+    };
+    let mut errors = Vec::new();
+    let mut rules = Rules::new(root, config, &mut errors);
+    let mut current = root.to_path_buf();
+    let mut components = rel_path.components().peekable();
+    while let Some(component) = components.next() {
+        current.push(component);
+        let last = components.peek().is_none();
+        if rules.ignores(&current, !last || current.is_dir()) {
+            return true;
+        }
+        if !last {
+            rules = rules.enter(&current, &mut errors);
+        }
+    }
+    false
+}
+
+// Counters of a scan, followed by the clients that started it
+#[derive(Debug, Default)]
+pub struct ScanProgress {
+    pub directories: AtomicU64,
+    pub files: AtomicU64,
+    pub errors: AtomicU64,
+    pub cancelled: AtomicBool, // Set to stop the scan, which then fails as interrupted
+}
+
+impl ScanProgress {
+    pub fn to_rpc(&self) -> rpc::ScanProgress {
+        rpc::ScanProgress {
+            directories: self.directories.load(Ordering::Relaxed),
+            files: self.files.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Shared by the threads scanning the tree, each taking whole directories
+struct Scan<'a> {
+    config: &'a ScanConfig,
+    files: &'a Mutex<FileTable>,
+    errors: &'a Mutex<Vec<ScanError>>,
+    device: Option<u64>, // File system of the root
+    progress: Arc<ScanProgress>,
+}
+
+impl Scan<'_> {
+    fn error(&self, err: ScanError) {
+        self.progress.errors.fetch_add(1, Ordering::Relaxed);
+        self.errors.lock().unwrap().push(err);
+    }
+
+    fn rules(&self, rules: &Rules, dir: &Path) -> Rules {
+        let mut errors = Vec::new();
+        let rules = rules.enter(dir, &mut errors);
+        errors.into_iter().for_each(|err| self.error(err));
+        rules
+    }
+
+    fn file(&self, path: PathBuf) -> FileRef {
+        let metadata = match self.config.loaded_depth {
+            Some(_) => None,
+            None => Some(FileMetadata::new(&path)),
+        };
+        let file = self.files.lock().unwrap().insert(File::new(path, BTreeSet::new(), metadata));
+        self.progress.files.fetch_add(1, Ordering::Relaxed);
+        file
+    }
+
+    // Files below the directory at path, as a scan loading it would find them. Nothing
+    // is reported: what cannot be read is, once the directory is loaded.
+    fn list(&self, path: &Path, meta: &std::fs::Metadata, rules: &Rules, ancestors: &[PathBuf], found: &mut dyn FnMut(PathBuf)) {
+        if self.progress.cancelled.load(Ordering::Relaxed) {
+            return;
+        }
+        if !self.config.cross_devices && device(meta) != self.device {
+            return;
+        }
+        let Ok(canonical) = std::fs::canonicalize(path) else {
+            return;
+        };
+        if ancestors.contains(&canonical) {
+            return;
+        }
+        let mut ancestors = ancestors.to_vec();
+        ancestors.push(canonical);
+        let rules = rules.enter(path, &mut Vec::new());
+        let Ok(entries) = std::fs::read_dir(path) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let entry_path = entry.path();
+            let file_type = entry.file_type().ok();
+            if rules.ignores(&entry_path, file_type.is_some_and(|t| t.is_dir())) {
+                continue;
+            }
+            if file_type.is_some_and(|t| t.is_symlink()) && self.config.symlinks != SymlinkPolicy::Follow {
+                continue;
+            }
+            match std::fs::metadata(&entry_path) {
+                Ok(meta) if meta.is_dir() => self.list(&entry_path, &meta, &rules, &ancestors, found),
+                Ok(_) => found(entry_path),
+                Err(_) => {}
+            }
+        }
+    }
+
+    fn count(&self, path: &Path, meta: &std::fs::Metadata, rules: &Rules, ancestors: &[PathBuf]) -> u64 {
+        let mut count = 0;
+        self.list(path, meta, rules, ancestors, &mut |_| count += 1);
+        count
+    }
+
+    // ancestors are the canonical paths of the directories above path, below the levels
+    // of directories under path to load (None for all of them)
+    fn directory(
+        &self,
+        path: PathBuf,
+        meta: &std::fs::Metadata,
+        rules: &Rules,
+        ancestors: &[PathBuf],
+        below: Option<usize>,
+    ) -> Option<Node> {
+        if !self.config.cross_devices && device(meta) != self.device {
+            return None;
+        }
+        let canonical = match std::fs::canonicalize(&path) {
+            Ok(canonical) => canonical,
+            Err(err) => {
+                self.error(ScanError::Inaccessible(path, err.kind()));
+                return None;
+            }
+        };
+        if ancestors.contains(&canonical) {
+            self.error(ScanError::Cycle(path));
+            return None;
+        }
+        let mut ancestors = ancestors.to_vec();
+        ancestors.push(canonical);
+        let rules = self.rules(rules, &path);
+        match Node::new(path.clone(), self, &rules, &ancestors, below) {
+            Ok(node) => Some(node),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => None,
+            Err(err) => {
+                self.error(ScanError::Inaccessible(path, err.kind()));
+                None
+            }
+        }
+    }
+}
+
+// Files of a tree by id. The ids of unloaded files are not reused, so that an id held
+// across an eviction never designates another file.
+#[derive(Debug, Default)]
+pub struct FileTable {
+    slots: HashMap<u32, FileRef>,
+    next_id: u32, // Wraps only after 2^32 files loaded into the same tree
+}
+
+impl FileTable {
+    fn insert(&mut self, file: File) -> FileRef {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let file = FileRef {
+            id,
+            file_ref: Arc::new(RwLock::new(file)),
+        };
+        self.slots.insert(id, file.clone());
+        file
+    }
+
+    fn remove(&mut self, ids: &RoaringBitmap) {
+        for id in ids {
+            self.slots.remove(&id);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &FileRef> {
+        self.slots.values()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs())
+}
+
+// Directory tree of a shelf, with the table its file ids index into
+#[derive(Debug)]
+pub struct Tree {
+    pub root: NodeRef,
+    pub files: Mutex<FileTable>,
+    pub errors: Mutex<Vec<ScanError>>, // Entries that could not be scanned
+    path: PathBuf,
+    config: ScanConfig,
+    device: Option<u64>,
+    loading: Mutex<()>, // Serializes loads and evictions
+    loaded: Mutex<Vec<PathBuf>>, // Directories loaded since last taken, relative to the root
+}
+
+impl Tree {
+    pub fn new(path: PathBuf, config: &ScanConfig) -> Result<Self, io::Error> {
+        Tree::scan(path, config, Arc::default())
+    }
+
+    // Directories are scanned in parallel. Only fails if the root itself cannot be
+    // read, or if the scan is cancelled through progress.
+    pub fn scan(path: PathBuf, config: &ScanConfig, progress: Arc<ScanProgress>) -> Result<Self, io::Error> {
+        let meta = std::fs::metadata(&path)?;
+        let ancestors = vec![std::fs::canonicalize(&path)?];
+        let mut tree = Tree {
+            root: NodeRef::default(),
+            files: Mutex::default(),
+            errors: Mutex::default(),
+            path: path.clone(),
+            config: config.clone(),
+            device: device(&meta),
+            loading: Mutex::new(()),
+            loaded: Mutex::default(),
+        };
+        let scan = tree.scanner(progress);
+        let mut errors = Vec::new();
+        let rules = Rules::new(&path, config, &mut errors);
+        errors.into_iter().for_each(|err| scan.error(err));
+        let below = config.loaded_depth.map(|depth| depth.saturating_sub(1));
+        let root = Node::new(path, &scan, &rules, &ancestors, below)?;
+        if scan.progress.cancelled.load(Ordering::Relaxed) {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "scan cancelled"));
+        }
+        tree.root = Arc::new(RwLock::new(root));
+        Ok(tree)
+    }
+
+    fn scanner(&self, progress: Arc<ScanProgress>) -> Scan<'_> {
+        Scan {
+            config: &self.config,
+            files: &self.files,
+            errors: &self.errors,
+            device: self.device,
+            progress,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn files(&self, ids: &RoaringBitmap) -> Vec<FileRef> {
+        let files = self.files.lock().unwrap();
+        ids.iter().filter_map(|id| files.slots.get(&id).cloned()).collect()
+    }
+
+    // Ignore rules in effect in the directory at rel_path, with the canonical paths of
+    // that directory and of the directories above it
+    fn context(&self, rel_path: &Path) -> Result<(Rules, Vec<PathBuf>), io::Error> {
+        // Invalid rules were reported when the directories were first scanned
+        let mut errors = Vec::new();
+        let mut rules = Rules::new(&self.path, &self.config, &mut errors);
+        let mut current = self.path.clone();
+        let mut ancestors = vec![std::fs::canonicalize(&current)?];
+        for component in rel_path.components() {
+            current.push(component);
+            rules = rules.enter(&current, &mut errors);
+            ancestors.push(std::fs::canonicalize(&current)?);
+        }
+        Ok((rules, ancestors))
+    }
+
+    // Files below the unloaded directory at rel_path, read from the disk without loading
+    // them
+    pub fn unloaded_files(&self, rel_path: &Path) -> Vec<PathBuf> {
+        let path = self.path.join(rel_path);
+        let parent = rel_path.parent().unwrap_or(Path::new(""));
+        let mut files = Vec::new();
+        if let (Ok(meta), Ok((rules, ancestors))) = (std::fs::metadata(&path), self.context(parent)) {
+            self.scanner(Arc::default())
+                .list(&path, &meta, &rules, &ancestors, &mut |file| files.push(file));
+        }
+        files
+    }
+
+    // Directories loaded since the last call, relative to the root
+    pub fn take_loaded(&self) -> Vec<PathBuf> {
+        std::mem::take(&mut *self.loaded.lock().unwrap())
+    }
+
+    // Load the node at the end of chain, at rel_path below the root, if it was left
+    // unloaded. With whole, every directory below it is loaded as well. A directory that
+    // can no longer be scanned is removed from its parent and reported.
+    pub fn load(&self, chain: &[NodeRef], rel_path: &Path, whole: bool) -> Result<(), io::Error> {
+        let _loading = self.loading.lock().unwrap();
+        self.load_in(chain, rel_path, whole)
+    }
+
+    fn load_in(&self, chain: &[NodeRef], rel_path: &Path, whole: bool) -> Result<(), io::Error> {
+        let (target, ancestors) = chain.split_last().unwrap();
+        if target.read().unwrap().loaded {
+            if !whole {
+                return Ok(());
+            }
+            let children: Vec<(PathBuf, NodeRef)> = target
+                .read()
+                .unwrap()
+                .directories
+                .iter()
+                .map(|(name, child)| (name.clone(), child.clone()))
+                .collect();
+            let mut chain = chain.to_vec();
+            for (name, child) in children {
+                chain.push(child);
+                // Failures are reported, and leave out the directory
+                let _ = self.load_in(&chain, &rel_path.join(name), true);
+                chain.pop();
+            }
+            return Ok(());
+        }
+        let path = self.path.join(rel_path);
+        let parent = rel_path.parent().unwrap_or(Path::new(""));
+        // The scan reports why it left out the directory, if it did
+        let scanned = match std::fs::metadata(&path).and_then(|meta| Ok((meta, self.context(parent)?))) {
+            Ok((meta, (rules, canonical))) => {
+                let below = if whole { None } else { Some(0) };
+                self.scanner(Arc::default())
+                    .directory(path.clone(), &meta, &rules, &canonical, below)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "directory left out"))
+            }
+            Err(err) => {
+                self.errors.lock().unwrap().push(ScanError::Inaccessible(path, err.kind()));
+                Err(err)
+            }
+        };
+        // The counts the scan left for the directory give way to what was found now
+        let (old_count, old_unloaded) = {
+            let target = target.read().unwrap();
+            (target.file_count, target.unloaded)
+        };
+        let node = match scanned {
+            Ok(node) => node,
+            Err(err) => {
+                if let (Some(parent), Some(name)) = (ancestors.last(), rel_path.file_name()) {
+                    parent.write().unwrap().directories.remove(Path::new(name));
+                }
+                for node in ancestors {
+                    let mut node = node.write().unwrap();
+                    node.file_count = node.file_count.saturating_sub(old_count);
+                    node.unloaded = node.unloaded.saturating_sub(old_unloaded);
+                }
+                return Err(err);
+            }
+        };
+        let (count, unloaded) = (node.file_count, node.unloaded);
+        let untagged = node.untagged.clone();
+        node.touch();
+        *target.write().unwrap() = node;
+        for node in ancestors {
+            let mut node = node.write().unwrap();
+            node.file_count = (node.file_count + count).saturating_sub(old_count);
+            node.unloaded = (node.unloaded + unloaded).saturating_sub(old_unloaded);
+            node.untagged |= &untagged;
+        }
+        self.loaded.lock().unwrap().push(rel_path.to_path_buf());
+        Ok(())
+    }
+
+    // Unload the directories below the levels loaded up front that were not accessed
+    // for the configured time, returning the number of files unloaded. Directories
+    // holding tags, or below a dtag, are kept so that tags never refer to unloaded files:
+    // the files unloaded are all untagged, and stay counted as such.
+    pub fn evict(&self) -> u64 {
+        let Some(depth) = self.config.loaded_depth else {
+            return 0;
+        };
+        let _loading = self.loading.lock().unwrap();
+        let deadline = now().saturating_sub(self.config.evict_after.as_secs());
+        let mut evicted = RoaringBitmap::new();
+        evict(&mut vec![self.root.clone()], depth.saturating_sub(1), deadline, &mut evicted);
+        self.files.lock().unwrap().remove(&evicted);
+        evicted.len()
+    }
+
+    // Verify the aggregates of every node against the files below it, and the file ids
+    // against the table. With repair, the ids and aggregates found wrong are rebuilt from
+    // the files, which hold the tags, and from the dtags and exclusions of the nodes.
+    pub fn check(&self, repair: bool) -> Vec<Violation> {
+        let _loading = self.loading.lock().unwrap();
+        let mut violations = Vec::new();
+        let mut files = self.files.lock().unwrap();
+        let mut reached = RoaringBitmap::new();
+        check_ids(&self.root, &files, &mut reached, &mut violations);
+        for (id, file) in &files.slots {
+            if !reached.contains(*id) {
+                violations.push(Violation {
+                    path: file.file_ref.read().unwrap().path().clone(),
+                    invariant: Invariant::FileTable,
+                    tag: None,
+                    expected: 0,
+                    found: 1,
+                });
+            }
+        }
+        if repair && !violations.is_empty() {
+            let mut slots = HashMap::new();
+            let mut next_id = files.next_id;
+            reindex(&self.root, &mut slots, &mut next_id);
+            files.slots = slots;
+            files.next_id = next_id;
+        }
+        drop(files);
+        check_node(&self.root, &self.path, repair, &mut violations);
+        violations
+    }
+}
+
+// kept is the number of levels of directories below the end of chain loaded up front
+fn evict(chain: &mut Vec<NodeRef>, kept: usize, deadline: u64, evicted: &mut RoaringBitmap) {
+    let children: Vec<NodeRef> = {
+        let node = chain.last().unwrap().read().unwrap();
+        if !node.dtags.is_empty() {
+            return;
+        }
+        node.directories.values().cloned().collect()
+    };
+    for child in children {
+        let (idle, unloaded) = {
+            let node = child.read().unwrap();
+            if !node.loaded {
+                continue;
+            }
+            let idle = kept == 0
+                && node.accessed.load(Ordering::Relaxed) <= deadline
+                && node.tags.is_empty()
+                && node.dtag_counts.is_empty()
+                && node.exclusion_counts.is_empty();
+            (idle, node.unloaded)
+        };
+        if !idle {
+            chain.push(child);
+            evict(chain, kept.saturating_sub(1), deadline, evicted);
+            chain.pop();
+            continue;
+        }
+        let (count, untagged) = {
+            let mut child = child.write().unwrap();
+            let count = child.file_count;
+            *evicted |= child.file_ids();
+            (count, std::mem::replace(&mut *child, Node::unloaded(count)).untagged)
+        };
+        for node in chain.iter() {
+            let mut node = node.write().unwrap();
+            node.unloaded = (node.unloaded + count).saturating_sub(unloaded);
+            node.untagged -= &untagged;
+        }
+    }
+}
+
+// Invariant of a tree found broken, counting files (or exclusions) as the files and
+// nodes below say and as the aggregate holds them
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub path: PathBuf, // Directory holding the aggregate, or file for the file table
+    pub invariant: Invariant,
+    pub tag: Option<TagRef>,
+    pub expected: u64,
+    pub found: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Invariant {
+    Tags, // Node.tags against the tags of the files below
+    Untagged,
+    FileCount,
+    Unloaded,
+    DtagCount, // Node.dtag_counts against the dtagged directories below
+    ExclusionCount,
+    FileTable, // Files whose id does not lead to them in the table, or unreachable entries
+}
+
+impl Invariant {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Invariant::Tags => "tags",
+            Invariant::Untagged => "untagged",
+            Invariant::FileCount => "file_count",
+            Invariant::Unloaded => "unloaded",
+            Invariant::DtagCount => "dtag_count",
+            Invariant::ExclusionCount => "exclusion_count",
+            Invariant::FileTable => "file_table",
+        }
+    }
+}
+
+fn check_ids(node: &NodeRef, files: &FileTable, reached: &mut RoaringBitmap, violations: &mut Vec<Violation>) {
+    let node = node.read().unwrap();
+    for file in node.files.values() {
+        let indexed = files
+            .slots
+            .get(&file.id)
+            .is_some_and(|slot| Arc::ptr_eq(&slot.file_ref, &file.file_ref));
+        // A second file with the same id is as wrong as a missing entry
+        if !indexed || !reached.insert(file.id) {
+            violations.push(Violation {
+                path: file.file_ref.read().unwrap().path().clone(),
+                invariant: Invariant::FileTable,
+                tag: None,
+                expected: 1,
+                found: 0,
+            });
+        }
+    }
+    for child in node.directories.values() {
+        check_ids(child, files, reached, violations);
+    }
+}
+
+// Files keep their id unless another file took it first, which then gets a new one
+fn reindex(node: &NodeRef, slots: &mut HashMap<u32, FileRef>, next_id: &mut u32) {
+    let children: Vec<NodeRef> = {
+        let mut node = node.write().unwrap();
+        for file in node.files.values_mut() {
+            if slots.contains_key(&file.id) {
+                file.id = *next_id;
+            }
+            *next_id = (*next_id).max(file.id.wrapping_add(1));
+            slots.insert(file.id, file.clone());
+        }
+        node.directories.values().cloned().collect()
+    };
+    for child in children {
+        reindex(&child, slots, next_id);
+    }
+}
+
+// Aggregates of a node as recomputed from what lies below it
+#[derive(Default)]
+struct Aggregates {
+    tags: HashMap<TagRef, RoaringBitmap>,
+    untagged: RoaringBitmap,
+    file_count: u64,
+    unloaded: u64,
+    dtag_counts: HashMap<TagRef, u64>,
+    exclusion_counts: HashMap<TagRef, u64>,
+}
+
+// Counts that do not match as (tag, expected, found), tags that only one side has included
+fn compare(found: &HashMap<TagRef, u64>, expected: &HashMap<TagRef, u64>) -> Vec<(TagRef, u64, u64)> {
+    let tags: HashSet<&TagRef> = found.keys().chain(expected.keys()).collect();
+    tags.into_iter()
+        .filter_map(|tag| {
+            let (expected, found) = (expected.get(tag).cloned().unwrap_or(0), found.get(tag).cloned().unwrap_or(0));
+            (expected != found).then(|| (tag.clone(), expected, found))
+        })
+        .collect()
+}
+
+fn check_node(node: &NodeRef, path: &Path, repair: bool, violations: &mut Vec<Violation>) -> Aggregates {
+    let mut expected = Aggregates::default();
+    let children: Vec<(PathBuf, NodeRef)> = {
+        let node = node.read().unwrap();
+        // Only the scan knows the files of an unloaded directory
+        if !node.loaded {
+            expected.file_count = node.file_count;
+            expected.unloaded = node.file_count;
+        }
+        for (file_path, file) in &node.files {
+            let file_ref = file.file_ref.read().unwrap();
+            for tag in file_ref.tags() {
+                expected.tags.entry(tag.clone()).or_default().insert(file.id);
+            }
+            if file_ref.tags().is_empty() {
+                expected.untagged.insert(file.id);
+            }
+            for dtag in node.excluded_files.get(file_path).into_iter().flatten() {
+                *expected.exclusion_counts.entry(dtag.clone()).or_default() += 1;
+            }
+        }
+        for dtag in &node.excluded {
+            *expected.exclusion_counts.entry(dtag.clone()).or_default() += 1;
+        }
+        expected.file_count += node.files.len() as u64;
+        node.directories.iter().map(|(name, child)| (name.clone(), child.clone())).collect()
+    };
+    for (name, child) in children {
+        let below = check_node(&child, &path.join(name), repair, violations);
+        for (tag, ids) in below.tags {
+            *expected.tags.entry(tag).or_default() |= ids;
+        }
+        expected.untagged |= below.untagged;
+        expected.file_count += below.file_count;
+        expected.unloaded += below.unloaded;
+        for (dtag, count) in below.dtag_counts {
+            *expected.dtag_counts.entry(dtag).or_default() += count;
+        }
+        for (dtag, count) in below.exclusion_counts {
+            *expected.exclusion_counts.entry(dtag).or_default() += count;
+        }
+    }
+
+    let mut node = node.write().unwrap();
+    for dtag in &node.dtags {
+        *expected.dtag_counts.entry(dtag.clone()).or_default() += expected.file_count;
+    }
+    let violation = |invariant, tag, expected, found| Violation {
+        path: path.to_path_buf(),
+        invariant,
+        tag,
+        expected,
+        found,
+    };
+    let mut found = Vec::new();
+    let tags: HashSet<&TagRef> = node.tags.keys().chain(expected.tags.keys()).collect();
+    for tag in tags {
+        let (ids, held) = (expected.tags.get(tag), node.tags.get(tag));
+        if ids != held {
+            let count = |ids: Option<&RoaringBitmap>| ids.map_or(0, |ids| ids.len());
+            found.push(violation(Invariant::Tags, Some(tag.clone()), count(ids), count(held)));
+        }
+    }
+    if node.untagged != expected.untagged {
+        found.push(violation(Invariant::Untagged, None, expected.untagged.len(), node.untagged.len()));
+    }
+    if node.file_count != expected.file_count {
+        found.push(violation(Invariant::FileCount, None, expected.file_count, node.file_count));
+    }
+    if node.unloaded != expected.unloaded {
+        found.push(violation(Invariant::Unloaded, None, expected.unloaded, node.unloaded));
+    }
+    for (dtag, expected, held) in compare(&node.dtag_counts, &expected.dtag_counts) {
+        found.push(violation(Invariant::DtagCount, Some(dtag), expected, held));
+    }
+    for (dtag, expected, held) in compare(&node.exclusion_counts, &expected.exclusion_counts) {
+        found.push(violation(Invariant::ExclusionCount, Some(dtag), expected, held));
+    }
+    if repair && !found.is_empty() {
+        node.tags = expected.tags.clone();
+        node.untagged = expected.untagged.clone();
+        node.file_count = expected.file_count;
+        node.unloaded = expected.unloaded;
+        node.dtag_counts = expected.dtag_counts.clone();
+        node.exclusion_counts = expected.exclusion_counts.clone();
+    }
+    violations.append(&mut found);
+    expected
+}
+
+// Tag memberships are bitmaps of the ids of the files below the node
 #[derive(Debug, Default)]
 pub struct Node {
     pub files: HashMap<PathBuf, FileRef>,
-    pub tags: HashMap<TagRef, BTreeSet<FileRef>>,
+    pub tags: HashMap<TagRef, RoaringBitmap>,
     pub dtags: HashSet<TagRef>, // directory level tags, to be applied down
-    pub dtag_files: HashMap<TagRef, BTreeSet<FileRef>>, // files tagged with directory level tags
-    pub directories: HashMap<PathBuf, Node>, // untagged: set of untagged files, support structure
+    // Files below dtagged nodes, counting a file once per dtagged directory above it.
+    // Only an estimate, files are resolved by walking down to the dtagged nodes.
+    pub dtag_counts: HashMap<TagRef, u64>,
+    pub excluded: HashSet<TagRef>, // dtags of the directories above not passed down to this one
+    pub excluded_files: HashMap<PathBuf, HashSet<TagRef>>, // Same, for the files of the node
+    pub exclusion_counts: HashMap<TagRef, u64>, // Exclusions at or below the node
+    pub directories: HashMap<PathBuf, NodeRef>,
+    pub untagged: RoaringBitmap, // Files below the node without tags of their own, dtagged or not
+    pub links: HashMap<PathBuf, PathBuf>, // Symbolic links with their target, when recorded
+    pub file_count: u64, // Files of the node and of every node below it, loaded or not
+    pub unloaded: u64, // Files below the node in directories left unloaded, all untagged
+    pub loaded: bool, // False for the directories of lazy shelves not read yet
+    pub accessed: AtomicU64, // Seconds since the epoch, when the node was last walked through
 }
 
 impl Node {
-    pub fn new(path: PathBuf) -> Result<Self, io::Error> {
-        let entries = std::fs::read_dir(&path)?
-            .map(|res| res.map(|e| e.path()))
-            .collect::<Result<Vec<_>, io::Error>>()?;
-        let (dir_paths, file_paths): (Vec<_>, Vec<_>) =
-            entries.into_iter().partition(|path| path.is_dir());
-        let files = file_paths
-            .into_iter()
-            .map(|file_path| {
-                (
-                    file_path.clone(),
-                    FileRef {
-                        file_ref: Rc::new(RwLock::new(File::new(
-                            file_path.clone(),
-                            BTreeSet::new(),
-                            BTreeSet::new(),
-                            FileMetadata::new(&file_path),
-                        ))),
-                    },
-                )
-            })
-            .collect::<HashMap<PathBuf, FileRef>>();
-        let directories = dir_paths
-            .into_iter()
-            .map(|dir| {
-                (
-                    dir.strip_prefix(&path).unwrap().to_path_buf(),
-                    Node::new(dir).unwrap(),
-                )
+    // Entries that cannot be read are reported in scan and left out, as are the
+    // ignored ones. Subdirectories are handed to other threads, or left unloaded past
+    // the levels below to load.
+    fn new(path: PathBuf, scan: &Scan, rules: &Rules, ancestors: &[PathBuf], below: Option<usize>) -> Result<Self, io::Error> {
+        if scan.progress.cancelled.load(Ordering::Relaxed) {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "scan cancelled"));
+        }
+        let mut node = Node {
+            loaded: true,
+            accessed: AtomicU64::new(now()),
+            ..Default::default()
+        };
+        let mut dirs = Vec::new();
+        for entry in std::fs::read_dir(&path)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    scan.error(ScanError::Inaccessible(path.clone(), err.kind()));
+                    continue;
+                }
+            };
+            let entry_path = entry.path();
+            if rules.ignores(&entry_path, entry.file_type().is_ok_and(|t| t.is_dir())) {
+                continue;
+            }
+            let is_link = entry.file_type().is_ok_and(|t| t.is_symlink());
+            if is_link && scan.config.symlinks == SymlinkPolicy::Skip {
+                continue;
+            }
+            if is_link && scan.config.symlinks == SymlinkPolicy::Record {
+                match std::fs::read_link(&entry_path) {
+                    Ok(target) => {
+                        node.links.insert(entry_path, target);
+                    }
+                    Err(err) => scan.error(ScanError::Inaccessible(entry_path, err.kind())),
+                }
+                continue;
+            }
+            // Follows links, dangling ones end up here
+            let meta = match std::fs::metadata(&entry_path) {
+                Ok(meta) => meta,
+                Err(err) => {
+                    scan.error(ScanError::Inaccessible(entry_path, err.kind()));
+                    continue;
+                }
+            };
+            if meta.is_dir() && below == Some(0) {
+                // Left unloaded, its files are only counted
+                let count = scan.count(&entry_path, &meta, rules, ancestors);
+                node.file_count += count;
+                node.unloaded += count;
+                node.directories.insert(PathBuf::from(entry.file_name()), Arc::new(RwLock::new(Node::unloaded(count))));
+                continue;
+            }
+            if meta.is_dir() {
+                dirs.push((PathBuf::from(entry.file_name()), entry_path, meta));
+                continue;
+            }
+            let file = scan.file(entry_path.clone());
+            node.untagged.insert(file.id);
+            node.files.insert(entry_path, file);
+        }
+        let children: Vec<(PathBuf, Node)> = dirs
+            .into_par_iter()
+            .filter_map(|(name, dir, meta)| {
+                Some((name, scan.directory(dir, &meta, rules, ancestors, below.map(|below| below - 1))?))
             })
-            .collect::<HashMap<PathBuf, Node>>();
-        Ok(Node {
-            files,
-            tags: HashMap::new(),
-            dtags: HashSet::new(),
-            dtag_files: HashMap::new(),
-            directories,
-        })
+            .collect();
+        for (name, child) in children {
+            node.file_count += child.file_count;
+            node.unloaded += child.unloaded;
+            node.untagged |= &child.untagged;
+            node.directories.insert(name, Arc::new(RwLock::new(child)));
+        }
+        node.file_count += node.files.len() as u64;
+        scan.progress.directories.fetch_add(1, Ordering::Relaxed);
+        Ok(node)
+    }
+
+    // Directory not loaded, holding count files
+    fn unloaded(count: u64) -> Self {
+        Node {
+            file_count: count,
+            unloaded: count,
+            ..Default::default()
+        }
+    }
+
+    pub fn touch(&self) {
+        self.accessed.store(now(), Ordering::Relaxed);
+    }
+
+    pub fn all_files(&self) -> Vec<FileRef> {
+        let mut files = self.files.values().cloned().collect::<Vec<FileRef>>();
+        for node in self.directories.values() {
+            files.append(&mut node.read().unwrap().all_files());
+        }
+        files
     }
 
     pub fn attach_dtag(&mut self, dtag: TagRef) -> bool {
@@ -64,16 +950,88 @@ impl Node {
         self.dtags.remove(&dtag)
     }
 
-    pub fn attach(&mut self, tag: TagRef, file: FileRef) -> bool {
-        let set = self.tags.entry(tag).or_insert_with(|| BTreeSet::new());
-        set.insert(file.clone())
+    pub fn count_dtag(&mut self, dtag: TagRef, added: bool, count: u64) {
+        let total = self.dtag_counts.entry(dtag.clone()).or_default();
+        *total = if added { *total + count } else { total.saturating_sub(count) };
+        if *total == 0 {
+            self.dtag_counts.remove(&dtag);
+        }
+    }
+
+    pub fn count_exclusion(&mut self, dtag: TagRef, added: bool) {
+        let total = self.exclusion_counts.entry(dtag.clone()).or_default();
+        *total = if added { *total + 1 } else { total.saturating_sub(1) };
+        if *total == 0 {
+            self.exclusion_counts.remove(&dtag);
+        }
+    }
+
+    // Ids of the files inheriting dtag, only descending where dtagged nodes are
+    pub fn dtag_file_ids(&self, dtag: &TagRef) -> RoaringBitmap {
+        if self.dtags.contains(dtag) {
+            return self.covered_ids(dtag);
+        }
+        let mut ids = RoaringBitmap::new();
+        for node in self.directories.values() {
+            let node = node.read().unwrap();
+            if node.dtag_counts.contains_key(dtag) {
+                ids |= node.dtag_file_ids(dtag);
+            }
+        }
+        ids
+    }
+
+    // Ids of the files below the node inheriting dtag, given that the node itself does.
+    // Only descends where exclusions are.
+    pub fn covered_ids(&self, dtag: &TagRef) -> RoaringBitmap {
+        if !self.exclusion_counts.contains_key(dtag) {
+            return self.file_ids();
+        }
+        let mut ids = self
+            .files
+            .iter()
+            .filter(|(path, _)| !self.excluded_files.get(*path).is_some_and(|dtags| dtags.contains(dtag)))
+            .map(|(_, file)| file.id)
+            .collect::<RoaringBitmap>();
+        for node in self.directories.values() {
+            let node = node.read().unwrap();
+            ids |= match node.excluded.contains(dtag) {
+                true => node.dtag_file_ids(dtag),
+                false => node.covered_ids(dtag),
+            };
+        }
+        ids
+    }
+
+    // Untagged files below the node, leaving out those inheriting a dtag from it or from
+    // a node below it
+    pub fn untagged_ids(&self) -> RoaringBitmap {
+        let mut ids = self.untagged.clone();
+        for dtag in self.dtag_counts.keys() {
+            ids -= self.dtag_file_ids(dtag);
+        }
+        ids
+    }
+
+    // Ids of the files of the node and of every node below it
+    pub fn file_ids(&self) -> RoaringBitmap {
+        let mut ids = self.files.values().map(|file| file.id).collect::<RoaringBitmap>();
+        for node in self.directories.values() {
+            ids |= node.read().unwrap().file_ids();
+        }
+        ids
+    }
+
+    pub fn attach(&mut self, tag: TagRef, file: u32) -> bool {
+        self.untagged.remove(file);
+        self.tags.entry(tag).or_default().insert(file)
     }
 
-    pub fn detach(&mut self, tag: TagRef, file: Option<FileRef>) -> bool {
+    pub fn detach(&mut self, tag: TagRef, file: Option<u32>) -> bool {
         match file {
             Some(file) => {
                 if let Some(set) = self.tags.get_mut(&tag) {
-                    let res = set.remove(&file);
+                    let res = set.remove(file);
                     if set.is_empty() {
                         self.tags.remove(&tag);
                     }
@@ -86,3 +1044,104 @@ impl Node {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Directory under the temporary one holding the given files, empty
+    fn dir(paths: &[&str]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("ebi-node-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&root).unwrap();
+        for path in paths {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        std::fs::canonicalize(root).unwrap()
+    }
+
+    // Paths of the files of the tree relative to its root, sorted
+    fn names(tree: &Tree) -> Vec<String> {
+        let mut names: Vec<_> = tree
+            .files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|file| {
+                let file = file.file_ref.read().unwrap();
+                file.path().strip_prefix(tree.path()).unwrap().to_string_lossy().into_owned()
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn broken_entries_are_reported_and_left_out() {
+        use std::os::unix::fs::symlink;
+        let root = dir(&["a/x", "b/y"]);
+        symlink(root.join("nowhere"), root.join("dangling")).unwrap();
+        symlink(&root, root.join("a/up")).unwrap();
+        let tree = Tree::new(root.clone(), &ScanConfig::default()).unwrap();
+        assert_eq!(names(&tree), ["a/x", "b/y"]);
+        assert_eq!(tree.root.read().unwrap().file_count, 2);
+        let mut errors: Vec<_> = tree.errors.lock().unwrap().clone();
+        errors.sort_by(|a, b| a.path().cmp(b.path()));
+        assert!(matches!(&errors[..], [
+            ScanError::Cycle(cycle),
+            ScanError::Inaccessible(dangling, io::ErrorKind::NotFound),
+        ] if *cycle == root.join("a/up") && *dangling == root.join("dangling")));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn ignore_rules_leave_out_entries() {
+        let root = dir(&["a.txt", "a.log", "build/out", "sub/b.tmp", "sub/c.txt", "sub/keep.log", ".hidden"]);
+        std::fs::write(root.join("sub").join(IGNORE_FILE), "*.tmp\n!keep.log\n").unwrap();
+        let config = ScanConfig {
+            ignore: vec!["*.log".to_string(), "build/".to_string()],
+            skip_hidden: true,
+            ..Default::default()
+        };
+        assert_eq!(config.invalid_ignore(), None);
+        let tree = Tree::new(root.clone(), &config).unwrap();
+        // The .ebiignore file of sub takes precedence over the patterns of the shelf
+        assert_eq!(names(&tree), ["a.txt", "sub/c.txt", "sub/keep.log"]);
+        assert!(!tree.root.read().unwrap().directories.contains_key(Path::new("build")));
+        assert!(ignored(&root, &config, &root.join("build/out")));
+        assert!(ignored(&root, &config, &root.join("sub/b.tmp")));
+        assert!(ignored(&root, &config, &root.join(".hidden")));
+        assert!(!ignored(&root, &config, &root.join("sub/keep.log")));
+        assert!(tree.errors.lock().unwrap().is_empty());
+
+        let config = ScanConfig {
+            ignore: vec!["*.log".to_string(), "{a".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(config.invalid_ignore().map(String::as_str), Some("{a"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn parallel_scans_count_every_entry() {
+        let paths: Vec<String> = (0..8).flat_map(|d| (0..5).map(move |f| format!("d{d}/e/f{f}"))).collect();
+        let root = dir(&paths.iter().map(String::as_str).collect::<Vec<_>>());
+        let progress = Arc::new(ScanProgress::default());
+        let tree = Tree::scan(root.clone(), &ScanConfig::default(), progress.clone()).unwrap();
+        assert_eq!(names(&tree).len(), 40);
+        assert_eq!(tree.root.read().unwrap().file_count, 40);
+        assert_eq!(tree.root.read().unwrap().untagged.len(), 40);
+        assert_eq!(progress.files.load(Ordering::Relaxed), 40);
+        // The root, then d0..d7 and the e below each
+        assert_eq!(progress.directories.load(Ordering::Relaxed), 17);
+        assert_eq!(progress.errors.load(Ordering::Relaxed), 0);
+
+        let progress = Arc::new(ScanProgress::default());
+        progress.cancelled.store(true, Ordering::Relaxed);
+        let err = Tree::scan(root.clone(), &ScanConfig::default(), progress).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
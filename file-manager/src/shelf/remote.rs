@@ -0,0 +1,122 @@
+use crate::query::FileID;
+use crate::services::peer::{PeerService, UpdateTag};
+use crate::shelf::file::FileMetadata;
+use crate::shelf::shelf::{Shelf, ShelfFuture, UpdateErr};
+use crate::sync::TagOpKind;
+use crate::tag::TagRef;
+use crate::workspace::{ShelfId, WorkspaceId};
+use iroh::NodeId;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tower::Service;
+
+// Shelf owned by another peer. Reads are forwarded to it, tag operations are queued
+// as mutations until it acknowledges them.
+pub struct RemoteShelf {
+    peer_service: PeerService,
+    node: NodeId,
+    workspace_id: WorkspaceId,
+    shelf_id: ShelfId, // Id of the shelf on its peer
+}
+
+impl RemoteShelf {
+    pub fn new(peer_service: PeerService, node: NodeId, workspace_id: WorkspaceId, shelf_id: ShelfId) -> Self {
+        RemoteShelf {
+            peer_service,
+            node,
+            workspace_id,
+            shelf_id,
+        }
+    }
+
+    pub fn node(&self) -> NodeId {
+        self.node
+    }
+
+    // The change only shows once the peer applied it, so never reported as done
+    async fn update(&self, path: PathBuf, tag: TagRef, kind: TagOpKind) -> Result<bool, UpdateErr> {
+        self.peer_service
+            .clone()
+            .call(UpdateTag {
+                client_id: 0,
+                owner: Some(self.node),
+                workspace_id: self.workspace_id,
+                shelf_id: self.shelf_id,
+                path,
+                tag: tag.name(),
+                kind,
+            })
+            .await
+            .map_err(|_| UpdateErr::Unreachable(self.node))?;
+        Ok(false)
+    }
+
+    // Version of the last summary received from the peer
+    async fn version(&self) -> Option<u64> {
+        self.peer_service
+            .remote_summaries
+            .read()
+            .await
+            .get(&(self.node, self.workspace_id))
+            .and_then(|shelves| shelves.get(&self.shelf_id))
+            .map(|summary| summary.version)
+    }
+}
+
+impl Shelf for RemoteShelf {
+    fn retrieve(self: Arc<Self>, tag: TagRef) -> ShelfFuture<'static, Vec<FileID>> {
+        Box::pin(async move {
+            let files = self
+                .peer_service
+                .retrieve(self.node, self.workspace_id, self.shelf_id, tag.name())
+                .await
+                .map_err(|_| UpdateErr::Unreachable(self.node))?;
+            Ok(files
+                .into_iter()
+                .map(|file| {
+                    let metadata = FileMetadata::from(&file.metadata.unwrap_or_default());
+                    FileID::remote(self.node, PathBuf::from(file.path), metadata)
+                })
+                .collect())
+        })
+    }
+
+    fn attach(self: Arc<Self>, path: PathBuf, tag: TagRef) -> ShelfFuture<'static, bool> {
+        Box::pin(async move { self.update(path, tag, TagOpKind::Attach).await })
+    }
+
+    // Tag operations target a single path, so detaching from every file is not supported
+    fn detach(self: Arc<Self>, path: Option<PathBuf>, tag: TagRef) -> ShelfFuture<'static, bool> {
+        Box::pin(async move {
+            let path = path.ok_or_else(|| UpdateErr::PathNotFound(PathBuf::new()))?;
+            self.update(path, tag, TagOpKind::Detach).await
+        })
+    }
+
+    fn attach_dtag(self: Arc<Self>, path: PathBuf, dtag: TagRef) -> ShelfFuture<'static, bool> {
+        Box::pin(async move { self.update(path, dtag, TagOpKind::AttachDtag).await })
+    }
+
+    fn detach_dtag(self: Arc<Self>, path: PathBuf, dtag: TagRef) -> ShelfFuture<'static, bool> {
+        Box::pin(async move { self.update(path, dtag, TagOpKind::DetachDtag).await })
+    }
+
+    fn exclude(self: Arc<Self>, path: PathBuf, dtag: TagRef) -> ShelfFuture<'static, bool> {
+        Box::pin(async move { self.update(path, dtag, TagOpKind::ExcludeDtag).await })
+    }
+
+    fn include(self: Arc<Self>, path: PathBuf, dtag: TagRef) -> ShelfFuture<'static, bool> {
+        Box::pin(async move { self.update(path, dtag, TagOpKind::IncludeDtag).await })
+    }
+
+    fn refresh(self: Arc<Self>) -> ShelfFuture<'static, bool> {
+        Box::pin(async move {
+            let before = self.version().await;
+            self.peer_service
+                .fetch_summaries(self.node)
+                .await
+                .map_err(|_| UpdateErr::Unreachable(self.node))?;
+            Ok(self.version().await != before)
+        })
+    }
+}
@@ -1,354 +1,1171 @@
-use crate::query::{Query, QueryErr};
+use crate::query::{FileID, Query, QueryErr};
+use crate::persist::write_atomic;
+use crate::rpc;
 use crate::shelf::file::File;
-use crate::shelf::node::Node;
+use crate::shelf::node::{self, NodeRef, ScanConfig, ScanError, ScanProgress, Tree, Violation};
+use crate::shelf::summary::ShelfSummary;
 use crate::tag::{self, TagRef};
-use std::collections::{BTreeSet, HashSet};
+use crate::workspace::WorkspaceId;
+use iroh::NodeId;
+use prost::Message;
+use roaring::RoaringBitmap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::io;
-use std::path::PathBuf;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::result::Result;
+use std::sync::{Arc, Mutex, RwLock};
 
-use super::file::FileRef;
+use super::file::{FileMetadata, FileRef};
 
-// Shelf
+pub type ShelfFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, UpdateErr>> + Send + 'a>>;
+
+// Facade over the files of a shelf, hiding whether they are on the local file system
+// or on another device. Paths are relative to the shelf root. The futures own the
+// shelf, so that its work can be moved off the async threads.
+pub trait Shelf: Send + Sync {
+    fn retrieve(self: Arc<Self>, tag: TagRef) -> ShelfFuture<'static, Vec<FileID>>;
+    fn attach(self: Arc<Self>, path: PathBuf, tag: TagRef) -> ShelfFuture<'static, bool>;
+    fn detach(self: Arc<Self>, path: Option<PathBuf>, tag: TagRef) -> ShelfFuture<'static, bool>; // None: from every file
+    fn attach_dtag(self: Arc<Self>, path: PathBuf, dtag: TagRef) -> ShelfFuture<'static, bool>;
+    fn detach_dtag(self: Arc<Self>, path: PathBuf, dtag: TagRef) -> ShelfFuture<'static, bool>;
+    fn exclude(self: Arc<Self>, path: PathBuf, dtag: TagRef) -> ShelfFuture<'static, bool>; // From the dtag of a directory above
+    fn include(self: Arc<Self>, path: PathBuf, dtag: TagRef) -> ShelfFuture<'static, bool>; // Lift an exclusion
+    fn refresh(self: Arc<Self>) -> ShelfFuture<'static, bool>; // Whether the files changed
+}
+
+pub type LocalShelfRef = Arc<LocalShelf>;
+
+// Shelf over a local directory tree. Readers never wait on a whole update: nodes are
+// locked one at a time, and updates are serialized among themselves.
 #[derive(Debug)]
-pub struct Shelf {
-    root: Node,
+pub struct LocalShelf {
+    tree: RwLock<Arc<Tree>>, // Swapped at once by refresh
     root_path: PathBuf,
+    config: ScanConfig,
+    state: RwLock<ShelfState>,
+    updating: Mutex<()>,
     // String = Workspace identifier + Global
 }
 
-impl Shelf {
-    pub fn new(path: PathBuf) -> Result<Self, io::Error> {
-        Ok(Shelf {
-            root: Node::new(path.clone())?,
+// Changes found by a refresh: the files gone, and the new or modified ones with their
+// current tags
+#[derive(Debug, Default)]
+pub struct Refresh {
+    pub removed: Vec<PathBuf>,
+    pub updated: Vec<(FileID, HashSet<TagRef>)>,
+}
+
+impl Refresh {
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.updated.is_empty()
+    }
+}
+
+#[derive(Debug, Default)]
+struct ShelfState {
+    version: u64, // Bumped on every change to the tag state
+    tag_versions: HashMap<TagRef, u64>, // Shelf version at the last change of each tag
+}
+
+// Nodes from root down to the directory at rel_path, loading the ones left unloaded
+fn walk(tree: &Tree, rel_path: &Path) -> Result<Vec<NodeRef>, UpdateErr> {
+    let mut chain = vec![tree.root.clone()];
+    let mut current = PathBuf::new();
+    for dir in rel_path.components() {
+        let dir: PathBuf = dir.as_os_str().into();
+        let child = {
+            let node = chain.last().unwrap().read().unwrap();
+            node.touch();
+            current.push(&dir);
+            node.directories
+                .get(&dir)
+                .cloned()
+                .ok_or_else(|| UpdateErr::PathNotFound(tree.path().join(&current)))?
+        };
+        chain.push(child);
+        tree.load(&chain, &current, false)
+            .map_err(|err| UpdateErr::io(tree.path().join(&current), err))?;
+    }
+    chain.last().unwrap().read().unwrap().touch();
+    Ok(chain)
+}
+
+// Nodes from root down to the directory at rel_path, None if one of them is not loaded
+fn loaded_chain(tree: &Tree, rel_path: &Path) -> Option<Vec<NodeRef>> {
+    let mut chain = vec![tree.root.clone()];
+    for dir in rel_path.components() {
+        let child = chain.last()?.read().unwrap().directories.get(Path::new(dir.as_os_str()))?.clone();
+        if !child.read().unwrap().loaded {
+            return None;
+        }
+        chain.push(child);
+    }
+    Some(chain)
+}
+
+impl LocalShelf {
+    pub fn new(path: PathBuf, config: ScanConfig) -> Result<Self, io::Error> {
+        LocalShelf::scan(path, config, Arc::default())
+    }
+
+    // Scan reported (and cancelled) through progress
+    pub fn scan(path: PathBuf, config: ScanConfig, progress: Arc<ScanProgress>) -> Result<Self, io::Error> {
+        let tree = Tree::scan(path.clone(), &config, progress)?;
+        Ok(LocalShelf {
+            state: RwLock::new(ShelfState::default()),
+            tree: RwLock::new(Arc::new(tree)),
             root_path: path,
+            config,
+            updating: Mutex::new(()),
         })
     }
 
-    pub async fn retrieve(&self, tag: TagRef) -> BTreeSet<FileRef> {
-        let mut res = self
-            .root
-            .tags
-            .get(&tag)
-            .cloned()
-            .unwrap_or_else(|| BTreeSet::<FileRef>::new());
-        let mut dres = self
-            .root
-            .dtag_files
-            .get(&tag)
-            .cloned()
-            .unwrap_or_else(|| BTreeSet::<FileRef>::new());
-        res.append(&mut dres);
-        res
+    fn tree(&self) -> Arc<Tree> {
+        self.tree.read().unwrap().clone()
     }
 
-    pub async fn refresh(&self) -> Result<bool, io::Error> {
-        todo!();
+    fn root(&self) -> NodeRef {
+        self.tree().root.clone()
     }
 
-    pub fn attach(&mut self, path: PathBuf, tag: TagRef) -> Result<bool, UpdateErr> {
-        let stripped_path = path
+    pub fn summary(&self, workspace_id: WorkspaceId) -> ShelfSummary {
+        let root = self.root();
+        let root = root.read().unwrap();
+        let state = self.state.read().unwrap();
+        // Root aggregates the tags and dtags of the whole shelf
+        let mut counts: HashMap<&TagRef, u64> = HashMap::new();
+        for (tag, files) in &root.tags {
+            *counts.entry(tag).or_default() += files.len();
+        }
+        for (dtag, count) in &root.dtag_counts {
+            *counts.entry(dtag).or_default() += count;
+        }
+        // The files of unloaded directories are all untagged
+        let untagged = root.untagged_ids().len() + root.unloaded;
+        ShelfSummary::new(workspace_id, counts.into_iter(), root.file_count, untagged, state.version)
+    }
+
+    // Current tags (direct and dtags) of the file at path, or of every file below the
+    // directory at path. The files of the directories left unloaded below it are read
+    // from the disk, untagged.
+    pub fn entries(&self, path: &Path) -> Result<Vec<(FileID, HashSet<TagRef>)>, UpdateErr> {
+        let rel_path = path
             .strip_prefix(&self.root_path)
-            .map_err(|_| UpdateErr::PathNotFound)?
-            .parent();
+            .map_err(|_| UpdateErr::PathNotFound(path.to_path_buf()))?;
+        let tree = self.tree();
+        if let Ok(chain) = walk(&tree, rel_path) {
+            let (node, ancestors) = chain.split_last().unwrap();
+            let (mut entries, mut unloaded) = (Vec::new(), Vec::new());
+            collect_entries(node, rel_path, inherited_dtags(ancestors), &mut entries, &mut unloaded);
+            for dir in unloaded {
+                entries.extend(
+                    tree.unloaded_files(&dir)
+                        .into_iter()
+                        .map(|file| (FileID::local(file.clone(), FileMetadata::new(&file)), HashSet::new())),
+                );
+            }
+            return Ok(entries);
+        }
+        let parent = walk(&tree, rel_path.parent().unwrap_or(Path::new("")))?;
+        let dtags = inherited_dtags(&parent);
+        let node = parent.last().unwrap().read().unwrap();
+        let file = node.files.get(path).ok_or_else(|| UpdateErr::FileNotFound(path.to_path_buf()))?;
+        Ok(vec![file_entry(file, &dtags, node.excluded_files.get(path))])
+    }
 
-        let mut node_v: Vec<(PathBuf, Node)> = Vec::new();
-        // Take ownership
-        let mut curr_node = std::mem::take(&mut self.root);
+    // Current entries of the directories loaded since the last call and still loaded, as
+    // their files (and metadata) are now known
+    pub fn take_loaded(&self) -> Vec<(FileID, HashSet<TagRef>)> {
+        let tree = self.tree();
+        let mut entries = Vec::new();
+        for dir in tree.take_loaded() {
+            let Some(chain) = loaded_chain(&tree, &dir) else {
+                continue;
+            };
+            let (node, ancestors) = chain.split_last().unwrap();
+            collect_entries(node, &dir, inherited_dtags(ancestors), &mut entries, &mut Vec::new());
+        }
+        entries
+    }
 
-        // if stripped_path is none, file must be self.root
-        if let Some(path) = stripped_path {
-            for dir in path.components() {
-                let dir: PathBuf = dir.as_os_str().into();
+    pub fn version(&self) -> u64 {
+        self.state.read().unwrap().version
+    }
 
-                let child = curr_node
-                    .directories
-                    .remove(&dir)
-                    .ok_or_else(|| UpdateErr::PathNotFound)?;
+    // Version of the shelf when tag last changed, 0 if it never did
+    pub fn tag_version(&self, tag: &TagRef) -> u64 {
+        self.state.read().unwrap().tag_versions.get(tag).cloned().unwrap_or(0)
+    }
 
-                // Store the current node (ownership moved)
-                node_v.push((dir.to_path_buf(), curr_node));
+    fn bump_version(&self, tag: &TagRef) {
+        let mut state = self.state.write().unwrap();
+        state.version += 1;
+        let version = state.version;
+        state.tag_versions.insert(tag.clone(), version);
+    }
 
-                // Move to the child node
-                curr_node = child;
-            }
-        }
+    pub fn retrieve(&self, tag: TagRef) -> Vec<FileRef> {
+        let tree = self.tree();
+        let scope = Scope {
+            node: tree.root.clone(),
+            inherited: HashSet::new(),
+            tree,
+        };
+        scope.files(&scope.tagged(&tag))
+    }
 
-        let file = curr_node
-            .files
-            .get(&path)
-            .ok_or_else(|| UpdateErr::FileNotFound)?;
-        let file = file.clone();
-        let res = file.file_ref.write().unwrap().attach(tag.clone());
+    // Directory at path, for queries restricted to the files below it. Lazy shelves
+    // load it whole first.
+    pub fn scope(&self, path: &Path) -> Result<Scope, UpdateErr> {
+        let rel_path = path
+            .strip_prefix(&self.root_path)
+            .map_err(|_| UpdateErr::PathNotFound(path.to_path_buf()))?;
+        let tree = self.tree();
+        let chain = walk(&tree, rel_path)?;
+        tree.load(&chain, rel_path, true).map_err(|err| UpdateErr::io(path.to_path_buf(), err))?;
+        let (node, ancestors) = chain.split_last().unwrap();
+        let mut inherited = inherited_dtags(ancestors);
+        inherited.retain(|dtag| !node.read().unwrap().excluded.contains(dtag));
+        Ok(Scope {
+            node: node.clone(),
+            inherited,
+            tree,
+        })
+    }
+
+    pub fn config(&self) -> &ScanConfig {
+        &self.config
+    }
 
-        for (pbuf, mut node) in node_v.into_iter().rev() {
-            if res {
-                node.attach(tag.clone(), file.clone());
+    // Paths missing from the tree may be left out on purpose
+    fn rejection(&self, path: &Path, err: UpdateErr) -> UpdateErr {
+        match err {
+            UpdateErr::PathNotFound(_) | UpdateErr::FileNotFound(_) if node::ignored(&self.root_path, &self.config, path) => {
+                UpdateErr::Ignored(path.to_path_buf())
             }
-            let child = std::mem::replace(&mut curr_node, node);
-            curr_node.directories.insert(pbuf, child);
+            err => err,
         }
+    }
 
-        self.root = curr_node;
-        Ok(res)
+    // Entries left out of the last scan, or of the directories loaded since
+    pub fn errors(&self) -> Vec<ScanError> {
+        self.tree().errors.lock().unwrap().clone()
     }
 
-    pub fn detach(&mut self, path: Option<PathBuf>, tag: TagRef) -> Result<bool, UpdateErr> {
-        match path {
-            Some(path) => {
-                let stripped_path = path
-                    .strip_prefix(&self.root_path)
-                    .map_err(|_| UpdateErr::PathNotFound)?
-                    .parent();
+    // Unload the idle directories of a lazy shelf, returning the number of files unloaded
+    pub fn evict(&self) -> u64 {
+        let _updating = self.updating.lock().unwrap();
+        self.tree().evict()
+    }
 
-                let mut node_v: Vec<(PathBuf, Node)> = Vec::new();
-                // Take ownership
-                let mut curr_node = std::mem::take(&mut self.root);
+    // Check the invariants of the tree, rebuilding the aggregates found wrong with repair
+    pub fn check(&self, repair: bool) -> Vec<Violation> {
+        let _updating = self.updating.lock().unwrap();
+        let violations = self.tree().check(repair);
+        if repair && !violations.is_empty() {
+            self.state.write().unwrap().version += 1;
+        }
+        violations
+    }
 
-                // if stripped_path is none, file must be self.root
-                if let Some(path) = stripped_path {
-                    for dir in path.components() {
-                        let dir: PathBuf = dir.as_os_str().into();
+    pub fn root_path(&self) -> &Path {
+        &self.root_path
+    }
 
-                        let child = curr_node
-                            .directories
-                            .remove(&dir)
-                            .ok_or_else(|| UpdateErr::PathNotFound)?;
+    // Tags of the files, dtags and exclusions of the shelf, by path relative to its root
+    fn tag_state(&self) -> rpc::ShelfTags {
+        let tree = self.tree();
+        let entry = |path: &Path, tag: &TagRef| rpc::PathTag {
+            path: path.strip_prefix(&self.root_path).unwrap_or(path).to_string_lossy().into_owned(),
+            tag: tag.id(),
+        };
+        let mut state = rpc::ShelfTags::default();
+        for file in tree.files.lock().unwrap().iter() {
+            let file = file.file_ref.read().unwrap();
+            state.tags.extend(file.tags().iter().map(|tag| entry(file.path(), tag)));
+        }
+        let mut dtags = Vec::new();
+        collect_dtags(&tree.root, &self.root_path, &mut dtags);
+        state.dtags = dtags.iter().map(|(path, dtag)| entry(path, dtag)).collect();
+        let mut exclusions = Vec::new();
+        collect_exclusions(&tree.root, &self.root_path, &mut exclusions);
+        state.exclusions = exclusions.iter().map(|(path, dtag)| entry(path, dtag)).collect();
+        state
+    }
 
-                        // Store the current node (ownership moved)
-                        node_v.push((dir.to_path_buf(), curr_node));
+    pub fn persist_tags(&self, path: &Path) -> io::Result<()> {
+        let _updating = self.updating.lock().unwrap();
+        write_atomic(path, &self.tag_state().encode_to_vec())
+    }
 
-                        // Move to the child node
-                        curr_node = child;
-                    }
+    // Tag the freshly scanned tree as persisted at path, returning the number of entries
+    // dropped because their file, directory or tag is gone
+    pub fn load_tags(&self, path: &Path) -> io::Result<usize> {
+        let state = match std::fs::read(path) {
+            Ok(buf) => rpc::ShelfTags::decode(&*buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err),
+        };
+        let _updating = self.updating.lock().unwrap();
+        let tree = self.tree();
+        let mut restored = HashSet::new();
+        let mut dropped = 0;
+        let mut restore = |entry: &rpc::PathTag, apply: &dyn Fn(&Path, TagRef) -> Result<bool, UpdateErr>| {
+            match tag::TagManager::get(entry.tag) {
+                Some(tag) if apply(&self.root_path.join(&entry.path), tag.clone()).is_ok() => {
+                    restored.insert(tag);
                 }
+                _ => dropped += 1,
+            }
+        };
+        for entry in &state.tags {
+            restore(entry, &|path, tag| self.attach_in(&tree, path, tag));
+        }
+        for entry in &state.dtags {
+            restore(entry, &|path, dtag| self.attach_dtag_in(&tree, path, dtag));
+        }
+        for entry in &state.exclusions {
+            restore(entry, &|path, dtag| self.exclude_in(&tree, path, dtag, true));
+        }
+        if !restored.is_empty() {
+            let mut state = self.state.write().unwrap();
+            state.version += 1;
+            let version = state.version;
+            for tag in restored {
+                state.tag_versions.insert(tag, version);
+            }
+        }
+        Ok(dropped)
+    }
+
+    // Rescan the directory tree, keeping the tags of the files and directories still
+    // there. The new tree is tagged before it replaces the current one. Lazy shelves
+    // load again the directories holding the files loaded in the current tree.
+    pub fn refresh(&self) -> Result<Refresh, io::Error> {
+        let _updating = self.updating.lock().unwrap();
+        let new_tree = Tree::new(self.root_path.clone(), &self.config)?;
+        let old_tree = self.tree();
+        let old_files: Vec<FileRef> = old_tree.files.lock().unwrap().iter().cloned().collect();
+        let mut delta = Refresh::default();
+        let mut kept = HashSet::new();
+        let mut modified = Vec::new();
+
+        for file in &old_files {
+            let file = file.file_ref.read().unwrap();
+            let Some(new) = self.file(&new_tree, file.path()) else {
+                delta.removed.push(file.path().clone());
+                continue;
+            };
+            kept.insert(file.path().clone());
+            if new.file_ref.read().unwrap().metadata().modified != file.metadata().modified {
+                modified.push(file.path().clone());
+            }
+            for tag in file.tags() {
+                let _ = self.attach_in(&new_tree, file.path(), tag.clone());
+            }
+        }
+        let mut dtags = Vec::new();
+        collect_dtags(&old_tree.root, &self.root_path, &mut dtags);
+        for (dir, dtag) in dtags {
+            let _ = self.attach_dtag_in(&new_tree, &dir, dtag);
+        }
+        let mut exclusions = Vec::new();
+        collect_exclusions(&old_tree.root, &self.root_path, &mut exclusions);
+        for (path, dtag) in exclusions {
+            let _ = self.exclude_in(&new_tree, &path, dtag, true);
+        }
+        let added = new_tree
+            .files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|file| file.file_ref.read().unwrap().path().clone())
+            .filter(|path| !kept.contains(path))
+            .collect::<Vec<_>>();
+        // Tags whose files change: those of the files gone or modified, as they were...
+        let mut affected = HashSet::new();
+        for path in delta.removed.iter().chain(&modified) {
+            for (_, tags) in self.entries(path).unwrap_or_default() {
+                affected.extend(tags);
+            }
+        }
+
+        *self.tree.write().unwrap() = Arc::new(new_tree);
+        for path in added.iter().chain(&modified) {
+            delta.updated.extend(self.entries(path).unwrap_or_default());
+        }
+        // ...and those of the new or modified files, as they are now
+        for (_, tags) in &delta.updated {
+            affected.extend(tags.iter().cloned());
+        }
+        if !delta.is_empty() {
+            let mut state = self.state.write().unwrap();
+            state.version += 1;
+            let version = state.version;
+            for tag in affected {
+                state.tag_versions.insert(tag, version);
+            }
+        }
+        Ok(delta)
+    }
+
+    // Whether the file at path is part of the shelf, not left out by its ignore rules
+    pub fn contains(&self, path: &Path) -> bool {
+        self.file(&self.tree(), path).is_some()
+    }
+
+    fn file(&self, tree: &Tree, path: &Path) -> Option<FileRef> {
+        let rel_path = path.strip_prefix(&self.root_path).ok()?;
+        let chain = walk(tree, rel_path.parent()?).ok()?;
+        let node = chain.last()?.read().unwrap();
+        node.files.get(path).cloned()
+    }
+
+    pub fn attach(&self, path: PathBuf, tag: TagRef) -> Result<bool, UpdateErr> {
+        let _updating = self.updating.lock().unwrap();
+        let res = self
+            .attach_in(&self.tree(), &path, tag.clone())
+            .map_err(|err| self.rejection(&path, err))?;
+        if res {
+            self.bump_version(&tag);
+        }
+        Ok(res)
+    }
 
-                let file = curr_node
+    fn attach_in(&self, tree: &Tree, path: &Path, tag: TagRef) -> Result<bool, UpdateErr> {
+        let rel_path = path
+            .strip_prefix(&self.root_path)
+            .map_err(|_| UpdateErr::PathNotFound(path.to_path_buf()))?;
+        let chain = walk(tree, rel_path.parent().unwrap_or(Path::new("")))?;
+        let file = chain
+            .last()
+            .unwrap()
+            .read()
+            .unwrap()
+            .files
+            .get(path)
+            .cloned()
+            .ok_or_else(|| UpdateErr::FileNotFound(path.to_path_buf()))?;
+        let res = file.file_ref.write().unwrap().attach(tag.clone());
+        if res {
+            for node in &chain {
+                node.write().unwrap().attach(tag.clone(), file.id);
+            }
+        }
+        Ok(res)
+    }
+
+    pub fn detach(&self, path: Option<PathBuf>, tag: TagRef) -> Result<bool, UpdateErr> {
+        let _updating = self.updating.lock().unwrap();
+        let tree = self.tree();
+        let root = &tree.root;
+        let res = match path {
+            Some(path) => {
+                let rel_path = path
+                    .strip_prefix(&self.root_path)
+                    .map_err(|_| UpdateErr::PathNotFound(path.to_path_buf()))?;
+                let chain = walk(&tree, rel_path.parent().unwrap_or(Path::new("")))?;
+                let file = chain
+                    .last()
+                    .unwrap()
+                    .read()
+                    .unwrap()
                     .files
                     .get(&path)
-                    .ok_or_else(|| UpdateErr::FileNotFound)?;
-                let file = file.clone();
-                let res = file.file_ref.write().unwrap().detach(tag.clone());
-                for (pbuf, mut node) in node_v.into_iter().rev() {
-                    if res {
-                        node.detach(tag.clone(), Some(file.clone()));
+                    .cloned()
+                    .ok_or_else(|| UpdateErr::FileNotFound(path.clone()))?;
+                let (res, untagged) = {
+                    let mut file = file.file_ref.write().unwrap();
+                    (file.detach(tag.clone()), file.tags().is_empty())
+                };
+                if res {
+                    for node in &chain {
+                        let mut node = node.write().unwrap();
+                        node.detach(tag.clone(), Some(file.id));
+                        if untagged {
+                            node.untagged.insert(file.id);
+                        }
                     }
-                    let child = std::mem::replace(&mut curr_node, node);
-                    curr_node.directories.insert(pbuf, child);
                 }
-
-                self.root = curr_node;
-                Ok(res)
+                res
             }
             None => {
-                fn recursive_detach(node: &mut Node, tag: TagRef) {
-                    node.detach(tag.clone(), None);
-
-                    for (_, node) in node.directories.iter_mut() {
-                        recursive_detach(node, tag.clone());
+                // untagged: files left without tags
+                fn recursive_detach(node: &NodeRef, tag: TagRef, untagged: &RoaringBitmap) {
+                    let children: Vec<NodeRef> = {
+                        let mut node = node.write().unwrap();
+                        let files = node.tags.get(&tag).map(|files| files & untagged);
+                        node.untagged |= files.unwrap_or_default();
+                        node.detach(tag.clone(), None);
+                        node.directories.values().cloned().collect()
+                    };
+                    for child in children {
+                        recursive_detach(&child, tag.clone(), untagged);
                     }
                 }
 
-                let res = self.root.tags.get(&tag).is_some();
-
                 // Detach tag from every single (tagged) file in the Shelf
-                self.root.tags.get_mut(&tag).map(|set| {
-                    set.iter().for_each(|file| {
-                        file.file_ref.write().unwrap().detach(tag.clone());
-                    });
-                });
+                let files = root.read().unwrap().tags.get(&tag).cloned();
+                let mut untagged = RoaringBitmap::new();
+                if let Some(files) = &files {
+                    for file in &tree.files(files) {
+                        let mut file_ref = file.file_ref.write().unwrap();
+                        file_ref.detach(tag.clone());
+                        if file_ref.tags().is_empty() {
+                            untagged.insert(file.id);
+                        }
+                    }
+                }
                 // Delete the tag from every Node in the Shelf
-                recursive_detach(&mut self.root, tag.clone());
-
-                Ok(res)
+                recursive_detach(root, tag.clone(), &untagged);
+                files.is_some()
             }
+        };
+        if res {
+            self.bump_version(&tag);
+        }
+        Ok(res)
+    }
+
+    pub fn attach_dtag(&self, path: PathBuf, dtag: TagRef) -> Result<bool, UpdateErr> {
+        let _updating = self.updating.lock().unwrap();
+        let res = self
+            .attach_dtag_in(&self.tree(), &path, dtag.clone())
+            .map_err(|err| self.rejection(&path, err))?;
+        if res {
+            self.bump_version(&dtag);
         }
+        Ok(res)
     }
 
-    pub fn attach_dtag(&mut self, path: PathBuf, dtag: TagRef) -> Result<bool, UpdateErr> {
+    // Only the dtagged node holds the dtag, its ancestors count the files below it. In
+    // lazy shelves the whole directory is loaded first, so that the count holds.
+    fn attach_dtag_in(&self, tree: &Tree, path: &Path, dtag: TagRef) -> Result<bool, UpdateErr> {
         let dpath = path
             .strip_prefix(&self.root_path)
-            .map_err(|_| UpdateErr::PathNotFound)?;
-
-        let mut dtagged_parent = false;
-        let mut curr_node = &mut self.root;
-        for dir in dpath.components() {
-            let dir: PathBuf = dir.as_os_str().into();
-            let child = curr_node
-                .directories
-                .get_mut(&dir)
-                .ok_or_else(|| UpdateErr::PathNotFound)?;
-            if child.dtags.contains(&dtag) {
-                dtagged_parent = true;
+            .map_err(|_| UpdateErr::PathNotFound(path.to_path_buf()))?;
+        let chain = walk(tree, dpath)?;
+        tree.load(&chain, dpath, true).map_err(|err| UpdateErr::io(path.to_path_buf(), err))?;
+        let (res, count) = {
+            let mut target = chain.last().unwrap().write().unwrap();
+            (target.attach_dtag(dtag.clone()), target.file_count)
+        };
+        if res {
+            for node in &chain {
+                node.write().unwrap().count_dtag(dtag.clone(), true, count);
             }
-            curr_node = child;
         }
-        if dtagged_parent {
-            return Ok(curr_node.attach_dtag(dtag.clone()));
+        Ok(res)
+    }
+
+    pub fn detach_dtag(&self, path: PathBuf, dtag: TagRef) -> Result<bool, UpdateErr> {
+        let _updating = self.updating.lock().unwrap();
+        let res = self
+            .detach_dtag_in(&self.tree(), &path, dtag.clone())
+            .map_err(|err| self.rejection(&path, err))?;
+        if res {
+            self.bump_version(&dtag);
         }
+        Ok(res)
+    }
 
-        let mut node_v: Vec<(PathBuf, Node)> = Vec::new();
-        // Take ownership
-        let mut curr_node = std::mem::take(&mut self.root);
+    // Same as attach_dtag_in, the count taken off the ancestors being the one they got
+    fn detach_dtag_in(&self, tree: &Tree, path: &Path, dtag: TagRef) -> Result<bool, UpdateErr> {
+        let dpath = path
+            .strip_prefix(&self.root_path)
+            .map_err(|_| UpdateErr::PathNotFound(path.to_path_buf()))?;
+        let chain = walk(tree, dpath)?;
+        tree.load(&chain, dpath, true).map_err(|err| UpdateErr::io(path.to_path_buf(), err))?;
+        let (res, count) = {
+            let mut target = chain.last().unwrap().write().unwrap();
+            (target.detach_dtag(dtag.clone()), target.file_count)
+        };
+        if res {
+            for node in &chain {
+                node.write().unwrap().count_dtag(dtag.clone(), false, count);
+            }
+        }
+        Ok(res)
+    }
 
-        // if dpath is none, dir must be self.root
-        for dir in dpath.components() {
-            let dir: PathBuf = dir.as_os_str().into();
-            let child = curr_node
-                .directories
-                .remove(&dir)
-                .ok_or_else(|| UpdateErr::PathNotFound)?;
-            // Store the current node (ownership moved)
-            node_v.push((dir.to_path_buf(), curr_node));
-            // Move to the child node
-            curr_node = child;
+    pub fn exclude(&self, path: PathBuf, dtag: TagRef) -> Result<bool, UpdateErr> {
+        let _updating = self.updating.lock().unwrap();
+        let res = self
+            .exclude_in(&self.tree(), &path, dtag.clone(), true)
+            .map_err(|err| self.rejection(&path, err))?;
+        if res {
+            self.bump_version(&dtag);
         }
+        Ok(res)
+    }
 
-        let res = curr_node.attach_dtag(dtag.clone());
+    pub fn include(&self, path: PathBuf, dtag: TagRef) -> Result<bool, UpdateErr> {
+        let _updating = self.updating.lock().unwrap();
+        let res = self.exclude_in(&self.tree(), &path, dtag.clone(), false)?;
+        if res {
+            self.bump_version(&dtag);
+        }
+        Ok(res)
+    }
 
-        fn recursive_attach(node: &mut Node, dtag: TagRef) -> BTreeSet<FileRef> {
-            let mut files = node.files.values().cloned().collect::<BTreeSet<FileRef>>();
-            let mut subdir_files = BTreeSet::new();
-            for (_, subnode) in node.directories.iter_mut() {
-                let mut sub_files = recursive_attach(subnode, dtag.clone());
-                subdir_files.append(&mut sub_files);
+    // The directory or file at path stops (or starts again) inheriting dtag from the
+    // directories above it. Its ancestors count the exclusions below them.
+    fn exclude_in(&self, tree: &Tree, path: &Path, dtag: TagRef, excluded: bool) -> Result<bool, UpdateErr> {
+        let rel_path = path
+            .strip_prefix(&self.root_path)
+            .map_err(|_| UpdateErr::PathNotFound(path.to_path_buf()))?;
+        let (res, chain) = match walk(tree, rel_path) {
+            Ok(chain) => {
+                let mut target = chain.last().unwrap().write().unwrap();
+                let res = match excluded {
+                    true => target.excluded.insert(dtag.clone()),
+                    false => target.excluded.remove(&dtag),
+                };
+                drop(target);
+                (res, chain)
             }
-            files.append(&mut subdir_files);
-
-            fn add_dtag_files(node: &mut Node, dtag: TagRef, files: BTreeSet<FileRef>) {
-                let set = node
-                    .dtag_files
-                    .entry(dtag)
-                    .or_insert_with(|| BTreeSet::new());
-                files.iter().for_each(|f| {
-                    set.insert(f.clone());
-                });
+            Err(_) => {
+                let chain = walk(tree, rel_path.parent().unwrap_or(Path::new("")))?;
+                let mut node = chain.last().unwrap().write().unwrap();
+                if !node.files.contains_key(path) {
+                    return Err(UpdateErr::FileNotFound(path.to_path_buf()));
+                }
+                let dtags = node.excluded_files.entry(path.to_path_buf()).or_default();
+                let res = match excluded {
+                    true => dtags.insert(dtag.clone()),
+                    false => dtags.remove(&dtag),
+                };
+                if dtags.is_empty() {
+                    node.excluded_files.remove(path);
+                }
+                drop(node);
+                (res, chain)
+            }
+        };
+        if res {
+            for node in &chain {
+                node.write().unwrap().count_exclusion(dtag.clone(), excluded);
             }
+        }
+        Ok(res)
+    }
+}
+
+// Directory of a shelf, answering from its own aggregates. Ids refer to the tree it
+// was found in, even if the shelf is refreshed meanwhile.
+pub struct Scope {
+    tree: Arc<Tree>,
+    node: NodeRef,
+    inherited: HashSet<TagRef>, // dtags of the directories above it, not excluded from it
+}
 
-            add_dtag_files(node, dtag, files.clone());
-            return files;
+impl Scope {
+    // Files below the directory holding tag, directly or through a dtag
+    pub fn tagged(&self, tag: &TagRef) -> RoaringBitmap {
+        let node = self.node.read().unwrap();
+        let mut ids = node.tags.get(tag).cloned().unwrap_or_default();
+        if self.inherited.contains(tag) {
+            ids |= node.covered_ids(tag);
+        } else if node.dtag_counts.contains_key(tag) {
+            ids |= node.dtag_file_ids(tag);
         }
+        ids
+    }
 
-        let files = recursive_attach(&mut curr_node, dtag.clone());
+    pub fn all(&self) -> RoaringBitmap {
+        self.node.read().unwrap().file_ids()
+    }
 
-        for (pbuf, mut node) in node_v.into_iter().rev() {
-            let set = node
-                .dtag_files
-                .entry(dtag.clone())
-                .or_insert_with(|| BTreeSet::new());
-            set.append(&mut files.clone());
-            let child = std::mem::replace(&mut curr_node, node);
-            curr_node.directories.insert(pbuf, child);
+    // Files below the directory with neither tags nor dtags
+    pub fn untagged(&self) -> RoaringBitmap {
+        let node = self.node.read().unwrap();
+        let mut ids = node.untagged_ids();
+        for dtag in &self.inherited {
+            ids -= node.covered_ids(dtag);
         }
+        ids
+    }
 
-        files.iter().for_each(|f| {
-            f.file_ref.write().unwrap().attach_dtag(dtag.clone());
-        });
+    pub fn files(&self, ids: &RoaringBitmap) -> Vec<FileRef> {
+        self.tree.files(ids)
+    }
+}
 
-        Ok(res)
+// dtags the directories of chain pass down, each directory dropping the ones excluded
+// from it before adding its own
+fn inherited_dtags(chain: &[NodeRef]) -> HashSet<TagRef> {
+    let mut dtags = HashSet::new();
+    for node in chain {
+        let node = node.read().unwrap();
+        dtags.retain(|dtag| !node.excluded.contains(dtag));
+        dtags.extend(node.dtags.iter().cloned());
     }
+    dtags
+}
 
-    pub fn detach_dtag(&mut self, path: PathBuf, dtag: TagRef) -> Result<bool, UpdateErr> {
-        let dpath = path
-            .strip_prefix(&self.root_path)
-            .map_err(|_| UpdateErr::PathNotFound)?;
-
-        let mut dtagged_parent = false;
-        let mut curr_node = &mut self.root;
-        for dir in dpath.components() {
-            let dir: PathBuf = dir.as_os_str().into();
-            let child = curr_node
-                .directories
-                .get_mut(&dir)
-                .ok_or_else(|| UpdateErr::PathNotFound)?;
-            if child.dtags.contains(&dtag) {
-                dtagged_parent = true;
+// unloaded gets the directories left unloaded, relative to the root
+fn collect_entries(
+    node: &NodeRef,
+    rel_path: &Path,
+    mut dtags: HashSet<TagRef>,
+    acc: &mut Vec<(FileID, HashSet<TagRef>)>,
+    unloaded: &mut Vec<PathBuf>,
+) {
+    let node = node.read().unwrap();
+    if !node.loaded {
+        unloaded.push(rel_path.to_path_buf());
+        return;
+    }
+    dtags.retain(|dtag| !node.excluded.contains(dtag));
+    dtags.extend(node.dtags.iter().cloned());
+    acc.extend(
+        node.files
+            .iter()
+            .map(|(path, file)| file_entry(file, &dtags, node.excluded_files.get(path))),
+    );
+    for (dir, child) in &node.directories {
+        collect_entries(child, &rel_path.join(dir), dtags.clone(), acc, unloaded);
+    }
+}
+
+// Directories and files of the tree rooted at path excluded from dtags, with those dtags
+fn collect_exclusions(node: &NodeRef, path: &Path, acc: &mut Vec<(PathBuf, TagRef)>) {
+    let node = node.read().unwrap();
+    if node.exclusion_counts.is_empty() {
+        return;
+    }
+    acc.extend(node.excluded.iter().map(|dtag| (path.to_path_buf(), dtag.clone())));
+    for (file, dtags) in &node.excluded_files {
+        acc.extend(dtags.iter().map(|dtag| (file.clone(), dtag.clone())));
+    }
+    for (dir, child) in &node.directories {
+        collect_exclusions(child, &path.join(dir), acc);
+    }
+}
+
+// Directories of the tree rooted at path holding dtags, with those dtags
+fn collect_dtags(node: &NodeRef, path: &Path, acc: &mut Vec<(PathBuf, TagRef)>) {
+    let node = node.read().unwrap();
+    acc.extend(node.dtags.iter().map(|dtag| (path.to_path_buf(), dtag.clone())));
+    for (dir, child) in &node.directories {
+        collect_dtags(child, &path.join(dir), acc);
+    }
+}
+
+// Tree updates take std locks and may read directories, so they run on the blocking pool
+fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T, UpdateErr> + Send + 'static) -> ShelfFuture<'static, T> {
+    Box::pin(async move { tokio::task::spawn_blocking(f).await.map_err(|_| UpdateErr::Cancelled)? })
+}
+
+impl Shelf for LocalShelf {
+    fn retrieve(self: Arc<Self>, tag: TagRef) -> ShelfFuture<'static, Vec<FileID>> {
+        blocking(move || {
+            Ok(LocalShelf::retrieve(&self, tag)
+                .iter()
+                .map(|file| {
+                    let file = file.file_ref.read().unwrap();
+                    FileID::local(file.path().clone(), file.metadata().clone())
+                })
+                .collect())
+        })
+    }
+
+    fn attach(self: Arc<Self>, path: PathBuf, tag: TagRef) -> ShelfFuture<'static, bool> {
+        blocking(move || LocalShelf::attach(&self, self.root_path.join(path), tag))
+    }
+
+    fn detach(self: Arc<Self>, path: Option<PathBuf>, tag: TagRef) -> ShelfFuture<'static, bool> {
+        blocking(move || LocalShelf::detach(&self, path.map(|path| self.root_path.join(path)), tag))
+    }
+
+    fn attach_dtag(self: Arc<Self>, path: PathBuf, dtag: TagRef) -> ShelfFuture<'static, bool> {
+        blocking(move || LocalShelf::attach_dtag(&self, self.root_path.join(path), dtag))
+    }
+
+    fn detach_dtag(self: Arc<Self>, path: PathBuf, dtag: TagRef) -> ShelfFuture<'static, bool> {
+        blocking(move || LocalShelf::detach_dtag(&self, self.root_path.join(path), dtag))
+    }
+
+    fn exclude(self: Arc<Self>, path: PathBuf, dtag: TagRef) -> ShelfFuture<'static, bool> {
+        blocking(move || LocalShelf::exclude(&self, self.root_path.join(path), dtag))
+    }
+
+    fn include(self: Arc<Self>, path: PathBuf, dtag: TagRef) -> ShelfFuture<'static, bool> {
+        blocking(move || LocalShelf::include(&self, self.root_path.join(path), dtag))
+    }
+
+    fn refresh(self: Arc<Self>) -> ShelfFuture<'static, bool> {
+        blocking(move || {
+            LocalShelf::refresh(&self)
+                .map(|delta| !delta.is_empty())
+                .map_err(|err| UpdateErr::io(self.root_path.clone(), err))
+        })
+    }
+}
+
+// excluded: dtags the file does not inherit
+fn file_entry(file: &FileRef, dtags: &HashSet<TagRef>, excluded: Option<&HashSet<TagRef>>) -> (FileID, HashSet<TagRef>) {
+    let file = file.file_ref.read().unwrap();
+    let inherited = dtags.iter().filter(|dtag| !excluded.is_some_and(|excluded| excluded.contains(*dtag)));
+    let tags = file.tags().iter().chain(inherited).cloned().collect();
+    (FileID::local(file.path().clone(), file.metadata().clone()), tags)
+}
+
+// Failed operation on a shelf, with the path, tag or peer it is about
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateErr {
+    PathNotFound(PathBuf),
+    FileNotFound(PathBuf),
+    UnknownTag(String),
+    WorkspaceNotFound(WorkspaceId),
+    Ignored(PathBuf), // The path is left out of the shelf by its ignore rules
+    PermissionDenied(PathBuf),
+    Io(PathBuf, io::ErrorKind),
+    Conflict { path: PathBuf, tag: String }, // A concurrent operation on the same tag and path won
+    Unreachable(NodeId), // The peer owning the shelf could not be reached
+    Cancelled,
+    TimedOut, // Still running, it may complete later
+}
+
+impl UpdateErr {
+    // Directory at path that could not be read
+    pub fn io(path: PathBuf, err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => UpdateErr::PathNotFound(path),
+            io::ErrorKind::PermissionDenied => UpdateErr::PermissionDenied(path),
+            io::ErrorKind::Interrupted => UpdateErr::Cancelled,
+            kind => UpdateErr::Io(path, kind),
+        }
+    }
+}
+
+impl fmt::Display for UpdateErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdateErr::PathNotFound(path) => write!(f, "no directory at {}", path.display()),
+            UpdateErr::FileNotFound(path) => write!(f, "no file at {}", path.display()),
+            UpdateErr::UnknownTag(name) => write!(f, "unknown tag \"{}\"", name),
+            UpdateErr::WorkspaceNotFound(id) => write!(f, "unknown workspace {}", id),
+            UpdateErr::Ignored(path) => write!(f, "{} is left out by the ignore rules", path.display()),
+            UpdateErr::PermissionDenied(path) => write!(f, "permission denied on {}", path.display()),
+            UpdateErr::Io(path, kind) => write!(f, "could not access {}: {}", path.display(), kind),
+            UpdateErr::Conflict { path, tag } => {
+                write!(f, "a concurrent operation on \"{}\" at {} won", tag, path.display())
             }
-            curr_node = child;
+            UpdateErr::Unreachable(node) => write!(f, "peer {} is unreachable", node),
+            UpdateErr::Cancelled => write!(f, "cancelled"),
+            UpdateErr::TimedOut => write!(f, "timed out, the operation may still complete"),
         }
-        if dtagged_parent {
-            return Ok(curr_node.detach_dtag(dtag.clone()));
+    }
+}
+
+impl From<UpdateErr> for rpc::Error {
+    fn from(err: UpdateErr) -> Self {
+        let error = rpc::Error {
+            message: err.to_string(),
+            ..Default::default()
+        };
+        let path = |code: rpc::ErrorCode, path: PathBuf| rpc::Error {
+            code: code as i32,
+            path: path.to_string_lossy().into_owned(),
+            ..error.clone()
+        };
+        match err {
+            UpdateErr::PathNotFound(p) => path(rpc::ErrorCode::PathNotFound, p),
+            UpdateErr::FileNotFound(p) => path(rpc::ErrorCode::FileNotFound, p),
+            UpdateErr::Ignored(p) => path(rpc::ErrorCode::Ignored, p),
+            UpdateErr::PermissionDenied(p) => path(rpc::ErrorCode::PermissionDenied, p),
+            UpdateErr::Io(p, _) => path(rpc::ErrorCode::Io, p),
+            UpdateErr::Conflict { path: p, tag } => rpc::Error {
+                tag,
+                ..path(rpc::ErrorCode::ConcurrentEdit, p)
+            },
+            UpdateErr::UnknownTag(tag) => rpc::Error {
+                code: rpc::ErrorCode::UnknownTag as i32,
+                tag,
+                ..error
+            },
+            UpdateErr::WorkspaceNotFound(id) => rpc::Error {
+                code: rpc::ErrorCode::WorkspaceNotFound as i32,
+                workspace_id: id,
+                ..error
+            },
+            UpdateErr::Unreachable(node) => rpc::Error {
+                code: rpc::ErrorCode::Unreachable as i32,
+                node: node.as_bytes().to_vec(),
+                ..error
+            },
+            UpdateErr::Cancelled => rpc::Error {
+                code: rpc::ErrorCode::Cancelled as i32,
+                ..error
+            },
+            UpdateErr::TimedOut => rpc::Error {
+                code: rpc::ErrorCode::TimedOut as i32,
+                ..error
+            },
         }
+    }
+}
 
-        let mut node_v: Vec<(PathBuf, Node)> = Vec::new();
-        // Take ownership
-        let mut curr_node = std::mem::take(&mut self.root);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tag::TagManager;
 
-        // if dpath is none, dir must be self.root
-        for dir in dpath.components() {
-            let dir: PathBuf = dir.as_os_str().into();
-            let child = curr_node
-                .directories
-                .remove(&dir)
-                .ok_or_else(|| UpdateErr::PathNotFound)?;
-            // Store the current node (ownership moved)
-            node_v.push((dir.to_path_buf(), curr_node));
-            // Move to the child node
-            curr_node = child;
+    // Directory under the temporary one holding the given files, empty
+    fn tree(paths: &[&str]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("ebi-shelf-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&root).unwrap();
+        for path in paths {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
         }
+        std::fs::canonicalize(root).unwrap()
+    }
 
-        let res = curr_node.detach_dtag(dtag.clone());
+    fn tag(name: &str) -> TagRef {
+        TagManager::get_or_create(rand::random::<u64>().max(1), name).unwrap()
+    }
 
-        fn recursive_detach(node: &mut Node, dtag: TagRef) -> BTreeSet<FileRef> {
-            // Stop detaching the dtag when encountering a child node already dtagged with it
-            if node.dtags.contains(&dtag) {
-                return BTreeSet::new();
-            }
+    // Paths of files relative to root, sorted
+    fn names(root: &Path, files: &[FileRef]) -> Vec<String> {
+        let mut names: Vec<_> = files
+            .iter()
+            .map(|file| {
+                let file = file.file_ref.read().unwrap();
+                file.path().strip_prefix(root).unwrap().to_string_lossy().into_owned()
+            })
+            .collect();
+        names.sort();
+        names
+    }
 
-            let mut files = node.files.values().cloned().collect::<BTreeSet<FileRef>>();
-            let mut subdir_files = BTreeSet::new();
-            for (_, subnode) in node.directories.iter_mut() {
-                let mut sub_files = recursive_detach(subnode, dtag.clone());
-                subdir_files.append(&mut sub_files);
-            }
-            files.append(&mut subdir_files);
-
-            fn remove_dtag_files(node: &mut Node, dtag: TagRef, files: BTreeSet<FileRef>) {
-                let set = node.dtag_files.get_mut(&dtag);
-                match set {
-                    Some(set) => {
-                        files.iter().for_each(|f| {
-                            set.remove(f);
-                        });
-                        if set.is_empty() {
-                            node.dtag_files.remove(&dtag);
-                        }
+    #[test]
+    fn shelves_are_shared_across_threads() {
+        fn shared<T: Send + Sync>() {}
+        shared::<LocalShelf>();
+        shared::<Scope>();
+
+        let paths: Vec<String> = (0..50).map(|i| format!("d{}/f{}", i % 5, i)).collect();
+        let root = tree(&paths.iter().map(String::as_str).collect::<Vec<_>>());
+        let shelf = LocalShelf::new(root.clone(), ScanConfig::default()).unwrap();
+        let foo = tag("foo");
+        // Readers only ever see whole attaches, each file tagged at most once
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut seen = 0;
+                    while seen < paths.len() {
+                        let files = shelf.retrieve(foo.clone());
+                        assert!(files.len() >= seen);
+                        seen = files.len();
+                        assert_eq!(names(&root, &files).len(), seen);
                     }
-                    None => (), //[!] Critical Internal Error
-                }
+                });
             }
+            for path in &paths {
+                assert!(shelf.attach(root.join(path), foo.clone()).unwrap());
+            }
+        });
+        assert_eq!(shelf.summary(1).untagged, 0);
+        assert_eq!(shelf.version(), paths.len() as u64);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 
-            remove_dtag_files(node, dtag, files.clone());
-            return files;
-        }
+    #[test]
+    fn dtags_reach_the_files_below_without_being_copied() {
+        let root = tree(&["top", "a/x", "a/b/y", "a/b/c/z"]);
+        let shelf = LocalShelf::new(root.clone(), ScanConfig::default()).unwrap();
+        let foo = tag("foo");
+        assert!(shelf.attach_dtag(root.join("a"), foo.clone()).unwrap());
+        assert!(!shelf.attach_dtag(root.join("a"), foo.clone()).unwrap());
+        assert_eq!(names(&root, &shelf.retrieve(foo.clone())), ["a/b/c/z", "a/b/y", "a/x"]);
+        // Only the dtagged node holds the dtag, the files keep their own tags
+        let file = shelf.file(&shelf.tree(), &root.join("a/b/y")).unwrap();
+        assert!(file.file_ref.read().unwrap().tags().is_empty());
+        assert_eq!(shelf.summary(1).counts[&foo.name()], 3);
+        let (_, tags) = &shelf.entries(&root.join("a/b/c/z")).unwrap()[0];
+        assert!(tags.contains(&foo));
 
-        let files = recursive_detach(&mut curr_node, dtag.clone());
+        // Files reached twice are listed once
+        assert!(shelf.attach_dtag(root.join("a/b"), foo.clone()).unwrap());
+        assert!(shelf.attach(root.join("a/b/y"), foo.clone()).unwrap());
+        assert!(shelf.attach(root.join("top"), foo.clone()).unwrap());
+        assert_eq!(names(&root, &shelf.retrieve(foo.clone())), ["a/b/c/z", "a/b/y", "a/x", "top"]);
+        assert!(shelf.detach_dtag(root.join("a"), foo.clone()).unwrap());
+        assert_eq!(names(&root, &shelf.retrieve(foo.clone())), ["a/b/c/z", "a/b/y", "top"]);
+        assert!(shelf.detach_dtag(root.join("a/b"), foo.clone()).unwrap());
+        assert_eq!(names(&root, &shelf.retrieve(foo.clone())), ["a/b/y", "top"]);
+        assert!(shelf.check(false).is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 
-        for (pbuf, mut node) in node_v.into_iter().rev() {
-            let set = node.dtag_files.get_mut(&dtag.clone());
-            match set {
-                Some(set) => {
-                    files.iter().for_each(|f| {
-                        set.remove(f);
-                    });
-                    let child = std::mem::replace(&mut curr_node, node);
-                    curr_node.directories.insert(pbuf, child);
-                }
-                None => (), //[!] Internal Error
-            }
-        }
+    #[test]
+    fn ignored_paths_are_rejected_as_such() {
+        let root = tree(&["a.txt", "a.log"]);
+        let config = ScanConfig {
+            ignore: vec!["*.log".to_string()],
+            ..Default::default()
+        };
+        let shelf = LocalShelf::new(root.clone(), config).unwrap();
+        let foo = tag("foo");
+        assert!(matches!(shelf.attach(root.join("a.log"), foo.clone()), Err(UpdateErr::Ignored(path)) if path == root.join("a.log")));
+        assert!(matches!(shelf.attach(root.join("b.txt"), foo.clone()), Err(UpdateErr::PathNotFound(_) | UpdateErr::FileNotFound(_))));
+        assert!(shelf.attach(root.join("a.txt"), foo).unwrap());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 
-        files.iter().for_each(|f| {
-            f.file_ref.write().unwrap().detach_dtag(dtag.clone());
-        });
+    #[test]
+    fn lazy_shelves_load_on_access_and_evict_idle_directories() {
+        let root = tree(&["top", "a/x", "a/y", "b/c/z"]);
+        let config = ScanConfig {
+            loaded_depth: Some(1),
+            evict_after: std::time::Duration::ZERO,
+            ..Default::default()
+        };
+        let shelf = LocalShelf::new(root.clone(), config).unwrap();
+        assert_eq!(shelf.tree().files.lock().unwrap().len(), 1);
+        let summary = shelf.summary(1);
+        assert_eq!((summary.file_count, summary.untagged), (4, 4));
+        let listed: Vec<_> = shelf.entries(&root).unwrap().into_iter().map(|(file, _)| file.path().clone()).collect();
+        assert_eq!(listed.len(), 4);
+        assert!(listed.contains(&root.join("b/c/z")));
 
-        Ok(res)
+        let foo = tag("foo");
+        assert!(shelf.attach(root.join("a/x"), foo.clone()).unwrap());
+        let mut loaded: Vec<_> = shelf.take_loaded().into_iter().map(|(file, tags)| (file.path().clone(), tags.len())).collect();
+        loaded.sort();
+        assert_eq!(loaded, [(root.join("a/x"), 1), (root.join("a/y"), 0)]);
+        assert!(shelf.take_loaded().is_empty());
+        assert_eq!(shelf.summary(1).untagged, 3);
+
+        // Scopes load their directory whole, which is unloaded again once idle
+        assert_eq!(shelf.scope(&root.join("b")).unwrap().all().len(), 1);
+        assert_eq!(shelf.evict(), 1);
+        assert!(shelf.detach(Some(root.join("a/x")), foo.clone()).unwrap());
+        assert_eq!(shelf.evict(), 2);
+        assert_eq!(shelf.tree().files.lock().unwrap().len(), 1);
+        let summary = shelf.summary(1);
+        assert_eq!((summary.file_count, summary.untagged), (4, 4));
+        assert!(shelf.retrieve(foo).is_empty());
+        assert!(shelf.check(false).is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
     }
-}
 
-// [TODO]: define extensive errors
-#[derive(Debug)]
-pub enum UpdateErr {
-    PathNotFound,
-    FileNotFound,
+    #[test]
+    fn scopes_restrict_queries_to_a_directory() {
+        let root = tree(&["top", "a/x", "a/b/y", "a/b/z", "c/w"]);
+        let shelf = LocalShelf::new(root.clone(), ScanConfig::default()).unwrap();
+        let (foo, bar) = (tag("foo"), tag("bar"));
+        assert!(shelf.attach(root.join("top"), foo.clone()).unwrap());
+        assert!(shelf.attach(root.join("a/b/y"), foo.clone()).unwrap());
+        assert!(shelf.attach(root.join("c/w"), bar.clone()).unwrap());
+        assert!(shelf.attach_dtag(root.join("a"), bar.clone()).unwrap());
+
+        let scope = shelf.scope(&root.join("a/b")).unwrap();
+        assert_eq!(names(&root, &scope.files(&scope.all())), ["a/b/y", "a/b/z"]);
+        assert_eq!(names(&root, &scope.files(&scope.tagged(&foo))), ["a/b/y"]);
+        // The dtag of a reaches the files of the scope from above
+        assert_eq!(names(&root, &scope.files(&scope.tagged(&bar))), ["a/b/y", "a/b/z"]);
+        assert!(scope.untagged().is_empty());
+
+        let scope = shelf.scope(&root).unwrap();
+        assert_eq!(scope.all().len(), 5);
+        assert_eq!(names(&root, &scope.files(&scope.tagged(&foo))), ["a/b/y", "top"]);
+        assert_eq!(names(&root, &scope.files(&scope.tagged(&bar))), ["a/b/y", "a/b/z", "a/x", "c/w"]);
+        assert!(scope.untagged().is_empty());
+        assert!(matches!(shelf.scope(&root.join("d")), Err(UpdateErr::PathNotFound(_))));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn untagged_files_have_neither_tags_nor_dtags() {
+        let root = tree(&["top", "a/x", "a/b/y"]);
+        let shelf = LocalShelf::new(root.clone(), ScanConfig::default()).unwrap();
+        let foo = tag("foo");
+        let untagged = |path: &Path| {
+            let scope = shelf.scope(path).unwrap();
+            names(&root, &scope.files(&scope.untagged()))
+        };
+        assert_eq!(untagged(&root), ["a/b/y", "a/x", "top"]);
+        assert!(shelf.attach(root.join("top"), foo.clone()).unwrap());
+        assert_eq!(untagged(&root), ["a/b/y", "a/x"]);
+        assert!(shelf.attach_dtag(root.join("a/b"), foo.clone()).unwrap());
+        assert_eq!(untagged(&root), ["a/x"]);
+        assert!(untagged(&root.join("a/b")).is_empty());
+        assert_eq!(shelf.summary(1).untagged, 1);
+
+        assert!(shelf.detach_dtag(root.join("a/b"), foo.clone()).unwrap());
+        assert!(shelf.detach(Some(root.join("top")), foo).unwrap());
+        assert_eq!(untagged(&root), ["a/b/y", "a/x", "top"]);
+        assert_eq!(shelf.summary(1).untagged, 3);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn exclusions_stop_dtags_from_passing_down() {
+        let root = tree(&["a/x", "a/y", "a/b/z", "a/b/c/w"]);
+        let shelf = LocalShelf::new(root.clone(), ScanConfig::default()).unwrap();
+        let foo = tag("foo");
+        assert!(shelf.attach_dtag(root.join("a"), foo.clone()).unwrap());
+        assert!(shelf.exclude(root.join("a/b"), foo.clone()).unwrap());
+        assert!(!shelf.exclude(root.join("a/b"), foo.clone()).unwrap());
+        assert!(shelf.exclude(root.join("a/y"), foo.clone()).unwrap());
+        assert_eq!(names(&root, &shelf.retrieve(foo.clone())), ["a/x"]);
+        let has_foo = |path: &str| shelf.entries(&root.join(path)).unwrap().iter().all(|(_, tags)| tags.contains(&foo));
+        assert!(has_foo("a/x"));
+        assert!(!has_foo("a/y"));
+        assert!(shelf.entries(&root.join("a/b")).unwrap().iter().all(|(_, tags)| tags.is_empty()));
+        // Excluded files are untagged again
+        let scope = shelf.scope(&root).unwrap();
+        assert_eq!(names(&root, &scope.files(&scope.untagged())), ["a/b/c/w", "a/b/z", "a/y"]);
+        assert!(shelf.check(false).is_empty());
+
+        assert!(shelf.include(root.join("a/b"), foo.clone()).unwrap());
+        assert!(shelf.include(root.join("a/y"), foo.clone()).unwrap());
+        assert_eq!(names(&root, &shelf.retrieve(foo.clone())), ["a/b/c/w", "a/b/z", "a/x", "a/y"]);
+        assert!(has_foo("a/b"));
+        assert!(shelf.check(false).is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn checks_report_and_repair_broken_aggregates() {
+        let root = tree(&["top", "a/x", "a/y"]);
+        let shelf = LocalShelf::new(root.clone(), ScanConfig::default()).unwrap();
+        let foo = tag("foo");
+        assert!(shelf.attach(root.join("a/x"), foo.clone()).unwrap());
+        assert!(shelf.check(false).is_empty());
+        {
+            let tree = shelf.tree();
+            let mut node = tree.root.write().unwrap();
+            node.file_count += 3;
+            node.tags.get_mut(&foo).unwrap().insert(99);
+            node.untagged.clear();
+        }
+        let mut found: Vec<_> = shelf
+            .check(false)
+            .into_iter()
+            .map(|v| (v.path, v.invariant.name(), v.tag.map(|tag| tag.name()), v.expected, v.found))
+            .collect();
+        found.sort();
+        assert_eq!(found, [
+            (root.clone(), "file_count", None, 3, 6),
+            (root.clone(), "tags", Some(foo.name()), 1, 2),
+            (root.clone(), "untagged", None, 2, 0),
+        ]);
+        // Reports leave the tree as it was
+        assert_eq!(shelf.check(false).len(), 3);
+        let version = shelf.version();
+        assert_eq!(shelf.check(true).len(), 3);
+        assert_eq!(shelf.version(), version + 1);
+        assert!(shelf.check(false).is_empty());
+        let summary = shelf.summary(1);
+        assert_eq!((summary.file_count, summary.untagged, summary.counts[&foo.name()]), (3, 2, 1));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
@@ -0,0 +1,162 @@
+use crate::rpc;
+use crate::tag::TagRef;
+use crate::workspace::WorkspaceId;
+use std::collections::{BTreeSet, HashMap};
+
+const BLOOM_WORDS: usize = 16; // 1024 bits
+const BLOOM_HASHES: u64 = 4;
+
+// Fixed-size bloom filter over tags. Hashing must be stable across daemons, since filters
+// built by one peer are probed by another: tag ids are local to each daemon, so a tag is
+// keyed on its name and the (shared) workspace it is resolved in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagBloom {
+    bits: Vec<u64>,
+}
+
+impl Default for TagBloom {
+    fn default() -> Self {
+        TagBloom {
+            bits: vec![0; BLOOM_WORDS],
+        }
+    }
+}
+
+impl TagBloom {
+    pub fn key(workspace_id: WorkspaceId, name: &str) -> u64 {
+        fxhash::hash64(&(workspace_id, name))
+    }
+
+    fn positions(id: u64) -> impl Iterator<Item = usize> {
+        let h1 = fxhash::hash64(&id);
+        let h2 = h1.rotate_left(32) | 1;
+        let nbits = (BLOOM_WORDS * 64) as u64;
+        (0..BLOOM_HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % nbits) as usize)
+    }
+
+    pub fn insert(&mut self, id: u64) {
+        for pos in TagBloom::positions(id) {
+            self.bits[pos / 64] |= 1 << (pos % 64);
+        }
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        TagBloom::positions(id).all(|pos| {
+            self.bits
+                .get(pos / 64)
+                .is_some_and(|word| word & (1 << (pos % 64)) != 0)
+        })
+    }
+}
+
+// Compact description of a shelf, exchanged between peers to route workspace queries
+#[derive(Debug, Clone, Default)]
+pub struct ShelfSummary {
+    pub tags: BTreeSet<String>,
+    pub counts: HashMap<String, u64>, // Estimated files per tag, by name
+    pub file_count: u64,
+    pub untagged: u64, // Files with neither tags nor dtags
+    pub bloom: TagBloom,
+    pub version: u64, // Increases whenever the tag state of the shelf changes
+}
+
+impl ShelfSummary {
+    pub fn new<'a>(workspace_id: WorkspaceId, tags: impl Iterator<Item = (&'a TagRef, u64)>, file_count: u64, untagged: u64, version: u64) -> Self {
+        let mut summary = ShelfSummary {
+            file_count,
+            untagged,
+            version,
+            ..Default::default()
+        };
+        for (tag, count) in tags {
+            summary.bloom.insert(TagBloom::key(workspace_id, &tag.name()));
+            summary.tags.insert(tag.name());
+            summary.counts.insert(tag.name(), count);
+        }
+        summary
+    }
+
+    // False only if no file on the shelf can carry the tag named name in the workspace
+    pub fn may_contain(&self, workspace_id: WorkspaceId, name: &str) -> bool {
+        self.bloom.contains(TagBloom::key(workspace_id, name))
+    }
+
+    pub fn to_rpc(&self, shelf_id: u64) -> rpc::ShelfSummary {
+        rpc::ShelfSummary {
+            shelf_id,
+            tags: self.tags.iter().cloned().collect(),
+            counts: self.counts.clone(),
+            file_count: self.file_count,
+            untagged: self.untagged,
+            bloom: self.bloom.bits.clone(),
+            version: self.version,
+        }
+    }
+}
+
+impl From<rpc::ShelfSummary> for ShelfSummary {
+    fn from(summary: rpc::ShelfSummary) -> Self {
+        let mut bits = summary.bloom;
+        bits.resize(BLOOM_WORDS, 0);
+        ShelfSummary {
+            tags: summary.tags.into_iter().collect(),
+            counts: summary.counts,
+            file_count: summary.file_count,
+            untagged: summary.untagged,
+            bloom: TagBloom { bits },
+            version: summary.version,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bloom_has_no_false_negatives() {
+        let mut bloom = TagBloom::default();
+        let keys: Vec<_> = (0..200).map(|i| TagBloom::key(1, &format!("tag{}", i))).collect();
+        for key in &keys {
+            bloom.insert(*key);
+        }
+        assert!(keys.iter().all(|key| bloom.contains(*key)));
+        assert!(!TagBloom::default().contains(keys[0]));
+    }
+
+    #[test]
+    fn bloom_keys_depend_on_the_workspace() {
+        let mut bloom = TagBloom::default();
+        bloom.insert(TagBloom::key(1, "foo"));
+        assert!(bloom.contains(TagBloom::key(1, "foo")));
+        assert_ne!(TagBloom::key(1, "foo"), TagBloom::key(2, "foo"));
+        assert_ne!(TagBloom::key(1, "foo"), TagBloom::key(1, "bar"));
+    }
+
+    #[test]
+    fn summary_survives_the_wire() {
+        let mut summary = ShelfSummary {
+            file_count: 12,
+            untagged: 3,
+            version: 7,
+            ..Default::default()
+        };
+        for name in ["foo", "bar"] {
+            summary.bloom.insert(TagBloom::key(1, name));
+            summary.tags.insert(name.to_string());
+            summary.counts.insert(name.to_string(), 4);
+        }
+        let received = ShelfSummary::from(summary.to_rpc(5));
+        assert!(received.may_contain(1, "foo") && received.may_contain(1, "bar"));
+        assert_eq!(received.bloom, summary.bloom);
+        assert_eq!(received.tags, summary.tags);
+        assert_eq!(received.counts, summary.counts);
+        assert_eq!((received.file_count, received.untagged, received.version), (12, 3, 7));
+        // Filters of another size are resized rather than probed out of bounds
+        let short = ShelfSummary::from(rpc::ShelfSummary {
+            bloom: vec![u64::MAX; 2],
+            ..Default::default()
+        });
+        assert_eq!(short.bloom.bits.len(), BLOOM_WORDS);
+    }
+}
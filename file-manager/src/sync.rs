@@ -0,0 +1,510 @@
+use crate::persist::write_atomic;
+use crate::rpc;
+use crate::workspace::{ShelfId, WorkspaceId};
+use iroh::NodeId;
+use prost::Message;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Replicated tag state of shared shelves.
+//
+// Every attach/detach (dtags and their exclusions included) on a shared shelf becomes a
+// TagOp stamped with a Lamport clock and the NodeId of the daemon that issued it. For each
+// (shelf, path, tag, target) only the op with the greatest OpId is kept (last-writer-wins
+// register), so peers that have received the same set of ops converge to the same state
+// regardless of the order in which they were received. The winners are persisted along
+// with the clock, so the state survives restarts and stamps are never reused.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OpId {
+    pub lamport: u64,
+    pub node: NodeId, // Tie-breaker for concurrent ops
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TagOpKind {
+    Attach,
+    Detach,
+    AttachDtag,
+    DetachDtag,
+    ExcludeDtag,
+    IncludeDtag,
+}
+
+// What an operation sets or clears: a tag, a dtag or an exclusion from a dtag
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum OpTarget {
+    Tag,
+    Dtag,
+    Exclusion,
+}
+
+impl TagOpKind {
+    fn target(&self) -> OpTarget {
+        match self {
+            TagOpKind::Attach | TagOpKind::Detach => OpTarget::Tag,
+            TagOpKind::AttachDtag | TagOpKind::DetachDtag => OpTarget::Dtag,
+            TagOpKind::ExcludeDtag | TagOpKind::IncludeDtag => OpTarget::Exclusion,
+        }
+    }
+
+    pub fn is_attach(&self) -> bool {
+        matches!(self, TagOpKind::Attach | TagOpKind::AttachDtag | TagOpKind::ExcludeDtag)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TagOp {
+    pub id: OpId,
+    pub workspace_id: WorkspaceId,
+    pub shelf_id: ShelfId,
+    pub path: PathBuf, // Relative to the shelf root
+    pub tag: String,
+    pub kind: TagOpKind,
+}
+
+type OpKey = (ShelfId, PathBuf, String, OpTarget);
+
+impl TagOp {
+    fn key(&self) -> OpKey {
+        (
+            self.shelf_id,
+            self.path.clone(),
+            self.tag.clone(),
+            self.kind.target(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Merge {
+    Changed,
+    Unchanged,  // Winner, but the tag was already (de)attached
+    Superseded, // A later op on the same key already won
+}
+
+#[derive(Debug, Default)]
+struct WorkspaceLog {
+    winners: HashMap<OpKey, (TagOp, u64)>, // With the sequence number it was recorded at
+}
+
+// Sequence numbers are local to each daemon: every op that becomes a winner here gets the
+// next one, so a peer that received everything up to some sequence number (its
+// acknowledged prefix) only needs the winners recorded after it, whatever their origin.
+#[derive(Debug)]
+pub struct TagLog {
+    node: NodeId,
+    clock: u64,
+    seq: u64,
+    workspaces: HashMap<WorkspaceId, WorkspaceLog>,
+    acked: HashMap<(NodeId, WorkspaceId), u64>, // Prefix of each peer's log we hold
+    path: PathBuf,
+    unsaved: bool, // The last write failed
+}
+
+impl TagLog {
+    pub fn load(node: NodeId, path: PathBuf) -> Self {
+        let state = std::fs::read(&path)
+            .ok()
+            .and_then(|buf| rpc::TagLogState::decode(&*buf).ok())
+            .unwrap_or_default();
+        let mut log = TagLog {
+            node,
+            clock: state.clock,
+            seq: state.seq,
+            workspaces: HashMap::new(),
+            acked: HashMap::new(),
+            path,
+            unsaved: false,
+        };
+        for entry in state.ops {
+            if let Some(op) = entry.op.and_then(|op| TagOp::try_from(op).ok()) {
+                log.workspaces
+                    .entry(op.workspace_id)
+                    .or_default()
+                    .winners
+                    .insert(op.key(), (op, entry.seq));
+            }
+        }
+        for ack in state.acked {
+            if let Some(node) = decode_node(ack.node) {
+                log.acked.insert((node, ack.workspace_id), ack.seq);
+            }
+        }
+        log
+    }
+
+    pub fn persist(&mut self) -> io::Result<()> {
+        let res = self.write();
+        self.unsaved = res.is_err();
+        res
+    }
+
+    // Whether the log on disk is behind, its last write having failed
+    pub fn unsaved(&self) -> bool {
+        self.unsaved
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write(&self) -> io::Result<()> {
+        let state = rpc::TagLogState {
+            clock: self.clock,
+            seq: self.seq,
+            ops: self
+                .workspaces
+                .values()
+                .flat_map(|log| log.winners.values())
+                .map(|(op, seq)| rpc::LoggedOp {
+                    op: Some(op.into()),
+                    seq: *seq,
+                })
+                .collect(),
+            acked: self
+                .acked
+                .iter()
+                .map(|((node, workspace_id), seq)| rpc::SyncAck {
+                    node: node.as_bytes().to_vec(),
+                    workspace_id: *workspace_id,
+                    seq: *seq,
+                })
+                .collect(),
+        };
+        write_atomic(&self.path, &state.encode_to_vec())
+    }
+
+    // Stamp a local operation without recording it
+    pub fn stamp(
+        &mut self,
+        workspace_id: WorkspaceId,
+        shelf_id: ShelfId,
+        path: PathBuf,
+        tag: String,
+        kind: TagOpKind,
+    ) -> TagOp {
+        self.clock += 1;
+        TagOp {
+            id: OpId {
+                lamport: self.clock,
+                node: self.node,
+            },
+            workspace_id,
+            shelf_id,
+            path,
+            tag,
+            kind,
+        }
+    }
+
+    fn winner(&self, op: &TagOp) -> Option<&TagOp> {
+        let log = self.workspaces.get(&op.workspace_id)?;
+        log.winners.get(&op.key()).map(|(winner, _)| winner)
+    }
+
+    // Whether a later op on the same key already won
+    pub fn superseded(&self, op: &TagOp) -> bool {
+        self.winner(op).is_some_and(|winner| winner.id > op.id)
+    }
+
+    // Whether op is the recorded winner of its key
+    pub fn is_winner(&self, op: &TagOp) -> bool {
+        self.winner(op).is_some_and(|winner| winner.id == op.id)
+    }
+
+    // Merge operations received from a peer, returning the ones that changed the state
+    pub fn merge(&mut self, ops: Vec<TagOp>) -> Vec<TagOp> {
+        let mut effective = Vec::new();
+        for op in ops {
+            if self.merge_one(op.clone()) == Merge::Changed {
+                effective.push(op);
+            }
+        }
+        effective
+    }
+
+    pub fn merge_one(&mut self, op: TagOp) -> Merge {
+        self.clock = self.clock.max(op.id.lamport);
+        self.insert(op)
+    }
+
+    // Record op, reporting how it relates to the current winner of its key
+    fn insert(&mut self, op: TagOp) -> Merge {
+        let log = self.workspaces.entry(op.workspace_id).or_default();
+        match log.winners.get(&op.key()) {
+            Some((curr, _)) if curr.id > op.id => Merge::Superseded,
+            Some((curr, _)) if curr.id == op.id => Merge::Unchanged,
+            curr => {
+                let present = curr.is_some_and(|(c, _)| c.kind.is_attach());
+                let flipped = present != op.kind.is_attach();
+                self.seq += 1;
+                log.winners.insert(op.key(), (op, self.seq));
+                if flipped {
+                    Merge::Changed
+                } else {
+                    Merge::Unchanged
+                }
+            }
+        }
+    }
+
+    // Prefix of node's log (for the workspace) we already merged
+    pub fn acked(&self, node: NodeId, workspace_id: WorkspaceId) -> u64 {
+        self.acked.get(&(node, workspace_id)).copied().unwrap_or(0)
+    }
+
+    // Merge the reply of node to a sync request: ops holds every winner it recorded after
+    // our acknowledged prefix, so its whole log up to seq is now covered
+    pub fn sync(&mut self, node: NodeId, workspace_id: WorkspaceId, ops: Vec<TagOp>, seq: u64) -> Vec<TagOp> {
+        let effective = self.merge(ops);
+        let acked = self.acked.entry((node, workspace_id)).or_default();
+        *acked = (*acked).max(seq);
+        effective
+    }
+
+    // Winners recorded after the sequence number after, in the order they were recorded,
+    // along with the current sequence number. Superseded operations are never needed to
+    // converge, so only the current winners are sent.
+    pub fn missing(&self, workspace_id: WorkspaceId, after: u64) -> (Vec<TagOp>, u64) {
+        let Some(log) = self.workspaces.get(&workspace_id) else {
+            return (Vec::new(), self.seq);
+        };
+        let mut ops = log
+            .winners
+            .values()
+            .filter(|(_, seq)| *seq > after)
+            .collect::<Vec<_>>();
+        ops.sort_by_key(|(_, seq)| *seq);
+        (ops.into_iter().map(|(op, _)| op.clone()).collect(), self.seq)
+    }
+}
+
+impl From<TagOpKind> for rpc::TagOpKind {
+    fn from(kind: TagOpKind) -> Self {
+        match kind {
+            TagOpKind::Attach => rpc::TagOpKind::Attach,
+            TagOpKind::Detach => rpc::TagOpKind::Detach,
+            TagOpKind::AttachDtag => rpc::TagOpKind::AttachDtag,
+            TagOpKind::DetachDtag => rpc::TagOpKind::DetachDtag,
+            TagOpKind::ExcludeDtag => rpc::TagOpKind::ExcludeDtag,
+            TagOpKind::IncludeDtag => rpc::TagOpKind::IncludeDtag,
+        }
+    }
+}
+
+impl From<rpc::TagOpKind> for TagOpKind {
+    fn from(kind: rpc::TagOpKind) -> Self {
+        match kind {
+            rpc::TagOpKind::Attach => TagOpKind::Attach,
+            rpc::TagOpKind::Detach => TagOpKind::Detach,
+            rpc::TagOpKind::AttachDtag => TagOpKind::AttachDtag,
+            rpc::TagOpKind::DetachDtag => TagOpKind::DetachDtag,
+            rpc::TagOpKind::ExcludeDtag => TagOpKind::ExcludeDtag,
+            rpc::TagOpKind::IncludeDtag => TagOpKind::IncludeDtag,
+        }
+    }
+}
+
+impl From<&TagOp> for rpc::TagOp {
+    fn from(op: &TagOp) -> Self {
+        rpc::TagOp {
+            lamport: op.id.lamport,
+            node: op.id.node.as_bytes().to_vec(),
+            workspace_id: op.workspace_id,
+            shelf_id: op.shelf_id,
+            path: op.path.to_string_lossy().into_owned(),
+            tag: op.tag.clone(),
+            kind: rpc::TagOpKind::from(op.kind) as i32,
+        }
+    }
+}
+
+// Why a TagOp received from a peer could not be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MalformedOp {
+    Node,
+    Kind(i32),
+}
+
+impl std::fmt::Display for MalformedOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MalformedOp::Node => write!(f, "malformed operation: invalid node id"),
+            MalformedOp::Kind(kind) => write!(f, "malformed operation: unknown kind {}", kind),
+        }
+    }
+}
+
+impl TryFrom<rpc::TagOp> for TagOp {
+    type Error = MalformedOp;
+
+    fn try_from(op: rpc::TagOp) -> Result<Self, Self::Error> {
+        let node = decode_node(op.node).ok_or(MalformedOp::Node)?;
+        let kind = rpc::TagOpKind::try_from(op.kind).map_err(|_| MalformedOp::Kind(op.kind))?;
+        Ok(TagOp {
+            id: OpId {
+                lamport: op.lamport,
+                node,
+            },
+            workspace_id: op.workspace_id,
+            shelf_id: op.shelf_id,
+            path: PathBuf::from(op.path),
+            tag: op.tag,
+            kind: kind.into(),
+        })
+    }
+}
+
+fn decode_node(node: Vec<u8>) -> Option<NodeId> {
+    let node: [u8; 32] = node.try_into().ok()?;
+    NodeId::from_bytes(&node).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::SecretKey;
+
+    fn node(n: u8) -> NodeId {
+        SecretKey::from_bytes(&[n; 32]).public()
+    }
+
+    fn log(n: u8) -> TagLog {
+        TagLog::load(node(n), std::env::temp_dir().join(format!("ebi-taglog-{}.pb", rand::random::<u64>())))
+    }
+
+    fn op(lamport: u64, n: u8, path: &str, kind: TagOpKind) -> TagOp {
+        TagOp {
+            id: OpId { lamport, node: node(n) },
+            workspace_id: 1,
+            shelf_id: 2,
+            path: PathBuf::from(path),
+            tag: "foo".to_string(),
+            kind,
+        }
+    }
+
+    fn state(log: &TagLog) -> Vec<(PathBuf, TagOpKind)> {
+        let mut state: Vec<_> = log.missing(1, 0).0.into_iter().map(|op| (op.path, op.kind)).collect();
+        state.sort_by(|a, b| a.0.cmp(&b.0));
+        state
+    }
+
+    #[test]
+    fn last_writer_wins() {
+        let mut log = log(1);
+        assert_eq!(log.merge_one(op(1, 2, "a", TagOpKind::Attach)), Merge::Changed);
+        assert_eq!(log.merge_one(op(3, 2, "a", TagOpKind::Detach)), Merge::Changed);
+        assert_eq!(log.merge_one(op(2, 3, "a", TagOpKind::Attach)), Merge::Superseded);
+        assert_eq!(log.merge_one(op(3, 2, "a", TagOpKind::Detach)), Merge::Unchanged);
+        // Same stamp, the node breaks the tie
+        assert!(node(3) > node(2));
+        assert_eq!(log.merge_one(op(3, 3, "a", TagOpKind::Attach)), Merge::Changed);
+        assert_eq!(state(&log), vec![(PathBuf::from("a"), TagOpKind::Attach)]);
+    }
+
+    #[test]
+    fn targets_are_independent() {
+        let mut log = log(1);
+        assert_eq!(log.merge_one(op(1, 2, "a", TagOpKind::Attach)), Merge::Changed);
+        assert_eq!(log.merge_one(op(2, 2, "a", TagOpKind::AttachDtag)), Merge::Changed);
+        assert_eq!(log.merge_one(op(3, 2, "a", TagOpKind::IncludeDtag)), Merge::Unchanged);
+        assert_eq!(log.missing(1, 0).0.len(), 3);
+    }
+
+    #[test]
+    fn replicas_converge_whatever_the_order() {
+        let ops = vec![
+            op(1, 2, "a", TagOpKind::Attach),
+            op(2, 3, "a", TagOpKind::Detach),
+            op(2, 2, "b", TagOpKind::AttachDtag),
+            op(4, 2, "b", TagOpKind::AttachDtag),
+            op(4, 3, "b", TagOpKind::DetachDtag), // Concurrent, node(3) > node(2)
+            op(5, 2, "c", TagOpKind::ExcludeDtag),
+        ];
+        let mut forward = log(1);
+        forward.merge(ops.clone());
+        let mut backward = log(4);
+        backward.merge(ops.iter().rev().cloned().collect());
+        assert_eq!(state(&forward), state(&backward));
+        assert_eq!(
+            state(&forward),
+            vec![
+                (PathBuf::from("a"), TagOpKind::Detach),
+                (PathBuf::from("b"), TagOpKind::DetachDtag),
+                (PathBuf::from("c"), TagOpKind::ExcludeDtag),
+            ]
+        );
+        // Local ops are stamped after everything merged
+        let local = forward.stamp(1, 2, PathBuf::from("a"), "foo".to_string(), TagOpKind::Attach);
+        assert!(local.id.lamport > 5);
+    }
+
+    #[test]
+    fn stamped_ops_are_recorded_only_when_merged() {
+        let mut log = log(1);
+        let attach = log.stamp(1, 2, PathBuf::from("a"), "foo".to_string(), TagOpKind::Attach);
+        assert!(!log.is_winner(&attach) && !log.superseded(&attach));
+        assert!(log.missing(1, 0).0.is_empty());
+        assert_eq!(log.merge_one(attach.clone()), Merge::Changed);
+        assert!(log.is_winner(&attach));
+        // A stale op loses against the recorded winner
+        let stale = op(0, 2, "a", TagOpKind::Detach);
+        assert!(log.superseded(&stale) && !log.is_winner(&stale));
+    }
+
+    #[test]
+    fn malformed_ops_are_rejected() {
+        let mut wire = rpc::TagOp::from(&op(1, 2, "a", TagOpKind::Attach));
+        wire.kind = 42;
+        assert_eq!(TagOp::try_from(wire.clone()).unwrap_err(), MalformedOp::Kind(42));
+        wire.node.truncate(4);
+        assert_eq!(TagOp::try_from(wire).unwrap_err(), MalformedOp::Node);
+    }
+
+    #[test]
+    fn missing_is_a_suffix_of_the_winners() {
+        let mut log = log(1);
+        log.merge(vec![op(1, 2, "a", TagOpKind::Attach), op(2, 2, "b", TagOpKind::Attach)]);
+        let (ops, seq) = log.missing(1, 0);
+        assert_eq!((ops.len(), seq), (2, 2));
+        let (ops, _) = log.missing(1, 1);
+        assert_eq!(ops[0].path, PathBuf::from("b"));
+        // A new winner for an old key is sent again, and only once
+        log.merge_one(op(3, 2, "a", TagOpKind::Detach));
+        let (ops, seq) = log.missing(1, 2);
+        assert_eq!(ops.len(), 1);
+        assert_eq!((ops[0].path.clone(), ops[0].kind, seq), (PathBuf::from("a"), TagOpKind::Detach, 3));
+        assert!(log.missing(1, seq).0.is_empty());
+        assert!(log.missing(7, 0).0.is_empty());
+    }
+
+    #[test]
+    fn sync_advances_the_acknowledged_prefix() {
+        let mut log = log(1);
+        assert_eq!(log.acked(node(2), 1), 0);
+        let effective = log.sync(node(2), 1, vec![op(1, 2, "a", TagOpKind::Attach)], 5);
+        assert_eq!(effective.len(), 1);
+        assert_eq!(log.acked(node(2), 1), 5);
+        log.sync(node(2), 1, Vec::new(), 3);
+        assert_eq!(log.acked(node(2), 1), 5);
+    }
+
+    #[test]
+    fn persisted_log_reloads() {
+        let mut saved = log(1);
+        saved.merge(vec![op(4, 2, "a", TagOpKind::Attach), op(2, 2, "b", TagOpKind::AttachDtag)]);
+        saved.sync(node(2), 1, Vec::new(), 9);
+        saved.persist().unwrap();
+        assert!(!saved.unsaved());
+        let mut loaded = TagLog::load(node(1), saved.path().to_path_buf());
+        assert_eq!(state(&loaded), state(&saved));
+        assert_eq!(loaded.acked(node(2), 1), 9);
+        assert_eq!(loaded.missing(1, 0).1, 2);
+        let local = loaded.stamp(1, 2, PathBuf::from("c"), "foo".to_string(), TagOpKind::Attach);
+        assert_eq!(local.id.lamport, 5);
+        std::fs::remove_file(saved.path()).unwrap();
+    }
+}
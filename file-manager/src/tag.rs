@@ -1,7 +1,13 @@
+use crate::persist::write_atomic;
+use crate::rpc::{TagDef, TagDefs};
+use crate::workspace::WorkspaceId;
+use prost::Message;
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::hash::{Hash, Hasher};
-use std::sync::{Arc, RwLock};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, RwLock};
 
 #[derive(Debug, Eq, PartialOrd, PartialEq, Ord, Hash, Default)]
 pub struct Tag {
@@ -9,6 +15,25 @@ pub struct Tag {
     priority: u64,
     name: String,
     parent: Option<TagRef>,
+    scope: TagScope,
+}
+
+// Workspaces a tag is visible in. Names are unique within a workspace, workspace tags
+// shadowing global tags of the same name.
+#[derive(Debug, Clone, Eq, PartialOrd, PartialEq, Ord, Hash, Default)]
+pub enum TagScope {
+    #[default]
+    Global,
+    Workspaces(BTreeSet<WorkspaceId>),
+}
+
+impl TagScope {
+    fn owned_by(&self, workspace_id: WorkspaceId) -> bool {
+        match self {
+            TagScope::Global => false,
+            TagScope::Workspaces(workspaces) => workspaces.contains(&workspace_id),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -16,6 +41,20 @@ pub struct TagRef {
     pub tag_ref: Arc<RwLock<Tag>>,
 }
 
+impl TagRef {
+    pub fn id(&self) -> u64 {
+        self.tag_ref.read().unwrap().id
+    }
+
+    pub fn name(&self) -> String {
+        self.tag_ref.read().unwrap().name.clone()
+    }
+
+    pub fn scope(&self) -> TagScope {
+        self.tag_ref.read().unwrap().scope.clone()
+    }
+}
+
 impl Clone for TagRef {
     fn clone(&self) -> Self {
         TagRef {
@@ -32,13 +71,7 @@ impl PartialEq for TagRef {
 
 impl PartialOrd for TagRef {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(
-            self.tag_ref
-                .read()
-                .unwrap()
-                .priority
-                .cmp(&other.tag_ref.read().unwrap().priority),
-        )
+        Some(self.cmp(other))
     }
 }
 
@@ -48,28 +81,167 @@ impl Hash for TagRef {
     }
 }
 
+// By priority, then id so that distinct tags of equal priority are not merged in sets
 impl Ord for TagRef {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.tag_ref
-            .read()
-            .unwrap()
-            .priority
-            .cmp(&other.tag_ref.read().unwrap().priority)
+        let (tag, other) = (self.tag_ref.read().unwrap(), other.tag_ref.read().unwrap());
+        (tag.priority, tag.id).cmp(&(other.priority, other.id))
     }
 }
 
 impl Eq for TagRef {}
 
-//[#] We require a tag manmager
+// Registry of every tag known to the daemon, shared by the services, the shelves and
+// the query parser
+static TAGS: LazyLock<RwLock<TagManager>> = LazyLock::new(|| RwLock::new(TagManager::default()));
+
+#[derive(Default)]
 pub struct TagManager {
-    tags: HashMap<String, TagRef>,
+    tags: HashMap<u64, TagRef>,
+    next_id: u64,
+    path: Option<PathBuf>, // Unset until loaded, nothing is persisted before
+}
+
+#[derive(Debug)]
+pub enum TagErr {
+    NameClash, // A tag of that name is already visible in one of the workspaces
+    EmptyScope,
+    Io,
+}
+
+impl From<io::Error> for TagErr {
+    fn from(_: io::Error) -> Self {
+        TagErr::Io
+    }
 }
 
 impl TagManager {
-    pub fn retrieve_tag(name: &str) -> Option<TagRef> {
-        println!("Retrieving tag {}", name);
-        return Some(TagRef {
-            tag_ref: Arc::new(RwLock::new(Tag::default())),
+    // Load the persisted tags. Parents are declared before their children.
+    pub fn load(data_dir: &Path) -> io::Result<()> {
+        let path = data_dir.join("tags.pb");
+        let defs = match std::fs::read(&path) {
+            Ok(buf) => TagDefs::decode(&*buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => TagDefs::default(),
+            Err(err) => return Err(err),
+        };
+        let mut manager = TagManager {
+            tags: HashMap::new(),
+            next_id: defs.next_id.max(1),
+            path: Some(path),
+        };
+        for def in defs.tags {
+            let parent = manager.tags.get(&def.parent).cloned();
+            let scope = match def.workspaces.is_empty() {
+                true => TagScope::Global,
+                false => TagScope::Workspaces(def.workspaces.into_iter().collect()),
+            };
+            manager.insert(def.id, def.name, def.priority, parent, scope);
+        }
+        *TAGS.write().unwrap() = manager;
+        Ok(())
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut tags: Vec<&TagRef> = self.tags.values().collect();
+        tags.sort_by_key(|tag| tag.id());
+        let defs = TagDefs {
+            next_id: self.next_id,
+            tags: tags
+                .into_iter()
+                .map(|tag| {
+                    let tag = tag.tag_ref.read().unwrap();
+                    TagDef {
+                        id: tag.id,
+                        name: tag.name.clone(),
+                        priority: tag.priority,
+                        parent: tag.parent.as_ref().map_or(0, TagRef::id),
+                        workspaces: match &tag.scope {
+                            TagScope::Global => Vec::new(),
+                            TagScope::Workspaces(workspaces) => workspaces.iter().cloned().collect(),
+                        },
+                    }
+                })
+                .collect(),
+        };
+        write_atomic(path, &defs.encode_to_vec())
+    }
+
+    fn insert(&mut self, id: u64, name: String, priority: u64, parent: Option<TagRef>, scope: TagScope) -> TagRef {
+        let tag = TagRef {
+            tag_ref: Arc::new(RwLock::new(Tag {
+                id,
+                priority,
+                name,
+                parent,
+                scope,
+            })),
+        };
+        self.tags.insert(id, tag.clone());
+        self.next_id = self.next_id.max(id + 1);
+        tag
+    }
+
+    fn resolve(&self, workspace_id: WorkspaceId, name: &str) -> Option<TagRef> {
+        let mut global = None;
+        for tag in self.tags.values() {
+            let t = tag.tag_ref.read().unwrap();
+            if t.name != name {
+                continue;
+            }
+            match &t.scope {
+                scope if scope.owned_by(workspace_id) => return Some(tag.clone()),
+                TagScope::Global => global = Some(tag.clone()),
+                TagScope::Workspaces(_) => {}
+            }
+        }
+        global
+    }
+
+    // Tag visible as name in the workspace: its own tag first, else the global one
+    pub fn retrieve_tag(workspace_id: WorkspaceId, name: &str) -> Option<TagRef> {
+        TAGS.read().unwrap().resolve(workspace_id, name)
+    }
+
+    pub fn get(id: u64) -> Option<TagRef> {
+        TAGS.read().unwrap().tags.get(&id).cloned()
+    }
+
+    // Tag visible as name in the workspace, created in the workspace if there is none.
+    // Used when tagging files, where tags are referred to by name.
+    pub fn get_or_create(workspace_id: WorkspaceId, name: &str) -> Result<TagRef, TagErr> {
+        let mut manager = TAGS.write().unwrap();
+        if let Some(tag) = manager.resolve(workspace_id, name) {
+            return Ok(tag);
+        }
+        let id = manager.next_id.max(1);
+        let tag = manager.insert(id, name.to_string(), 0, None, TagScope::Workspaces(BTreeSet::from([workspace_id])));
+        manager.persist()?;
+        Ok(tag)
+    }
+
+    pub fn create_tag(name: &str, priority: u64, parent: Option<TagRef>, scope: TagScope) -> Result<TagRef, TagErr> {
+        let mut manager = TAGS.write().unwrap();
+        let clash = manager.tags.values().any(|tag| {
+            let tag = tag.tag_ref.read().unwrap();
+            tag.name == name
+                && match (&tag.scope, &scope) {
+                    (TagScope::Global, TagScope::Global) => true,
+                    (TagScope::Workspaces(a), TagScope::Workspaces(b)) => !a.is_disjoint(b),
+                    _ => false,
+                }
         });
+        if clash {
+            return Err(TagErr::NameClash);
+        }
+        if matches!(&scope, TagScope::Workspaces(workspaces) if workspaces.is_empty()) {
+            return Err(TagErr::EmptyScope);
+        }
+        let id = manager.next_id.max(1);
+        let tag = manager.insert(id, name.to_string(), priority, parent, scope);
+        manager.persist()?;
+        Ok(tag)
     }
 }
@@ -1,18 +1,263 @@
-use crate::Shelf;
+use crate::query::FileID;
+use crate::rpc;
+use crate::services::peer::PeerService;
+use crate::shelf::node::{ScanConfig, ScanError, Violation};
+use crate::shelf::remote::RemoteShelf;
+use crate::shelf::shelf::{LocalShelf, LocalShelfRef, Shelf, ShelfFuture, UpdateErr};
+use crate::sync::{TagOp, TagOpKind};
+use crate::tag::{TagManager, TagRef};
 use iroh::NodeId;
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+pub type WorkspaceId = u64;
+pub type ShelfId = u64;
+pub enum ShelfLocation {
+    Local(LocalShelfRef),
+    Remote(Arc<RemoteShelf>), // root_path is a path on the side of its peer
+}
 
-struct ShelfInfo {
-    id: u64,
-    root_path: PathBuf,
-    //summary: ShelfSummary
+pub struct ShelfInfo {
+    pub id: ShelfId,
+    pub root_path: PathBuf,
+    pub location: ShelfLocation,
+}
+
+impl ShelfInfo {
+    // Path of path relative to the shelf root, if the shelf covers it
+    pub fn relative(&self, path: &Path) -> Option<PathBuf> {
+        path.strip_prefix(&self.root_path).ok().map(Path::to_path_buf)
+    }
+
+    pub fn is_local(&self) -> bool {
+        matches!(self.location, ShelfLocation::Local(_))
+    }
+
+    pub fn shelf(&self) -> Arc<dyn Shelf> {
+        match &self.location {
+            ShelfLocation::Local(shelf) => shelf.clone(),
+            ShelfLocation::Remote(shelf) => shelf.clone(),
+        }
+    }
 }
 
 pub struct Workspace {
-    id: WorkspaceId,
-    local_shelves: ShelfInfo,
-    remote_shelves: (ShelfInfo, NodeId)
+    pub id: WorkspaceId,
+    pub name: String,
+    shelves: Vec<ShelfInfo>,
+    unavailable: Vec<rpc::ShelfDef>, // Local shelves that failed to load, kept in the definition
 }
 
-pub type WorkspaceId = u64;
+impl Workspace {
+    pub fn new(id: WorkspaceId, name: String) -> Self {
+        Workspace {
+            id,
+            name,
+            shelves: Vec::new(),
+            unavailable: Vec::new(),
+        }
+    }
+
+    pub fn shelves(&self) -> &[ShelfInfo] {
+        &self.shelves
+    }
+
+    pub fn shelf(&self, shelf_id: ShelfId) -> Option<&ShelfInfo> {
+        self.shelves.iter().find(|s| s.id == shelf_id)
+    }
+
+    // shelf is the result of scanning path
+    pub fn add_local(&mut self, shelf_id: ShelfId, path: PathBuf, shelf: LocalShelf) -> LocalShelfRef {
+        let shelf = Arc::new(shelf);
+        self.shelves.push(ShelfInfo {
+            id: shelf_id,
+            root_path: path,
+            location: ShelfLocation::Local(shelf.clone()),
+        });
+        shelf
+    }
+
+    pub fn add_remote(&mut self, shelf_id: ShelfId, root_path: PathBuf, shelf: RemoteShelf) {
+        self.shelves.push(ShelfInfo {
+            id: shelf_id,
+            root_path,
+            location: ShelfLocation::Remote(Arc::new(shelf)),
+        });
+    }
+
+    pub fn remove_shelf(&mut self, shelf_id: ShelfId) -> Option<ShelfInfo> {
+        self.unavailable.retain(|def| def.id != shelf_id);
+        let idx = self.shelves.iter().position(|s| s.id == shelf_id)?;
+        Some(self.shelves.remove(idx))
+    }
+
+    // Local shelf (loaded or not) whose root is path, contains it or lies below it
+    pub fn overlap(&self, path: &Path) -> Option<ShelfId> {
+        self.shelves
+            .iter()
+            .filter(|s| s.is_local())
+            .map(|s| (s.id, s.root_path.as_path()))
+            .chain(self.unavailable.iter().map(|def| (def.id, Path::new(&def.root_path))))
+            .find(|(_, root)| overlaps(root, path))
+            .map(|(id, _)| id)
+    }
+
+    // Every shelf is asked whether it covers path; the deepest root wins
+    pub fn shelf_of(&self, path: &Path) -> Option<&ShelfInfo> {
+        self.shelves
+            .iter()
+            .filter(|s| s.relative(path).is_some())
+            .max_by_key(|s| s.root_path.components().count())
+    }
+
+    // Apply a tag operation to the local shelf it targets. Remote shelves apply
+    // their own operations. The shelf is looked up at once, so the workspace need not
+    // stay borrowed while the operation runs.
+    pub fn apply(&self, op: &TagOp) -> ShelfFuture<'static, bool> {
+        let shelf = self.shelf(op.shelf_id).filter(|info| info.is_local()).map(ShelfInfo::shelf);
+        let op = op.clone();
+        Box::pin(async move {
+            let shelf = shelf.ok_or_else(|| UpdateErr::PathNotFound(op.path.clone()))?;
+            // Tagging with an unknown name creates the tag in the workspace
+            let tag = match op.kind.is_attach() {
+                true => TagManager::get_or_create(op.workspace_id, &op.tag).map_err(|_| UpdateErr::UnknownTag(op.tag.clone()))?,
+                false => TagManager::retrieve_tag(op.workspace_id, &op.tag).ok_or_else(|| UpdateErr::UnknownTag(op.tag.clone()))?,
+            };
+            let path = op.path;
+            match op.kind {
+                TagOpKind::Attach => shelf.attach(path, tag).await,
+                TagOpKind::Detach => shelf.detach(Some(path), tag).await,
+                TagOpKind::AttachDtag => shelf.attach_dtag(path, tag).await,
+                TagOpKind::DetachDtag => shelf.detach_dtag(path, tag).await,
+                TagOpKind::ExcludeDtag => shelf.exclude(path, tag).await,
+                TagOpKind::IncludeDtag => shelf.include(path, tag).await,
+            }
+        })
+    }
+
+    // Current files and tags of every local shelf
+    pub async fn entries(&self) -> Vec<(FileID, HashSet<TagRef>)> {
+        let mut entries = Vec::new();
+        for info in &self.shelves {
+            if let ShelfLocation::Local(shelf) = &info.location {
+                entries.extend(shelf.entries(&info.root_path).unwrap_or_default());
+            }
+        }
+        entries
+    }
+
+    pub fn to_rpc(&self) -> rpc::WorkspaceDef {
+        rpc::WorkspaceDef {
+            id: self.id,
+            name: self.name.clone(),
+            shelves: self
+                .shelves
+                .iter()
+                .map(|s| rpc::ShelfDef {
+                    id: s.id,
+                    root_path: s.root_path.to_string_lossy().into_owned(),
+                    node: match &s.location {
+                        ShelfLocation::Local(_) => Vec::new(),
+                        ShelfLocation::Remote(shelf) => shelf.node().as_bytes().to_vec(),
+                    },
+                    ..match &s.location {
+                        ShelfLocation::Local(shelf) => rpc::ShelfDef {
+                            errors: shelf.errors().iter().map(rpc::ScanError::from).collect(),
+                            ignore: shelf.config().ignore.clone(),
+                            skip_hidden: shelf.config().skip_hidden,
+                            loaded_depth: shelf.config().loaded_depth.unwrap_or(0) as u32,
+                            ..Default::default()
+                        },
+                        ShelfLocation::Remote(_) => rpc::ShelfDef::default(),
+                    }
+                })
+                .chain(self.unavailable.iter().cloned())
+                .collect(),
+        }
+    }
+
+    // Local shelves that can no longer be scanned, or overlap one of the local roots
+    // already loaded, are kept unloaded and reported. The others get back the tags
+    // persisted in tags_dir.
+    pub fn from_rpc(def: rpc::WorkspaceDef, peer_service: &PeerService, config: &ScanConfig, tags_dir: &Path, roots: &mut Vec<PathBuf>) -> (Self, Vec<(PathBuf, io::Error)>) {
+        let mut workspace = Workspace::new(def.id, def.name);
+        let mut failed = Vec::new();
+        for shelf in def.shelves {
+            let root_path = PathBuf::from(&shelf.root_path);
+            if shelf.node.is_empty() {
+                let config = ScanConfig {
+                    ignore: shelf.ignore.clone(),
+                    skip_hidden: shelf.skip_hidden,
+                    loaded_depth: (shelf.loaded_depth > 0).then_some(shelf.loaded_depth as usize),
+                    ..config.clone()
+                };
+                let scanned = match roots.iter().any(|root| overlaps(root, &root_path)) {
+                    true => Err(io::Error::new(io::ErrorKind::AlreadyExists, "overlaps another shelf")),
+                    false => LocalShelf::new(root_path.clone(), config).and_then(|scanned| {
+                        scanned.load_tags(&tags_path(tags_dir, shelf.id))?;
+                        Ok(scanned)
+                    }),
+                };
+                match scanned {
+                    Ok(scanned) => {
+                        roots.push(root_path.clone());
+                        workspace.add_local(shelf.id, root_path, scanned);
+                    }
+                    Err(err) => {
+                        workspace.unavailable.push(rpc::ShelfDef {
+                            unavailable: err.to_string(),
+                            ..shelf
+                        });
+                        failed.push((root_path, err));
+                    }
+                }
+                continue;
+            }
+            let node = <[u8; 32]>::try_from(shelf.node)
+                .ok()
+                .and_then(|n| NodeId::from_bytes(&n).ok());
+            if let Some(node) = node {
+                workspace.add_remote(shelf.id, root_path, RemoteShelf::new(peer_service.clone(), node, def.id, shelf.id));
+            }
+        }
+        (workspace, failed)
+    }
+}
+
+// File holding the tag state of a local shelf
+pub fn tags_path(tags_dir: &Path, shelf_id: ShelfId) -> PathBuf {
+    tags_dir.join(format!("{}.pb", shelf_id))
+}
+
+// Shelves covering nested paths would each hold their own copy of the files below the
+// inner root, with diverging tags
+pub fn overlaps(a: &Path, b: &Path) -> bool {
+    a.starts_with(b) || b.starts_with(a)
+}
+
+impl From<&Violation> for rpc::Violation {
+    fn from(violation: &Violation) -> Self {
+        rpc::Violation {
+            path: violation.path.to_string_lossy().into_owned(),
+            invariant: violation.invariant.name().to_string(),
+            tag: violation.tag.as_ref().map(|tag| tag.name()).unwrap_or_default(),
+            expected: violation.expected,
+            found: violation.found,
+        }
+    }
+}
+
+impl From<&ScanError> for rpc::ScanError {
+    fn from(err: &ScanError) -> Self {
+        rpc::ScanError {
+            path: err.path().to_string_lossy().into_owned(),
+            reason: match err {
+                ScanError::Inaccessible(_, kind) => kind.to_string(),
+                ScanError::Cycle(_) => "link to a directory above it".to_string(),
+                ScanError::InvalidRules(_) => "invalid ignore rules".to_string(),
+            },
+        }
+    }
+}